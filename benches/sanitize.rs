@@ -0,0 +1,29 @@
+//! Compares sanitizing a string into a `TrustedStr`, which does not check the
+//! result again, with sanitizing it and then validating the result.
+//!
+//! Run with `cargo bench --bench sanitize`.
+use notification_emitter::{sanitize_str, TrustedStr};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// The fastest of several runs of `f`, which is the least disturbed by the
+/// rest of the system
+fn fastest(f: impl Fn() -> TrustedStr) -> Duration {
+    (0..20)
+        .map(|_| {
+            let start = Instant::now();
+            black_box(f());
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let untrusted = "Mail from \u{2603} <a@b>\t\u{7}\r\n".repeat(1 << 15);
+    let once = fastest(|| TrustedStr::sanitize(black_box(&untrusted)));
+    let twice = fastest(|| TrustedStr::try_new(sanitize_str(black_box(&untrusted))).unwrap());
+    println!("{} bytes:", untrusted.len());
+    println!("  sanitized:                      {:?}", once);
+    println!("  sanitized and validated again:  {:?}", twice);
+}
@@ -0,0 +1,79 @@
+use futures_util::Stream;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+/// Bounded queue of the actions invoked on the notifications of a qube
+///
+/// The queue never blocks the producer: when it is full, the oldest action
+/// is dropped to make room.
+#[derive(Debug)]
+pub(crate) struct ActionQueue {
+    capacity: usize,
+    events: VecDeque<(u32, String)>,
+    waker: Option<Waker>,
+}
+
+impl ActionQueue {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            events: VecDeque::new(),
+            waker: None,
+        }
+    }
+
+    /// Queue the invocation of `action_key` on notification `local_id`.
+    /// Returns `true` if an older action had to be dropped.
+    pub(crate) fn push(&mut self, local_id: u32, action_key: String) -> bool {
+        let dropped = self.events.len() >= self.capacity.max(1);
+        if dropped {
+            self.events.pop_front();
+        }
+        self.events.push_back((local_id, action_key));
+        if let Some(waker) = self.waker.take() {
+            waker.wake()
+        }
+        dropped
+    }
+}
+
+/// Stream of `(local_id, action_key)` pairs for the actions invoked on the
+/// notifications of one qube, in the order they were invoked, or of
+/// `(local_id, text)` pairs for the inline replies to them
+///
+/// The stream ends once the emitter it came from is dropped and every queued
+/// action has been read.
+#[derive(Debug)]
+pub struct ActionStream(pub(crate) Rc<RefCell<ActionQueue>>);
+
+impl Stream for ActionStream {
+    type Item = (u32, String);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut queue = self.0.borrow_mut();
+        if let Some(event) = queue.events.pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        if Rc::strong_count(&self.0) == 1 {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_drop_oldest() {
+        let mut queue = ActionQueue::new(2);
+        assert!(!queue.push(1, "a".to_owned()));
+        assert!(!queue.push(2, "b".to_owned()));
+        assert!(queue.push(3, "c".to_owned()));
+        assert_eq!(queue.events, [(2, "b".to_owned()), (3, "c".to_owned())]);
+    }
+}
@@ -0,0 +1,395 @@
+//! End-to-end check that whatever a qube sends, only sanitized notifications
+//! reach the daemon
+//!
+//! Unlike the unit tests, this does not check what happens to any particular
+//! input.  It sends many generated notifications that try to get something
+//! past the proxy, through the whole emitter, and checks invariants on what
+//! the mock daemon received.
+use crate::{
+    is_safe_for_display, mock, HintValue, ImageParameters, ManualClock, Notification,
+    NotificationEmitter, QubePolicy, Urgency, QUBES_HINT_PREFIX,
+};
+use std::collections::HashMap;
+use std::rc::Rc;
+use zbus::zvariant::Value;
+
+/// A small deterministic random number generator, so that failures can be
+/// reproduced
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        // xorshift64
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+    fn chance(&mut self, one_in: usize) -> bool {
+        self.below(one_in) == 0
+    }
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+    fn int(&mut self) -> i32 {
+        let random = self.next() as i32;
+        *self.pick(&[
+            -1,
+            0,
+            1,
+            2,
+            3,
+            4,
+            8,
+            100,
+            255,
+            256,
+            i32::MAX,
+            i32::MIN,
+            random,
+        ])
+    }
+}
+
+/// Pieces of text that something along the way might mishandle
+const FRAGMENTS: &[&str] = &[
+    "Hi",
+    " ",
+    "\n",
+    "\r",
+    "\r\n",
+    "\t",
+    "\0",
+    "\x07",
+    "\x1b[31m",
+    "\x7f",
+    "\u{85}",
+    "\u{9b}",
+    "\u{202e}",
+    "\u{2066}",
+    "\u{200b}",
+    "\u{feff}",
+    "\u{fffd}",
+    "\u{10ffff}",
+    "<b>",
+    "</b>",
+    "<i>",
+    "</u>",
+    "<b",
+    "<<b>>",
+    "<a href=\"https://example.com\">",
+    "<img src=\"file:///etc/shadow\"/>",
+    "<![CDATA[",
+    "<!--",
+    "&",
+    "&amp;",
+    "&#x202e;",
+    "&#0;",
+    "&lt",
+    "%s%n",
+    "x-qubes-vmname",
+    "dom0",
+];
+
+fn text(rng: &mut Rng) -> String {
+    let mut text = String::new();
+    for _ in 0..rng.below(12) {
+        text += *rng.pick(FRAGMENTS)
+    }
+    if rng.chance(20) {
+        // Long lines and many lines
+        text += &rng.pick(FRAGMENTS).repeat(rng.below(3000))
+    }
+    text
+}
+
+fn hint_value(rng: &mut Rng) -> HintValue {
+    match rng.below(6) {
+        0 => HintValue::Boolean(rng.chance(2)),
+        1 => HintValue::Byte(rng.next() as u8 % 4),
+        2 => HintValue::Int32(rng.int()),
+        3 => HintValue::UInt32(rng.int() as u32),
+        4 => HintValue::String(text(rng)),
+        _ => {
+            let mut data = rng.pick(&[&b"RIFF"[..], b"OggS\0", b"ID3"]).to_vec();
+            data.resize(rng.below(64), rng.next() as u8);
+            HintValue::Bytes(data)
+        }
+    }
+}
+
+const HINT_KEYS: &[&str] = &[
+    "sound-name",
+    "sound-file",
+    "resident",
+    "action-icons",
+    "transient",
+    "suppress-sound",
+    "value",
+    "urgency",
+    "category",
+    "image-data",
+    "image-path",
+    "desktop-entry",
+    "x-dunst-stack-tag",
+    "x-qubes-vmname",
+    "x-qubes-label",
+];
+
+fn image(rng: &mut Rng) -> ImageParameters {
+    let has_alpha = rng.chance(2);
+    let width = if rng.chance(2) {
+        rng.below(8) as i32
+    } else {
+        rng.int()
+    };
+    let height = if rng.chance(2) {
+        rng.below(8) as i32
+    } else {
+        rng.int()
+    };
+    let channels = if rng.chance(4) {
+        rng.int()
+    } else {
+        3 + has_alpha as i32
+    };
+    let rowstride = width
+        .wrapping_mul(channels)
+        .wrapping_add(*rng.pick(&[-1, 0, 0, 1, 3]));
+    let size = rowstride.wrapping_mul(height).clamp(0, 1 << 22) as usize;
+    let len = if rng.chance(20) {
+        // More than the proxy accepts
+        (1 << 21) + 1
+    } else {
+        size.saturating_add_signed(*rng.pick(&[-1, 0, 0, 1, 4096]))
+    };
+    ImageParameters {
+        untrusted_width: width,
+        untrusted_height: height,
+        untrusted_rowstride: rowstride,
+        untrusted_has_alpha: has_alpha,
+        untrusted_bits_per_sample: *rng.pick(&[8, 8, 8, 16, 0]),
+        untrusted_channels: channels,
+        untrusted_data: vec![rng.next() as u8; len],
+    }
+}
+
+fn notification(rng: &mut Rng, open: &[u32]) -> Notification {
+    let mut actions = vec![];
+    for _ in 0..rng.below(4) {
+        actions.push(match rng.below(8) {
+            0 => text(rng),
+            _ => rng
+                .pick(&["default", "open", "x-qubes", "0pen", "a b"])
+                .to_string(),
+        });
+        actions.push(text(rng))
+    }
+    if rng.chance(10) {
+        actions.pop();
+    }
+    let mut hints = vec![];
+    for _ in 0..rng.below(5) {
+        hints.push((rng.pick(HINT_KEYS).to_string(), hint_value(rng)))
+    }
+    Notification::V2 {
+        suppress_sound: rng.chance(4),
+        transient: rng.chance(4),
+        urgency: *rng.pick(&[None, Some(Urgency::Low), Some(Urgency::Critical)]),
+        replaces_id: match open {
+            [] => 0,
+            _ if rng.chance(2) => 0,
+            _ => *rng.pick(open),
+        },
+        summary: text(rng),
+        body: text(rng),
+        actions,
+        category: match rng.below(8) {
+            0 => Some(rng.pick(&["Email", ".x", "x..y", ""]).to_string()),
+            1 => Some(text(rng)),
+            2 => Some("email.arrived".to_owned()),
+            _ => None,
+        },
+        expire_timeout: if rng.chance(4) { rng.int() } else { -1 },
+        image: Some(image(rng)).filter(|_| rng.chance(3)),
+        hints,
+    }
+}
+
+fn check_text(what: &str, text: &str) {
+    let bad = text
+        .chars()
+        .find(|&c| !(is_safe_for_display(c) || c == '\t' || c == '\n'));
+    assert!(bad.is_none(), "{} contains {:?}: {:?}", what, bad, text);
+    assert!(text.lines().count() <= 501, "{} has too many lines", what);
+}
+
+/// Check the body markup: only the tags and entities that the proxy allows,
+/// with the tags balanced
+fn check_markup(body: &str) {
+    let mut open = vec![];
+    let mut rest = body;
+    while let Some(c) = rest.chars().next() {
+        let piece = match c {
+            '<' => {
+                let end = rest.find('>').expect("tags are closed") + 1;
+                let tag = &rest[..end];
+                match tag.strip_prefix("</") {
+                    Some(name) => assert_eq!(open.pop(), Some(name), "unbalanced {}", body),
+                    None => open.push(&tag[1..]),
+                }
+                assert!(
+                    ["b>", "i>", "u>"].contains(&&tag[tag.len() - 2..]),
+                    "{}",
+                    tag
+                );
+                tag
+            }
+            '&' => {
+                let end = rest.find(';').expect("entities are terminated") + 1;
+                let entity = &rest[..end];
+                assert!(["&amp;", "&lt;", "&gt;", "&apos;", "&quot;"].contains(&entity));
+                entity
+            }
+            '>' => panic!("unescaped > in {:?}", body),
+            c => &rest[..c.len_utf8()],
+        };
+        rest = &rest[piece.len()..];
+    }
+    assert!(open.is_empty(), "unclosed tags in {:?}", body);
+}
+
+fn check_bytes(what: &str, value: &Value<'_>) -> usize {
+    match value {
+        Value::Array(bytes) => {
+            assert!(bytes.iter().all(|b| matches!(b, Value::U8(_))), "{}", what);
+            bytes.len()
+        }
+        _ => panic!("{} is not bytes: {:?}", what, value),
+    }
+}
+
+fn check_hint(key: &str, value: &Value<'_>) {
+    match (key, value) {
+        ("urgency", Value::U8(0..=2)) => {}
+        ("category", Value::Array(bytes)) => {
+            check_bytes(key, value);
+            for byte in bytes.iter() {
+                assert!(matches!(byte, Value::U8(b'a'..=b'z' | b'.')), "{:?}", byte)
+            }
+        }
+        ("sound-name" | "x-dunst-stack-tag" | "x-canonical-private-synchronous", Value::Str(s)) => {
+            check_text(key, s)
+        }
+        ("sound-file", Value::Str(path)) => {
+            check_text(key, path);
+            assert!(path.starts_with('/') && !path.contains(".."), "{}", path)
+        }
+        ("resident" | "action-icons" | "transient" | "suppress-sound", Value::Bool(_)) => {}
+        ("value", Value::I32(0..=100)) => {}
+        ("image-data" | "image_data" | "icon_data", Value::Structure(image)) => {
+            let fields = image.fields();
+            let &[Value::I32(width), Value::I32(height), Value::I32(rowstride), Value::Bool(has_alpha), Value::I32(8), Value::I32(channels), ref data] =
+                fields
+            else {
+                panic!("malformed image {:?}", fields.get(..6))
+            };
+            assert!((1..=255).contains(&width) && (1..=255).contains(&height));
+            assert_eq!(channels, 3 + has_alpha as i32);
+            assert!(width * channels <= rowstride);
+            let len = check_bytes(key, data);
+            assert_eq!(len, height as usize * rowstride as usize);
+        }
+        (key, _) if key == QUBES_HINT_PREFIX.to_owned() + "vmname" => {
+            assert_eq!(value, &Value::from("work"))
+        }
+        _ => panic!("unexpected hint {} {:?}", key, value),
+    }
+}
+
+/// Check `notification`, received by a daemon that interprets markup if
+/// `markup` is set
+fn check(notification: &mock::ReceivedNotification, markup: bool) {
+    check_text("app name", &notification.app_name);
+    check_text("icon", &notification.app_icon);
+    check_text("summary", &notification.summary);
+    assert!(notification.summary.starts_with("work: "));
+    check_text("body", &notification.body);
+    if markup {
+        check_markup(&notification.body)
+    }
+    assert!(notification.actions.len().is_multiple_of(2));
+    for pair in notification.actions.chunks(2) {
+        let key = pair[0].as_bytes();
+        assert!(key[0].is_ascii_alphabetic(), "{:?}", pair[0]);
+        assert!(key
+            .iter()
+            .all(|c| c.is_ascii_alphanumeric() || b"-._".contains(c)));
+        check_text("action label", &pair[1]);
+    }
+    assert!(notification.expire_timeout >= -1);
+    let hints: &HashMap<_, _> = &notification.hints;
+    for (key, value) in hints {
+        check_hint(key, value)
+    }
+}
+
+#[tokio::test]
+async fn test_adversarial_notifications() {
+    let permissive = QubePolicy {
+        rate_limit: u32::MAX,
+        allow_empty_summary: true,
+        coerce_boolean_hints: true,
+        group_notifications: true,
+        sound_files: true,
+        ..QubePolicy::default()
+    };
+    // Markup is escaped entirely
+    let strict = QubePolicy {
+        rate_limit: u32::MAX,
+        markup: false,
+        ..QubePolicy::default()
+    };
+    let all = [
+        "actions",
+        "action-icons",
+        "body",
+        "body-markup",
+        "persistence",
+        "sound",
+        "x-dunst-stack-tag",
+    ];
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for (capabilities, policy) in [
+        (&all[..], permissive.clone()),
+        (&[], permissive),
+        (&all, strict),
+    ] {
+        let markup = capabilities.contains(&"body-markup");
+        let daemon = mock::MockDaemon::new(capabilities).await;
+        let emitter = NotificationEmitter::new(&daemon.connection, "work".to_owned(), policy);
+        // Replacements wait for their cooldown
+        let emitter = emitter
+            .await
+            .unwrap()
+            .with_clock(Rc::new(ManualClock::new()));
+        let mut open = vec![];
+        for _ in 0..300 {
+            if let Ok(id) = emitter
+                .send_notification(notification(&mut rng, &open))
+                .await
+            {
+                open.push(id)
+            }
+        }
+        let received = daemon.notifications();
+        // Enough got through for this to mean something
+        assert!(received.len() > 30, "only {} forwarded", received.len());
+        for notification in &received {
+            check(notification, markup)
+        }
+    }
+}
@@ -1,6 +1,12 @@
+#![allow(
+    clippy::explicit_auto_deref,
+    clippy::needless_return,
+    clippy::too_many_arguments
+)]
 use bincode::Options;
 use futures_channel::oneshot::Sender;
-use notification_emitter::{ImageParameters, ReplyMessage, MAX_MESSAGE_SIZE};
+use notification_emitter::MAX_SOUND_FILE_SIZE;
+use notification_emitter::{HintValue, ImageParameters, ReplyMessage, MAX_MESSAGE_SIZE};
 use notification_emitter::{Message, Notification, Urgency, MAJOR_VERSION, MINOR_VERSION};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -8,14 +14,20 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::Mutex;
 use zbus::zvariant::{DeserializeDict, SerializeDict, Type, Value};
 
+/// Reply to a `Notify` call: the notification ID or a D-Bus error name and message
+type NotifyReply = Result<u32, (String, Option<String>)>;
+
 #[derive(Debug)]
 struct ServerInner {
     out: tokio::io::Stdout,
-    map: HashMap<u64, Sender<Result<u32, (String, Option<String>)>>>,
+    map: HashMap<u64, Sender<NotifyReply>>,
 }
 
-struct Server(Arc<Mutex<ServerInner>>, core::sync::atomic::AtomicU64);
+/// The D-Bus server: shared state, the next sequence number, and the
+/// negotiated protocol minor version
+struct Server(Arc<Mutex<ServerInner>>, core::sync::atomic::AtomicU64, u16);
 
+#[allow(dead_code)]
 #[derive(SerializeDict, DeserializeDict, Type)]
 #[zvariant(signature = "a{sv}")]
 struct Hints {
@@ -37,6 +49,34 @@ macro_rules! log_return {
     }};
 }
 
+/// Convert a hint to a form that can be sent to the proxy, if the proxy could
+/// possibly understand it.
+fn hint_value(value: Value<'_>) -> Option<HintValue> {
+    Some(match value {
+        Value::Bool(b) => HintValue::Boolean(b),
+        Value::U8(b) => HintValue::Byte(b),
+        Value::I32(i) => HintValue::Int32(i),
+        Value::U32(u) => HintValue::UInt32(u),
+        Value::Str(s) => HintValue::String(s.to_string()),
+        _ => return None,
+    })
+}
+
+/// Read the sound file at `path`, which may also be a `file://` URI, unless
+/// it is larger than the proxy accepts
+fn read_sound_file(path: &str) -> std::io::Result<Vec<u8>> {
+    use std::io::Read as _;
+    let path = path.strip_prefix("file://").unwrap_or(path);
+    let mut data = vec![];
+    std::fs::File::open(path)?
+        .take(MAX_SOUND_FILE_SIZE as u64 + 1)
+        .read_to_end(&mut data)?;
+    if data.len() > MAX_SOUND_FILE_SIZE {
+        return Err(std::io::Error::other("file too large"));
+    }
+    Ok(data)
+}
+
 fn is_valid_action_name(action: &[u8]) -> zbus::fdo::Result<()> {
     // 255 is arbitrary but should be more than enough
     if action.is_empty() {
@@ -72,7 +112,13 @@ fn is_valid_action_name(action: &[u8]) -> zbus::fdo::Result<()> {
 #[zbus::dbus_interface(name = "org.freedesktop.Notifications")]
 impl Server {
     async fn get_capabilities(&self) -> zbus::fdo::Result<(Vec<String>,)> {
-        Ok((vec!["persistence".to_owned(), "actions".to_owned()],))
+        let mut capabilities = vec!["persistence".to_owned(), "actions".to_owned()];
+        // Where the daemon does not support inline replies either, the proxy
+        // shows them as normal actions
+        if self.2 >= 5 {
+            capabilities.push("inline-reply".to_owned())
+        }
+        Ok((capabilities,))
     }
     #[dbus_interface(signal)]
     async fn notification_closed(
@@ -81,6 +127,21 @@ impl Server {
         id: u32,
         reason: u32,
     ) -> zbus::Result<()>;
+    /// Non-standard: a notification was dropped by the notification proxy
+    #[dbus_interface(signal)]
+    async fn notification_suppressed(
+        &self,
+        signal_context: &zbus::SignalContext<'_>,
+        reason: String,
+    ) -> zbus::Result<()>;
+    /// Non-standard: the actions of a notification were dropped by the
+    /// notification proxy, so they will never be invoked
+    #[dbus_interface(signal)]
+    async fn actions_unavailable(
+        &self,
+        signal_context: &zbus::SignalContext<'_>,
+        id: u32,
+    ) -> zbus::Result<()>;
     #[dbus_interface(signal)]
     async fn action_invoked(
         &self,
@@ -88,6 +149,15 @@ impl Server {
         id: u32,
         action_key: String,
     ) -> zbus::Result<()>;
+    /// Non-standard KDE extension: the user replied to a notification with
+    /// an `inline-reply` action
+    #[dbus_interface(signal)]
+    async fn notification_replied(
+        &self,
+        signal_context: &zbus::SignalContext<'_>,
+        id: u32,
+        text: String,
+    ) -> zbus::Result<()>;
     async fn get_server_information(&self) -> zbus::fdo::Result<(String, String, String, String)> {
         Ok((
             "Qubes OS Notification Proxy".to_owned(),
@@ -117,6 +187,7 @@ impl Server {
         let mut transient = false;
         let mut urgency = None;
         let mut category = None;
+        let mut other_hints = vec![];
         for (i, j) in hints.into_iter() {
             match &*i {
                 "action-icons" => {}
@@ -158,15 +229,25 @@ impl Server {
                         untrusted_data,
                     })
                 }
-                "sound-file" => {
-                    eprintln!("Not yet implemented: Sound files (got {:?})", j)
-                }
-                "sound-name" => eprintln!(
-                    "Not yet implemented: Sound files specified by name (got {:?})",
-                    j
-                ),
-                "suppress-sound" => suppress_sound = true,
-                "transient" => transient = true,
+                // The proxy cannot read files of this qube, so it gets the
+                // contents instead
+                "sound-file" => match j {
+                    Value::Str(path) if self.2 >= 4 => match read_sound_file(&path) {
+                        Ok(data) => other_hints.push((i, HintValue::Bytes(data))),
+                        Err(e) => eprintln!("Ignoring sound file {:?}: {}", &*path, e),
+                    },
+                    j => eprintln!("Ignoring sound file hint {:?}", j),
+                },
+                "suppress-sound" | "transient" => match j {
+                    Value::Bool(flag) if i == "transient" => transient = flag,
+                    Value::Bool(flag) => suppress_sound = flag,
+                    // Some toolkits use integers.  The proxy decides whether
+                    // to accept them.
+                    j => match hint_value(j) {
+                        Some(value) if self.2 >= 2 => other_hints.push((i, value)),
+                        _ => eprintln!("Ignoring non-boolean hint {}", &*i),
+                    },
+                },
                 "x" | "y" => eprintln!("Ignoring coordinate hint {} {:?}", i, j),
                 "urgency" => match j {
                     Value::U8(0) => urgency = Some(Urgency::Low),
@@ -174,9 +255,11 @@ impl Server {
                     Value::U8(2) => urgency = Some(Urgency::Critical),
                     _ => eprintln!("Ignoring unknown urgency value {:?}", j),
                 },
-                _ => {
-                    eprintln!("Unknown hint {:?}, ignoring", &*i);
-                }
+                _ => match hint_value(j) {
+                    // The proxy decides which of these hints are allowed
+                    Some(value) if self.2 >= 2 => other_hints.push((i, value)),
+                    _ => eprintln!("Unknown hint {:?}, ignoring", &*i),
+                },
             }
         }
         let id = self.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
@@ -190,17 +273,33 @@ impl Server {
 
         let notification = Message {
             id,
-            notification: Notification::V1 {
-                suppress_sound,
-                transient,
-                urgency,
-                replaces_id,
-                summary,
-                body,
-                actions,
-                category,
-                expire_timeout,
-                image,
+            notification: if self.2 >= 2 {
+                Notification::V2 {
+                    suppress_sound,
+                    transient,
+                    urgency,
+                    replaces_id,
+                    summary,
+                    body,
+                    actions,
+                    category,
+                    expire_timeout,
+                    image,
+                    hints: other_hints,
+                }
+            } else {
+                Notification::V1 {
+                    suppress_sound,
+                    transient,
+                    urgency,
+                    replaces_id,
+                    summary,
+                    body,
+                    actions,
+                    category,
+                    expire_timeout,
+                    image,
+                }
             },
         };
 
@@ -242,7 +341,7 @@ async fn client_server() {
         .expect("Error reading from stdin")
         .to_le();
     let (daemon_major_version, daemon_minor_version) = notification_emitter::split_version(version);
-    let minor_version = (daemon_minor_version as u16).min(MINOR_VERSION);
+    let minor_version = daemon_minor_version.min(MINOR_VERSION);
     out.write_u32_le(notification_emitter::merge_versions(
         MAJOR_VERSION,
         minor_version,
@@ -266,7 +365,7 @@ async fn client_server() {
         .expect("cannot acquire name")
         .serve_at(
             "/org/freedesktop/Notifications",
-            Server(server.clone(), 0u64.into()),
+            Server(server.clone(), 0u64.into(), minor_version),
         )
         .expect("cannot serve")
         .build()
@@ -325,7 +424,7 @@ async fn client_server() {
                 .expect("task died"),
             ReplyMessage::Dismissed { id, reason } => {
                 let x = interface_ref.get().await;
-                x.notification_closed(interface_ref.signal_context(), id, reason)
+                x.notification_closed(interface_ref.signal_context(), id, reason.into())
                     .await
                     .expect("cannot emit signal");
             }
@@ -335,7 +434,35 @@ async fn client_server() {
                     .await
                     .expect("cannot emit signal");
             }
-            ReplyMessage::UnknownError { sequence: _ } => todo!(),
+            ReplyMessage::UnknownError { sequence } => server
+                .lock()
+                .await
+                .map
+                .remove(&sequence)
+                .expect("server violated the protocol")
+                .send(Err(("org.freedesktop.DBus.Error.Failed".to_owned(), None)))
+                .expect("task died"),
+            ReplyMessage::Suppressed {
+                sequence: _,
+                reason,
+            } => {
+                let x = interface_ref.get().await;
+                x.notification_suppressed(interface_ref.signal_context(), reason)
+                    .await
+                    .expect("cannot emit signal");
+            }
+            ReplyMessage::ActionsUnavailable { id } => {
+                let x = interface_ref.get().await;
+                x.actions_unavailable(interface_ref.signal_context(), id)
+                    .await
+                    .expect("cannot emit signal");
+            }
+            ReplyMessage::Replied { id, text } => {
+                let x = interface_ref.get().await;
+                x.notification_replied(interface_ref.signal_context(), id, text)
+                    .await
+                    .expect("cannot emit signal");
+            }
         }
     }
 }
@@ -345,5 +472,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let local_set = tokio::task::LocalSet::new();
 
     local_set.spawn_local(client_server());
-    Ok(local_set.await)
+    local_set.await;
+    Ok(())
 }
@@ -1,21 +1,60 @@
 use bincode::Options;
 use futures_util::StreamExt;
-use notification_emitter::{merge_versions, NotificationEmitter};
+use notification_emitter::{
+    control_socket_path, merge_versions, CloseReason, Config, ControlCommand, ControlSocket,
+    JsonLinesSink, NotificationEmitter, QubePolicy, QubeSendQueue, QubesCommand, ReturnWatch,
+    SendCommand, Target,
+};
+use notification_emitter::{qube_type, CONFIG_PATH};
 use notification_emitter::{
     MessageWriter, ReplyMessage, MAJOR_VERSION, MAX_MESSAGE_SIZE, MINOR_VERSION,
 };
+use std::path::Path;
 use std::rc::Rc;
 use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// How often to show the notifications held during quiet hours that are
+/// over, which is how late they can be shown
+const RELEASE_PERIOD: std::time::Duration = std::time::Duration::from_secs(30);
 
-async fn client_server(qube_name: String) {
-    let emitter = Rc::new(
-        NotificationEmitter::new(
-            qube_name.to_owned() + ": ",
-            "Qubes VM ".to_owned() + &*qube_name,
-        )
+async fn client_server(
+    qube_name: String,
+    vm_type: Option<String>,
+    policy: QubePolicy,
+    target: Target,
+    self_test: bool,
+) {
+    // The server that lost the qube earlier must leave its notifications be
+    if notification_emitter::announce_return(target.namespace.as_deref(), &qube_name).await {
+        eprintln!("Qube {} came back within the grace period", qube_name)
+    }
+    let connection = target
+        .connect()
         .await
-        .expect("Cannot connect to notifcation daemon"),
-    );
+        .expect("Cannot connect to notification daemon bus");
+    if self_test {
+        match notification_emitter::self_test(&connection).await {
+            Ok(()) => eprintln!("Self-test passed"),
+            Err(e) => eprintln!("Self-test failed: {}", e),
+        }
+    }
+    let mut emitter = NotificationEmitter::new(&connection, qube_name, policy)
+        .await
+        .expect("Cannot connect to notifcation daemon");
+    if let Some(ref namespace) = target.namespace {
+        emitter = emitter.with_namespace(namespace.clone())
+    }
+    if let Some(size) = target.queue_size {
+        emitter = emitter.with_send_queue(QubeSendQueue::new(size))
+    }
+    if let Some(ref path) = target.json_lines {
+        match JsonLinesSink::open(format!("json-lines:{}", path.display()), path) {
+            Ok(sink) => emitter = emitter.with_mirror(Box::new(sink)),
+            Err(e) => eprintln!("Cannot open {}: {}", path.display(), e),
+        }
+    }
+    let emitter = Rc::new(emitter);
     let options = bincode::DefaultOptions::new()
         .with_fixint_encoding()
         .with_native_endian()
@@ -47,6 +86,26 @@ but this server only supports version {MINOR_VERSION}"
         );
     }
     let stdout = MessageWriter::new();
+    let control = control_socket_path(target.namespace.as_deref(), emitter.qube_name())
+        .and_then(ControlSocket::bind);
+    match control {
+        Ok(control) => {
+            let (to_qube, mut messages) = futures_channel::mpsc::unbounded();
+            let emitter_ = emitter.clone();
+            let _handle = tokio::task::spawn_local(async move {
+                let e = control.serve(&emitter_, &to_qube).await;
+                eprintln!("Control socket failed: {}", e)
+            });
+            let stdout_ = stdout.clone();
+            let _handle = tokio::task::spawn_local(async move {
+                while let Some(message) = messages.next().await {
+                    let data = options.serialize(&message).expect("Serialization failed?");
+                    stdout_.transmit(&data).await
+                }
+            });
+        }
+        Err(e) => eprintln!("Cannot open the control socket: {}", e),
+    }
     let mut closed_stream = emitter
         .closed()
         .await
@@ -55,7 +114,12 @@ but this server only supports version {MINOR_VERSION}"
         .invocations()
         .await
         .expect("Cannot register for invoked signals");
+    let mut replied_stream = emitter
+        .replies()
+        .await
+        .expect("Cannot register for replied signals");
     let stdout_ = stdout.clone();
+    let emitter_ = emitter.clone();
     let _handle = tokio::task::spawn_local(async move {
         while let Some(item) = closed_stream.next().await {
             let item = match item.args() {
@@ -65,16 +129,20 @@ but this server only supports version {MINOR_VERSION}"
                     continue;
                 }
             };
+            // Other qubes' notifications are none of this qube's business
+            let id = match emitter_.notification_closed(item.id).await {
+                Some(id) => id,
+                None => continue,
+            };
+            let reason = CloseReason::from(item.reason);
+            eprintln!("Notification {} closed: {}", id, reason);
             let data = options
-                .serialize(&ReplyMessage::Dismissed {
-                    id: item.id,
-                    reason: item.reason,
-                })
+                .serialize(&ReplyMessage::Dismissed { id, reason })
                 .expect("Serialization failed?");
-            stdout_.transmit(&*data).await
+            stdout_.transmit(&data).await
         }
     });
-    let stdout_ = stdout.clone();
+    let emitter_ = emitter.clone();
     let _handle = tokio::task::spawn_local(async move {
         while let Some(item) = invoked_stream.next().await {
             let item = match item.args() {
@@ -84,22 +152,119 @@ but this server only supports version {MINOR_VERSION}"
                     continue;
                 }
             };
+            emitter_.action_invoked(item.id, item.action_key).await
+        }
+    });
+    let emitter_ = emitter.clone();
+    let _handle = tokio::task::spawn_local(async move {
+        while let Some(item) = replied_stream.next().await {
+            let item = match item.args() {
+                Ok(item) => item,
+                Err(e) => {
+                    eprintln!("Got invalid message from notification daemon: {}", e);
+                    continue;
+                }
+            };
+            emitter_.notification_replied(item.id, item.text).await
+        }
+    });
+    let stdout_ = stdout.clone();
+    let mut actions = emitter.invoked_actions();
+    let _handle = tokio::task::spawn_local(async move {
+        while let Some((id, action)) = actions.next().await {
+            let data = options
+                .serialize(&ReplyMessage::ActionInvoked { id, action })
+                .expect("Serialization failed?");
+            stdout_.transmit(&data).await
+        }
+    });
+    let stdout_ = stdout.clone();
+    let emitter_ = emitter.clone();
+    let mut replies = emitter.inline_replies();
+    let _handle = tokio::task::spawn_local(async move {
+        while let Some((id, text)) = replies.next().await {
             let data = options
-                .serialize(&ReplyMessage::ActionInvoked {
-                    id: item.id,
-                    action: item.action_key,
-                })
+                .serialize(&emitter_.reply_event(reply_minor, id, text))
                 .expect("Serialization failed?");
-            stdout_.transmit(&*data).await
+            stdout_.transmit(&data).await
+        }
+    });
+    if let Some(max_lifetime) = emitter.policy().max_lifetime {
+        let stdout_ = stdout.clone();
+        let emitter_ = emitter.clone();
+        // Check often enough that nothing stays open much longer than allowed
+        let period = (max_lifetime / 4).max(std::time::Duration::from_secs(1));
+        let _handle = tokio::task::spawn_local(async move {
+            loop {
+                tokio::time::sleep(period).await;
+                for id in emitter_.close_expired().await {
+                    let data = options
+                        .serialize(&ReplyMessage::Dismissed {
+                            id,
+                            reason: CloseReason::Undefined,
+                        })
+                        .expect("Serialization failed?");
+                    stdout_.transmit(&data).await
+                }
+            }
+        });
+    }
+    let stdout_ = stdout.clone();
+    let emitter_ = emitter.clone();
+    let _handle = tokio::task::spawn_local(async move {
+        // Reloading the policy can turn quiet hours on at any time
+        loop {
+            tokio::time::sleep(RELEASE_PERIOD).await;
+            for message in emitter_.release_held().await {
+                let data = options.serialize(&message).expect("Serialization failed?");
+                stdout_.transmit(&data).await
+            }
+        }
+    });
+    if !emitter.capabilities_known() {
+        let emitter_ = emitter.clone();
+        let _handle =
+            tokio::task::spawn_local(async move { emitter_.fetch_missing_capabilities().await });
+    }
+    let emitter_ = emitter.clone();
+    let _handle = tokio::task::spawn_local(async move {
+        let mut hangups = signal(SignalKind::hangup()).expect("Cannot listen for SIGHUP");
+        // The new policy applies to new notifications only.  Those already
+        // open stay closable, whatever the new policy says.
+        while hangups.recv().await.is_some() {
+            match Config::load(Path::new(CONFIG_PATH)) {
+                Ok(config) => {
+                    let qube = emitter_.qube_name();
+                    emitter_.set_policy(config.policy_for_type(qube, vm_type.as_deref()));
+                    eprintln!("Configuration reloaded")
+                }
+                Err(e) => eprintln!("Cannot reload configuration, keeping the old one: {}", e),
+            }
+            // The daemon might have been replaced as well
+            if let Err(e) = emitter_.refresh_daemon_info().await {
+                eprintln!("Cannot refresh the information about the daemon: {}", e)
+            }
+        }
+    });
+    let stdout_ = stdout.clone();
+    let emitter_ = emitter.clone();
+    let _handle = tokio::task::spawn_local(async move {
+        // Sent to every server process to clear all notifications
+        let mut clear = signal(SignalKind::user_defined1()).expect("Cannot listen for SIGUSR1");
+        while clear.recv().await.is_some() {
+            for message in emitter_.close_all().await {
+                let data = options.serialize(&message).expect("Serialization failed?");
+                stdout_.transmit(&data).await
+            }
         }
     });
     eprintln!("Entering loop");
     loop {
-        let size = stdin
-            .read_u32_le()
-            .await
-            .expect("Error reading from stdin")
-            .to_le();
+        let size = match stdin.read_u32_le().await {
+            Ok(size) => size.to_le(),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => panic!("Error reading from stdin: {}", e),
+        };
         if size > MAX_MESSAGE_SIZE {
             panic!("Message too large ({} bytes)", size)
         }
@@ -113,32 +278,95 @@ but this server only supports version {MINOR_VERSION}"
         let message: notification_emitter::Message = options
             .deserialize(&bytes)
             .expect("malformed input from client");
-        let sequence = message.id;
         let emitter = emitter.clone();
         let stdout = stdout.clone();
         tokio::task::spawn_local(async move {
-            let out = emitter.send_notification(message.notification).await;
-            let data = options
-                .serialize(&match out {
-                    Ok(id) => ReplyMessage::Id { id, sequence },
-                    Err(zbus::Error::MethodError(name, message, _)) => ReplyMessage::DBusError {
-                        name: name.to_string(),
-                        message,
-                        sequence,
-                    },
-                    Err(_) => ReplyMessage::UnknownError { sequence },
-                })
-                .expect("Serialization failed?");
-            stdout.transmit(&*data).await
+            for reply in emitter.reply_to(reply_minor, message).await {
+                let data = options.serialize(&reply).expect("Serialization failed?");
+                stdout.transmit(&data).await
+            }
         });
     }
+    eprintln!("Qube disconnected");
+    if emitter.policy().close_on_shutdown {
+        let namespace = target.namespace.as_deref();
+        let closed = match ReturnWatch::listen(namespace, emitter.qube_name()) {
+            Ok(watch) => emitter.close_after_shutdown(watch.returned()).await,
+            Err(e) => {
+                eprintln!("Cannot wait for the qube to come back: {}", e);
+                emitter.close_after_shutdown(std::future::pending()).await
+            }
+        };
+        if !closed {
+            eprintln!("Qube came back, keeping its notifications")
+        }
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let local_set = tokio::task::LocalSet::new();
 
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("send") {
+        let command = SendCommand::parse(args.skip(1))?;
+        let config = Config::load(Path::new(CONFIG_PATH))?;
+        let connection = Target::default().connect().await?;
+        match local_set.run_until(command.run(&connection, &config)).await {
+            Ok(id) => println!("{}", id),
+            Err(e) => {
+                eprintln!("Notification not sent: {}", e);
+                std::process::exit(1)
+            }
+        }
+        return Ok(());
+    }
+    if args.peek().map(String::as_str) == Some("qubes") {
+        let command = QubesCommand::parse(args.skip(1))?;
+        let config = Config::load(Path::new(CONFIG_PATH))?;
+        match command.run(&config).await {
+            Ok(qubes) => print!("{}", qubes),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }
+        }
+        return Ok(());
+    }
+    if let Some(command) = args.next_if(|arg| ControlCommand::COMMANDS.contains(&&**arg)) {
+        let command = ControlCommand::parse(command, args)?;
+        let config = Config::load(Path::new(CONFIG_PATH))?;
+        match command.run(&config).await {
+            Ok(reply) if !reply.starts_with("error ") => print!("{}", reply),
+            Ok(reply) => {
+                eprint!("{}", reply);
+                std::process::exit(1)
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }
+        }
+        return Ok(());
+    }
+    let self_test = args.any(|arg| arg == "--self-test");
     let source = std::env::var("QREXEC_REMOTE_DOMAIN").expect("No remote domain in qrexec");
-    local_set.spawn_local(client_server(source));
-    Ok(local_set.await)
+    let config = Config::load(Path::new(CONFIG_PATH))
+        .unwrap_or_else(|e| panic!("Cannot load configuration: {}", e));
+    let vm_type = qube_type(&source);
+    let policy = config.policy_for_type(&source, vm_type.as_deref());
+    // The service argument selects the notification daemon.  qrexec policy
+    // decides which qubes may use which target.
+    let target_name = std::env::var("QREXEC_SERVICE_ARGUMENT").unwrap_or_default();
+    let target = config
+        .target(&target_name)
+        .unwrap_or_else(|| panic!("Unknown notification target {:?}", target_name));
+    eprintln!(
+        "Forwarding notifications from {} to target {:?}",
+        source, target_name
+    );
+    local_set
+        .run_until(client_server(source, vm_type, policy, target, self_test))
+        .await;
+    Ok(())
 }
@@ -0,0 +1,256 @@
+use crate::policy::parse_urgency;
+use crate::{control_socket_path, known_qubes, send_control};
+use crate::{qube_type, Config, Notification, NotificationEmitter, ProxyError};
+use zbus::Connection;
+
+/// The `send` subcommand of the server: a notification injected from the
+/// command line, as if `qube` had sent it
+///
+/// This is for debugging the pipeline without a qube.  The notification goes
+/// through [`NotificationEmitter::send_notification`], exactly like the ones
+/// from a real qube.
+#[derive(Debug)]
+pub struct SendCommand {
+    /// The qube the notification pretends to come from
+    pub qube: String,
+    /// The type of the qube, such as `DispVM`.  If this is `None`, the type
+    /// is asked from dom0, like for a real qube.
+    pub vm_type: Option<String>,
+    /// The notification, as the qube would have sent it
+    pub notification: Notification,
+}
+
+impl SendCommand {
+    /// Parse the arguments after `send`:
+    /// `--qube NAME [--type TYPE] --summary TEXT [--body TEXT]
+    /// [--urgency low|normal|critical]`
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut qube = None;
+        let mut vm_type = None;
+        let mut summary = None;
+        let mut body = String::new();
+        let mut urgency = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))
+            };
+            match &*arg {
+                "--qube" => qube = Some(value()?),
+                "--type" => vm_type = Some(value()?),
+                "--summary" => summary = Some(value()?),
+                "--body" => body = value()?,
+                "--urgency" => urgency = Some(parse_urgency(&value()?)?),
+                _ => return Err(format!("Unknown argument {:?}", arg)),
+            }
+        }
+        Ok(Self {
+            qube: qube.ok_or("--qube is required")?,
+            vm_type,
+            notification: Notification::V2 {
+                suppress_sound: false,
+                transient: false,
+                urgency,
+                replaces_id: 0,
+                summary: summary.ok_or("--summary is required")?,
+                body,
+                actions: vec![],
+                category: None,
+                expire_timeout: -1,
+                image: None,
+                hints: vec![],
+            },
+        })
+    }
+
+    /// Send the notification to the daemon on `connection`, with the policy
+    /// `config` has for the qube and its type, as the server would.  Returns
+    /// the ID the qube would have got.
+    pub async fn run(self, connection: &Connection, config: &Config) -> Result<u32, ProxyError> {
+        let vm_type = self.vm_type.or_else(|| qube_type(&self.qube));
+        let policy = config.policy_for_type(&self.qube, vm_type.as_deref());
+        let emitter = NotificationEmitter::new(connection, self.qube, policy).await?;
+        emitter.send_notification(self.notification).await
+    }
+}
+
+/// A command for the control socket of the server of one qube, from the
+/// command line of the server: `COMMAND --qube NAME [--target NAME] [ARG...]`
+///
+/// See [`crate::ControlSocket`].
+#[derive(Debug)]
+pub struct ControlCommand {
+    /// The qube whose server runs the command
+    pub qube: String,
+    /// The name of the target that the server forwards to, which selects the
+    /// namespace of its socket
+    pub target: String,
+    /// The command line sent to the server
+    pub command: String,
+}
+
+impl ControlCommand {
+    /// The commands, which are the first argument of the server
+    pub const COMMANDS: &'static [&'static str] = &[
+        "stats",
+        "reset-stats",
+        "active",
+        "history",
+        "close-category",
+    ];
+
+    /// Parse `command`, one of [`Self::COMMANDS`], and the arguments after it
+    pub fn parse(command: String, args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut qube = None;
+        let mut target = String::new();
+        let mut command = command;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))
+            };
+            match &*arg {
+                "--qube" => qube = Some(value()?),
+                "--target" => target = value()?,
+                _ if arg.starts_with("--") => return Err(format!("Unknown argument {:?}", arg)),
+                _ if arg.contains(char::is_whitespace) => {
+                    return Err(format!("Invalid argument {:?}", arg))
+                }
+                _ => {
+                    command.push(' ');
+                    command += &arg
+                }
+            }
+        }
+        Ok(Self {
+            qube: qube.ok_or("--qube is required")?,
+            target,
+            command,
+        })
+    }
+
+    /// Send the command to the server of the qube, for the target in
+    /// `config`, and return its reply
+    pub async fn run(&self, config: &Config) -> Result<String, String> {
+        let target = config
+            .target(&self.target)
+            .ok_or_else(|| format!("Unknown notification target {:?}", self.target))?;
+        let path = control_socket_path(target.namespace.as_deref(), &self.qube)
+            .map_err(|e| format!("Invalid qube {:?}: {}", self.qube, e))?;
+        send_control(&path, &self.command)
+            .await
+            .map_err(|e| format!("Cannot reach the server for {}: {}", self.qube, e))
+    }
+}
+
+/// The `qubes` subcommand of the server, which lists the qubes that have a
+/// policy of their own or are active: `qubes [--target NAME]`
+///
+/// See [`known_qubes`].
+#[derive(Debug)]
+pub struct QubesCommand {
+    /// The name of the target whose servers are asked
+    pub target: String,
+}
+
+impl QubesCommand {
+    /// Parse the arguments after `qubes`
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut target = String::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match &*arg {
+                "--target" => {
+                    target = args
+                        .next()
+                        .ok_or_else(|| format!("Missing value for {}", arg))?
+                }
+                _ => return Err(format!("Unknown argument {:?}", arg)),
+            }
+        }
+        Ok(Self { target })
+    }
+
+    /// The qubes known for the target in `config`, one on each line
+    pub async fn run(&self, config: &Config) -> Result<String, String> {
+        let target = config
+            .target(&self.target)
+            .ok_or_else(|| format!("Unknown notification target {:?}", self.target))?;
+        let error = |e| format!("Cannot find the servers: {}", e);
+        let dir = crate::sound::runtime_dir(target.namespace.as_deref()).map_err(error)?;
+        let qubes = known_qubes(config, &dir).await.map_err(error)?;
+        Ok(qubes.iter().map(|qube| format!("{}\n", qube)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockDaemon;
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&arg| arg.to_owned()).collect()
+    }
+    #[tokio::test]
+    async fn test_send_command() {
+        let daemon = MockDaemon::new(&["body", "body-markup"]).await;
+        let command = SendCommand::parse(args(&[
+            "--qube",
+            "work",
+            "--type",
+            "AppVM",
+            "--summary",
+            "Hi\x07",
+            "--body",
+            "a & b",
+            "--urgency",
+            "low",
+        ]))
+        .unwrap();
+        let config = Config::default();
+        assert_eq!(command.run(&daemon.connection, &config).await.unwrap(), 1);
+        let received = daemon.notifications().remove(0);
+        assert_eq!(received.summary, "work: Hi\u{FFFD}");
+        assert_eq!(received.body, "a &amp; b");
+        // The policy for the type applies, as on the server
+        let config = Config::parse("[type:DispVM]\ndisplay_prefix = disposable").unwrap();
+        let command = SendCommand::parse(args(&[
+            "--qube",
+            "disp1",
+            "--type",
+            "DispVM",
+            "--summary",
+            "a",
+        ]));
+        let command = command.unwrap();
+        assert_eq!(command.run(&daemon.connection, &config).await.unwrap(), 1);
+        assert_eq!(daemon.notifications()[1].summary, "disposable: a");
+        assert!(SendCommand::parse(args(&["--qube", "work"])).is_err());
+        assert!(
+            SendCommand::parse(args(&["--qube", "work", "--summary", "a", "--urgency"])).is_err()
+        );
+        assert!(SendCommand::parse(args(&["--qube", "work", "--summary", "a", "-x"])).is_err());
+    }
+    #[test]
+    fn test_control_command() {
+        let stats = "stats".to_owned();
+        let command = ControlCommand::parse(stats.clone(), args(&["--qube", "work", "a"]));
+        let command = command.unwrap();
+        assert_eq!((&*command.qube, &*command.target), ("work", ""));
+        assert_eq!(command.command, "stats a");
+        let command = ControlCommand::parse(stats.clone(), args(&["--target", "gui2"]));
+        assert!(command.is_err());
+        let command = ControlCommand::parse(stats.clone(), args(&["--qube", "work", "a b"]));
+        assert!(command.is_err());
+        assert!(ControlCommand::parse(stats, args(&["--qube", "work", "--x"])).is_err());
+    }
+    #[test]
+    fn test_qubes_command() {
+        assert_eq!(QubesCommand::parse(args(&[])).unwrap().target, "");
+        let command = QubesCommand::parse(args(&["--target", "gui2"])).unwrap();
+        assert_eq!(command.target, "gui2");
+        assert!(QubesCommand::parse(args(&["--target"])).is_err());
+        assert!(QubesCommand::parse(args(&["work"])).is_err());
+    }
+}
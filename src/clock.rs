@@ -0,0 +1,112 @@
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Source of time for the time-based policies, such as rate limiting
+pub trait Clock {
+    /// The current time
+    fn now(&self) -> Instant;
+    /// Time since midnight UTC, for the policies that follow the time of day
+    fn time_of_day(&self) -> Duration;
+    /// Wait until `deadline`
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + '_>>;
+}
+
+/// The real clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    fn time_of_day(&self) -> Duration {
+        let since_epoch = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        // Leap seconds aside, every day has the same length in Unix time
+        Duration::from_millis((since_epoch.as_millis() % u128::from(DAY_MS)) as u64)
+    }
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + '_>> {
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+}
+
+/// A clock that only moves when told to, for tests
+///
+/// Sleeping moves the clock forward to the deadline and returns at once.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Cell<Instant>,
+    start: Instant,
+    start_time_of_day: Duration,
+}
+
+impl ManualClock {
+    /// A clock that starts at midnight
+    pub fn new() -> Self {
+        Self::at_time_of_day(Duration::ZERO)
+    }
+    /// A clock that starts `time_of_day` after midnight
+    pub fn at_time_of_day(time_of_day: Duration) -> Self {
+        let start = Instant::now();
+        Self {
+            now: Cell::new(start),
+            start,
+            start_time_of_day: time_of_day,
+        }
+    }
+    /// Move the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration)
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+    fn time_of_day(&self) -> Duration {
+        let elapsed = self.start_time_of_day + (self.now.get() - self.start);
+        Duration::from_millis((elapsed.as_millis() % u128::from(DAY_MS)) as u64)
+    }
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + '_>> {
+        if deadline > self.now.get() {
+            self.now.set(deadline)
+        }
+        Box::pin(std::future::ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[tokio::test]
+    async fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), start + Duration::from_secs(5));
+        clock.sleep_until(start + Duration::from_secs(7)).await;
+        assert_eq!(clock.now(), start + Duration::from_secs(7));
+        // Deadlines in the past do not move the clock back
+        clock.sleep_until(start).await;
+        assert_eq!(clock.now(), start + Duration::from_secs(7));
+    }
+    #[test]
+    fn test_time_of_day() {
+        let clock = ManualClock::at_time_of_day(Duration::from_secs(23 * 3600));
+        assert_eq!(clock.time_of_day(), Duration::from_secs(23 * 3600));
+        clock.advance(Duration::from_secs(2 * 3600));
+        assert_eq!(clock.time_of_day(), Duration::from_secs(3600));
+    }
+}
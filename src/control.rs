@@ -0,0 +1,362 @@
+use crate::handover::{is_file_name_safe, qube_socket_path};
+use crate::{
+    is_valid_category, Config, HistoryEntry, NotificationEmitter, QubeStats, ReplyMessage,
+};
+use futures_channel::mpsc::UnboundedSender;
+use std::os::unix::fs::MetadataExt as _;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Longest control command, in bytes
+const MAX_COMMAND_LEN: u64 = 4096;
+/// How long a client of the control socket may take to send its command
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the server for `qube` listens for control commands, in the directory
+/// for `namespace`
+pub fn control_socket_path(namespace: Option<&str>, qube: &str) -> std::io::Result<PathBuf> {
+    qube_socket_path(namespace, qube, "control")
+}
+
+/// The qubes that have a policy of their own in `config`, or whose server
+/// listens on a control socket in `dir` and is active, sorted by name.  See
+/// [`NotificationEmitter::is_active`].
+///
+/// Every qube has a server of its own, so this asks each of them.  Sockets
+/// left over by servers that are gone are skipped.
+pub async fn known_qubes(config: &Config, dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut qubes: Vec<String> = config.configured_qubes().map(str::to_owned).collect();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let qube = match name.to_str().and_then(|name| name.strip_suffix(".control")) {
+            Some(qube) if is_file_name_safe(qube) => qube,
+            _ => continue,
+        };
+        if let Ok(reply) = send_control(&dir.join(&name), "active").await {
+            if reply == "yes\n" {
+                qubes.push(qube.to_owned())
+            }
+        }
+    }
+    qubes.sort();
+    qubes.dedup();
+    Ok(qubes)
+}
+
+/// The control socket of a server, through which the user can look at and
+/// manage the notifications of the qube that the server serves
+///
+/// A client sends one command on a line and reads the reply until the server
+/// closes the connection.  Replies that start with `error ` report a failed
+/// command.  Only the user can use the socket, as it is in their runtime
+/// directory.  The socket is removed when this is dropped, unless a newer
+/// server for the qube took it over.
+#[derive(Debug)]
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    inode: u64,
+}
+
+impl ControlSocket {
+    /// Listen at `path`, taking it over from any earlier server for the qube
+    pub fn bind(path: PathBuf) -> std::io::Result<Self> {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let listener = UnixListener::bind(&path)?;
+        let inode = std::fs::symlink_metadata(&path)?.ino();
+        Ok(Self {
+            listener,
+            path,
+            inode,
+        })
+    }
+
+    /// Run the commands sent for `emitter`, one connection at a time.  The
+    /// messages that commands have for the qube, such as about the
+    /// notifications they closed, go to `to_qube`.  Only returns if no
+    /// connection can be accepted any more.
+    pub async fn serve(
+        &self,
+        emitter: &NotificationEmitter,
+        to_qube: &UnboundedSender<ReplyMessage>,
+    ) -> std::io::Error {
+        loop {
+            let stream = match self.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => return e,
+            };
+            if let Err(e) = handle_connection(emitter, stream, to_qube).await {
+                eprintln!("Control connection failed: {}", e)
+            }
+        }
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let ours = std::fs::symlink_metadata(&self.path).is_ok_and(|m| m.ino() == self.inode);
+        if !ours {
+            return;
+        }
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            eprintln!("Cannot remove {}: {}", self.path.display(), e)
+        }
+    }
+}
+
+async fn handle_connection(
+    emitter: &NotificationEmitter,
+    mut stream: UnixStream,
+    to_qube: &UnboundedSender<ReplyMessage>,
+) -> std::io::Result<()> {
+    let mut command = String::new();
+    {
+        let mut reader = tokio::io::BufReader::new((&mut stream).take(MAX_COMMAND_LEN));
+        let read = reader.read_line(&mut command);
+        match tokio::time::timeout(COMMAND_TIMEOUT, read).await {
+            Ok(read) => read?,
+            Err(_) => return Err(std::io::ErrorKind::TimedOut.into()),
+        };
+    }
+    let reply = run_control(emitter, command.trim_end_matches('\n'), to_qube).await;
+    stream.write_all(reply.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// The reply of `emitter` to the control command `command`.  Messages for the
+/// qube go to `to_qube`.
+pub(crate) async fn run_control(
+    emitter: &NotificationEmitter,
+    command: &str,
+    to_qube: &UnboundedSender<ReplyMessage>,
+) -> String {
+    let words: Vec<&str> = command.split_ascii_whitespace().collect();
+    match words[..] {
+        ["stats"] => format_stats(&emitter.qube_stats(), emitter.clock.now()),
+        ["active"] if emitter.is_active().await => "yes\n".to_owned(),
+        ["active"] => "no\n".to_owned(),
+        ["history"] => {
+            let bodies = emitter.policy().history_bodies;
+            let now = emitter.clock.now();
+            let history = emitter.history();
+            history
+                .iter()
+                .map(|entry| format_entry(entry, bodies, now))
+                .collect()
+        }
+        ["reset-stats"] => {
+            emitter.reset_stats();
+            "ok\n".to_owned()
+        }
+        ["close-category", category] if is_valid_category(category) => {
+            let messages = emitter.close_category(category).await;
+            let closed = messages.len();
+            for message in messages {
+                // Nobody to tell once the qube is gone
+                let _ = to_qube.unbounded_send(message);
+            }
+            format!("closed {}\n", closed)
+        }
+        ["close-category", category] => format!("error Invalid category {:?}\n", category),
+        _ => format!("error Unknown command {:?}\n", command),
+    }
+}
+
+/// `stats` as `name value` lines.  `seconds` is how long the statistics were
+/// collected for.
+fn format_stats(stats: &QubeStats, now: Instant) -> String {
+    let mut text = format!(
+        "seconds {}\nsent {}\nrate_limited {}\nblocked {}\nvalidation_failed {}\n\
+         images_rejected {}\n",
+        now.saturating_duration_since(stats.since).as_secs(),
+        stats.sent,
+        stats.rate_limited,
+        stats.blocked,
+        stats.validation_failed,
+        stats.images_rejected
+    );
+    if let Some(seq) = stats.last_seq {
+        text += &format!("last_seq {}\n", seq)
+    }
+    text
+}
+
+/// `entry` of the history on one line, with its `body` only if `bodies`.  The
+/// policy may have been reloaded since the entry was recorded, so this does
+/// not rely on the body being left out already.
+fn format_entry(entry: &HistoryEntry, bodies: bool, now: Instant) -> String {
+    let mut text = format!(
+        "seq {} id {} seconds_ago {} summary {:?}",
+        entry.seq,
+        entry.local_id,
+        now.saturating_duration_since(entry.time).as_secs(),
+        entry.summary
+    );
+    match &entry.body {
+        Some(body) if bodies => text += &format!(" body {:?}\n", body),
+        _ => text.push('\n'),
+    }
+    text
+}
+
+/// Send `command` to the control socket at `path` and return the reply
+pub async fn send_control(path: &Path, command: &str) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(path).await?;
+    stream.write_all(command.as_bytes()).await?;
+    stream.write_all(b"\n").await?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockDaemon;
+    use crate::tests::{emitter, notification, v2};
+    use crate::{ManualClock, QubePolicy};
+    use std::rc::Rc;
+    #[tokio::test]
+    async fn test_stats_commands() {
+        let daemon = MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            rate_limit: 1,
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        let (to_qube, _) = futures_channel::mpsc::unbounded();
+        emitter.send_notification(notification("a")).await.unwrap();
+        assert!(emitter.send_notification(notification("b")).await.is_err());
+        let stats = run_control(&emitter, "stats", &to_qube).await;
+        assert!(stats.contains("\nsent 1\nrate_limited 1\n"), "{}", stats);
+        assert!(stats.contains("\nlast_seq "));
+        assert_eq!(run_control(&emitter, "reset-stats", &to_qube).await, "ok\n");
+        let stats = run_control(&emitter, "stats", &to_qube).await;
+        assert!(stats.contains("\nsent 0\nrate_limited 0\n"), "{}", stats);
+        assert!(run_control(&emitter, "reset", &to_qube)
+            .await
+            .starts_with("error "));
+    }
+    #[tokio::test]
+    async fn test_active_command() {
+        let daemon = MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            rate_limit_window: Duration::from_secs(1),
+            ..QubePolicy::default()
+        };
+        let clock = Rc::new(ManualClock::new());
+        let work = emitter(&daemon, policy).await.with_clock(clock.clone());
+        let (to_qube, _) = futures_channel::mpsc::unbounded();
+        assert_eq!(run_control(&work, "active", &to_qube).await, "no\n");
+        let id = work.send_notification(notification("a")).await.unwrap();
+        assert_eq!(run_control(&work, "active", &to_qube).await, "yes\n");
+        // Still active after the activity is over, while the notification is
+        // open
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(run_control(&work, "active", &to_qube).await, "yes\n");
+        let server_id = work.ids.lock().await.server_id(id).unwrap().1;
+        assert_eq!(work.notification_closed(server_id).await, Some(id));
+        assert_eq!(run_control(&work, "active", &to_qube).await, "no\n");
+    }
+    #[tokio::test]
+    async fn test_known_qubes() {
+        let daemon = MockDaemon::new(&[]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let config = Config::parse("[vault]\nrate_limit = 1\n").unwrap();
+        let dir = std::env::temp_dir().join(format!("known-qubes-test-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        assert_eq!(known_qubes(&config, &dir).await.unwrap(), ["vault"]);
+        // Left over by servers that are gone, and a socket of another kind
+        std::fs::write(dir.join("gone.control"), "").unwrap();
+        std::fs::write(dir.join("test.return"), "").unwrap();
+        let control = ControlSocket::bind(dir.join("test.control")).unwrap();
+        let (to_qube, _) = futures_channel::mpsc::unbounded();
+        tokio::select! {
+            e = control.serve(&emitter, &to_qube) => panic!("{}", e),
+            _ = async {
+                assert_eq!(known_qubes(&config, &dir).await.unwrap(), ["vault"]);
+                emitter.send_notification(notification("a")).await.unwrap();
+                assert_eq!(known_qubes(&config, &dir).await.unwrap(), ["test", "vault"]);
+            } => {}
+        }
+        drop(control);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(known_qubes(&config, &dir).await.is_err());
+    }
+    #[tokio::test]
+    async fn test_history_command() {
+        let daemon = MockDaemon::new(&["body"]).await;
+        let policy = QubePolicy {
+            history_bodies: true,
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy.clone()).await;
+        let (to_qube, _) = futures_channel::mpsc::unbounded();
+        assert_eq!(run_control(&emitter, "history", &to_qube).await, "");
+        let with_body = |summary: &str| v2(summary, |fields| fields.body = "two\nlines".to_owned());
+        emitter.send_notification(with_body("a")).await.unwrap();
+        emitter.send_notification(notification("b")).await.unwrap();
+        let history = run_control(&emitter, "history", &to_qube).await;
+        let lines: Vec<_> = history.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", history);
+        assert!(lines[0].contains(" id 1 seconds_ago 0 summary \"test: a\" body \"two\\nlines\""));
+        assert!(lines[1].ends_with(" id 2 seconds_ago 0 summary \"test: b\" body \"\""));
+        // Bodies kept before the policy stopped allowing it are not shown
+        emitter.set_policy(QubePolicy {
+            history_bodies: false,
+            ..policy
+        });
+        let history = run_control(&emitter, "history", &to_qube).await;
+        assert!(!history.contains("body"), "{}", history);
+        assert_eq!(history.lines().count(), 2);
+    }
+    #[tokio::test]
+    async fn test_close_category_command() {
+        let daemon = MockDaemon::new(&[]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let (to_qube, mut messages) = futures_channel::mpsc::unbounded();
+        let with_category =
+            |category: &str| v2("a", |fields| fields.category = Some(category.to_owned()));
+        let im = emitter.send_notification(with_category("im.received"));
+        let im = im.await.unwrap();
+        emitter
+            .send_notification(with_category("email.arrived"))
+            .await
+            .unwrap();
+        let reply = run_control(&emitter, "close-category im.received", &to_qube).await;
+        assert_eq!(reply, "closed 1\n");
+        assert!(matches!(
+            messages.try_recv().unwrap(),
+            ReplyMessage::Dismissed { id, .. } if id == im
+        ));
+        assert!(messages.try_recv().is_err());
+        let reply = run_control(&emitter, "close-category ../x", &to_qube).await;
+        assert!(reply.starts_with("error "));
+        assert_eq!(daemon.state.lock().unwrap().closed, [1]);
+    }
+    #[tokio::test]
+    async fn test_control_socket() {
+        let daemon = MockDaemon::new(&[]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        emitter.send_notification(notification("a")).await.unwrap();
+        let path = std::env::temp_dir().join(format!("control-test-{}", std::process::id()));
+        let control = ControlSocket::bind(path.clone()).unwrap();
+        let (to_qube, _) = futures_channel::mpsc::unbounded();
+        let reply = tokio::select! {
+            e = control.serve(&emitter, &to_qube) => panic!("{}", e),
+            reply = send_control(&path, "stats") => reply.unwrap(),
+        };
+        assert!(reply.contains("\nsent 1\n"), "{}", reply);
+        // A newer server took the socket over
+        let newer = ControlSocket::bind(path.clone()).unwrap();
+        drop(control);
+        assert!(path.exists());
+        drop(newer);
+        assert!(!path.exists());
+    }
+}
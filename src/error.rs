@@ -0,0 +1,151 @@
+use crate::ReplyMessage;
+
+/// Why the policy of a qube rejected a notification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyRejection {
+    /// The qube sent too many notifications.
+    RateLimited,
+    /// The policy of the qube does not allow notifications in this category.
+    /// `None` means notifications without a category.
+    CategoryBlocked(Option<String>),
+    /// The send queue of the target was full, and this notification was
+    /// shed to make room.
+    QueueFull,
+    /// The notification was sent during the quiet hours of the qube.
+    QuietHours,
+}
+
+impl PolicyRejection {
+    /// Stable code for this reason
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::RateLimited => "rate-limited",
+            Self::CategoryBlocked(_) => "blocked-category",
+            Self::QueueFull => "queue-full",
+            Self::QuietHours => "quiet-hours",
+        }
+    }
+}
+
+/// Errors that can happen when forwarding a notification
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The notification sent by the qube was malformed.  The qube can fix
+    /// this.
+    Validation(String),
+    /// The notification was fine, but the policy of the qube does not allow
+    /// it.  Only the administrator can change this.
+    PolicyRejected(PolicyRejection),
+    /// The notification daemon could not be reached, even after retrying.
+    DaemonUnavailable,
+    /// The notification daemon returned an error.
+    DBus(zbus::Error),
+}
+
+/// Whether `error` means the daemon is (hopefully briefly) unavailable, such
+/// as while it is restarting.  These errors guarantee that the notification
+/// was not shown, so the call can be retried.  In particular, a missing reply
+/// is not transient, as the daemon might have shown the notification anyway.
+pub(crate) fn is_transient(error: &zbus::Error) -> bool {
+    match error {
+        zbus::Error::MethodError(name, _, _) => matches!(
+            name.as_str(),
+            "org.freedesktop.DBus.Error.ServiceUnknown"
+                | "org.freedesktop.DBus.Error.NameHasNoOwner"
+        ),
+        _ => false,
+    }
+}
+
+impl ProxyError {
+    /// Stable code for this error
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Validation(_) => "invalid",
+            Self::PolicyRejected(rejection) => rejection.code(),
+            Self::DaemonUnavailable => "daemon-unavailable",
+            Self::DBus(_) => "daemon-error",
+        }
+    }
+
+    /// The reason reported to the qube if this error caused its notification
+    /// to be suppressed.  Errors from the notification daemon are not
+    /// suppressions.
+    pub fn suppress_reason(&self) -> Option<&'static str> {
+        match self {
+            Self::Validation(_) | Self::PolicyRejected(_) => Some(self.code()),
+            Self::DaemonUnavailable | Self::DBus(_) => None,
+        }
+    }
+
+    /// Convert this error to the reply sent to the qube for the method call
+    /// with sequence number `sequence`.
+    pub fn into_reply(self, sequence: u64) -> ReplyMessage {
+        match self {
+            Self::Validation(message) => ReplyMessage::DBusError {
+                name: "org.freedesktop.DBus.Error.InvalidArgs".to_owned(),
+                message: Some(message),
+                sequence,
+            },
+            Self::PolicyRejected(PolicyRejection::RateLimited) => ReplyMessage::DBusError {
+                name: "org.freedesktop.DBus.Error.LimitsExceeded".to_owned(),
+                message: Some("Too many notifications".to_owned()),
+                sequence,
+            },
+            Self::PolicyRejected(PolicyRejection::QueueFull) => ReplyMessage::DBusError {
+                name: "org.freedesktop.DBus.Error.LimitsExceeded".to_owned(),
+                message: Some("Too many notifications queued".to_owned()),
+                sequence,
+            },
+            Self::PolicyRejected(
+                PolicyRejection::CategoryBlocked(_) | PolicyRejection::QuietHours,
+            ) => ReplyMessage::DBusError {
+                name: "org.freedesktop.DBus.Error.AccessDenied".to_owned(),
+                message: Some(self.to_string()),
+                sequence,
+            },
+            Self::DaemonUnavailable => ReplyMessage::DBusError {
+                name: "org.freedesktop.DBus.Error.ServiceUnknown".to_owned(),
+                message: Some("Notification daemon unavailable".to_owned()),
+                sequence,
+            },
+            Self::DBus(zbus::Error::MethodError(name, message, _)) => ReplyMessage::DBusError {
+                name: name.to_string(),
+                message,
+                sequence,
+            },
+            Self::DBus(_) => ReplyMessage::UnknownError { sequence },
+        }
+    }
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Validation(message) => write!(f, "Invalid notification: {}", message),
+            Self::PolicyRejected(PolicyRejection::RateLimited) => {
+                f.write_str("Rate limit exceeded")
+            }
+            Self::PolicyRejected(PolicyRejection::CategoryBlocked(Some(category))) => {
+                write!(f, "Notifications in category {} are blocked", category)
+            }
+            Self::PolicyRejected(PolicyRejection::CategoryBlocked(None)) => {
+                f.write_str("Notifications without a category are blocked")
+            }
+            Self::PolicyRejected(PolicyRejection::QueueFull) => f.write_str("Send queue full"),
+            Self::PolicyRejected(PolicyRejection::QuietHours) => {
+                f.write_str("Notifications are not shown during quiet hours")
+            }
+            Self::DaemonUnavailable => f.write_str("Notification daemon unavailable"),
+            Self::DBus(e) => write!(f, "D-Bus error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+impl From<zbus::Error> for ProxyError {
+    fn from(e: zbus::Error) -> Self {
+        Self::DBus(e)
+    }
+}
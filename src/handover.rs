@@ -0,0 +1,104 @@
+use std::path::PathBuf;
+use tokio::net::{UnixListener, UnixStream};
+
+/// Socket of kind `kind` of the server for `qube`, in the directory for
+/// `namespace`
+pub(crate) fn qube_socket_path(
+    namespace: Option<&str>,
+    qube: &str,
+    kind: &str,
+) -> std::io::Result<PathBuf> {
+    if !is_file_name_safe(qube) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Qube name not usable in a file name",
+        ));
+    }
+    Ok(crate::sound::runtime_dir(namespace)?.join(format!("{}.{}", qube, kind)))
+}
+
+/// Whether the qube name `qube` is safe in a file name.  Qubes enforces this
+/// already, but the name goes into a path.
+pub(crate) fn is_file_name_safe(qube: &str) -> bool {
+    let valid = qube
+        .bytes()
+        .all(|c| c.is_ascii_alphanumeric() || b"-_.".contains(&c));
+    !qube.is_empty() && !qube.starts_with('.') && valid
+}
+
+/// Socket on which the server for `qube` that lost its connection waits for
+/// the next server for the same qube
+fn socket_path(namespace: Option<&str>, qube: &str) -> std::io::Result<PathBuf> {
+    qube_socket_path(namespace, qube, "return")
+}
+
+/// Waits for a qube that disconnected to connect again.  Every connection
+/// is served by a separate process, so the server that lost the qube cannot
+/// see the new connection itself: the next server for the qube tells it
+/// with [`announce_return`] instead.  The socket is removed when this is
+/// dropped.
+pub struct ReturnWatch {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ReturnWatch {
+    /// Start waiting for `qube` to come back to the targets in `namespace`
+    pub fn listen(namespace: Option<&str>, qube: &str) -> std::io::Result<Self> {
+        let path = socket_path(namespace, qube)?;
+        // Left over by a server that did not get to clean up
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let listener = UnixListener::bind(&path)?;
+        Ok(Self { listener, path })
+    }
+
+    /// Completes once the next server for the qube has started
+    pub async fn returned(&self) {
+        match self.listener.accept().await {
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Cannot wait for the qube to come back: {}", e);
+                std::future::pending().await
+            }
+        }
+    }
+}
+
+impl Drop for ReturnWatch {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            eprintln!("Cannot remove {}: {}", self.path.display(), e)
+        }
+    }
+}
+
+/// Tell the server still waiting for `qube` to come back to the targets in
+/// `namespace`, if there is one, that it did.  Returns whether a server was
+/// waiting.
+pub async fn announce_return(namespace: Option<&str>, qube: &str) -> bool {
+    match socket_path(namespace, qube) {
+        Ok(path) => UnixStream::connect(path).await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[tokio::test]
+    async fn test_return_watch() {
+        assert!(!announce_return(None, "handover-test").await);
+        let watch = ReturnWatch::listen(None, "handover-test").unwrap();
+        // Another namespace is another set of targets
+        assert!(!announce_return(Some("gui2"), "handover-test").await);
+        assert!(announce_return(None, "handover-test").await);
+        watch.returned().await;
+        drop(watch);
+        assert!(!announce_return(None, "handover-test").await);
+        assert!(ReturnWatch::listen(None, "../handover-test").is_err());
+        assert!(ReturnWatch::listen(None, "..").is_err());
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// A notification as it was forwarded, for debugging
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// When the notification was forwarded
+    pub time: Instant,
+    pub local_id: u32,
+    /// The sequence number the proxy gave the notification
+    pub seq: u64,
+    /// The sanitized summary, including the qube prefix
+    pub summary: String,
+    /// The sanitized body, if the policy allows keeping bodies
+    pub body: Option<String>,
+}
+
+/// The last notifications forwarded for a qube
+///
+/// At most `capacity` entries are kept; the oldest one is dropped to make
+/// room for a new one.
+#[derive(Debug)]
+pub struct History {
+    capacity: usize,
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Record `entry`, dropping the oldest entry if the history is full
+    pub fn push(&mut self, entry: HistoryEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry)
+    }
+
+    /// The entries, oldest first
+    pub fn entries(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_history_evicts_oldest() {
+        let now = Instant::now();
+        let entry = |local_id| HistoryEntry {
+            time: now,
+            local_id,
+            seq: local_id.into(),
+            summary: "a".to_owned(),
+            body: None,
+        };
+        let mut history = History::new(2);
+        for local_id in 1..=3 {
+            history.push(entry(local_id))
+        }
+        let ids: Vec<_> = history.entries().map(|entry| entry.local_id).collect();
+        assert_eq!(ids, [2, 3]);
+        let mut history = History::new(0);
+        history.push(entry(1));
+        assert_eq!(history.entries().count(), 0);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::SoundFile;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::Instant;
+
+/// What is remembered about an open notification
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NotificationInfo {
+    /// Whether the notification has critical urgency
+    pub critical: bool,
+    /// The validated category of the notification, if it had one
+    pub category: Option<String>,
+    /// When the notification was last shown or updated
+    pub updated: Option<Instant>,
+    /// The file with the sound of the notification, removed once the
+    /// notification is forgotten or replaced
+    pub sound_file: Option<Rc<SoundFile>>,
+    /// The sequence number of the message that last showed or updated the
+    /// notification, if it came in a message
+    pub sequence: Option<u64>,
+    /// Whether the notification was shown with an inline reply action
+    pub inline_reply: bool,
+}
+
+#[derive(Debug)]
+struct Entry {
+    sink: usize,
+    server_id: u32,
+    info: NotificationInfo,
+}
+
+/// Map between the notification IDs seen by a qube and those of the sinks
+/// that show them
+///
+/// Qubes only ever see their own local IDs, so they cannot replace or learn
+/// about the notifications of other qubes.  Each notification is owned by one
+/// sink, identified by its index, and IDs are only unique within a sink.
+#[derive(Debug, Default)]
+pub struct IdMap {
+    last_local_id: u32,
+    by_local: HashMap<u32, Entry>,
+    by_server: HashMap<(usize, u32), u32>,
+    /// Local IDs given out for notifications that are not shown yet
+    reserved: HashSet<u32>,
+}
+
+impl IdMap {
+    /// Record a new notification with ID `server_id` in sink `sink`, returning
+    /// its local ID.
+    pub fn insert(&mut self, sink: usize, server_id: u32) -> u32 {
+        let local_id = self.next_local_id();
+        self.bind(local_id, sink, server_id);
+        local_id
+    }
+
+    /// A new local ID for a notification that is shown later, with
+    /// [`Self::bind`].  It is not given out again until it is bound or
+    /// [`Self::unreserve`]d.
+    pub fn reserve(&mut self) -> u32 {
+        let local_id = self.next_local_id();
+        self.reserved.insert(local_id);
+        local_id
+    }
+
+    /// Give up the local ID `local_id` that was reserved, as its notification
+    /// is not going to be shown
+    pub fn unreserve(&mut self, local_id: u32) {
+        self.reserved.remove(&local_id);
+    }
+
+    fn next_local_id(&mut self) -> u32 {
+        loop {
+            // 0 is never a valid ID
+            self.last_local_id = self.last_local_id.checked_add(1).unwrap_or(1);
+            let taken = self.by_local.contains_key(&self.last_local_id)
+                || self.reserved.contains(&self.last_local_id);
+            if !taken {
+                return self.last_local_id;
+            }
+        }
+    }
+
+    /// Make the open notification `local_id` refer to ID `server_id` in sink
+    /// `sink`.  The daemon may change the ID when a notification is replaced,
+    /// and the notification may move to another sink.
+    pub fn bind(&mut self, local_id: u32, sink: usize, server_id: u32) {
+        self.reserved.remove(&local_id);
+        let entry = Entry {
+            sink,
+            server_id,
+            info: NotificationInfo::default(),
+        };
+        if let Some(old) = self.by_local.insert(local_id, entry) {
+            self.by_server.remove(&(old.sink, old.server_id));
+        }
+        if let Some(old) = self.by_server.insert((sink, server_id), local_id) {
+            if old != local_id {
+                self.by_local.remove(&old);
+            }
+        }
+    }
+
+    /// The sink and sink ID of the open notification `local_id`
+    pub fn server_id(&self, local_id: u32) -> Option<(usize, u32)> {
+        let entry = self.by_local.get(&local_id)?;
+        Some((entry.sink, entry.server_id))
+    }
+
+    /// What is known about the open notification `local_id`
+    pub fn info(&self, local_id: u32) -> Option<&NotificationInfo> {
+        Some(&self.by_local.get(&local_id)?.info)
+    }
+
+    /// Record `info` about the open notification `local_id`
+    pub fn set_info(&mut self, local_id: u32, info: NotificationInfo) {
+        if let Some(entry) = self.by_local.get_mut(&local_id) {
+            entry.info = info
+        }
+    }
+
+    /// The open notifications, as local ID, sink, sink ID, and information
+    pub fn iter(&self) -> impl Iterator<Item = (u32, usize, u32, &NotificationInfo)> {
+        self.by_local
+            .iter()
+            .map(|(&local_id, entry)| (local_id, entry.sink, entry.server_id, &entry.info))
+    }
+
+    /// The local ID of the open notification with ID `server_id` in sink
+    /// `sink`
+    pub fn local_id(&self, sink: usize, server_id: u32) -> Option<u32> {
+        self.by_server.get(&(sink, server_id)).copied()
+    }
+
+    /// Forget the notification with ID `server_id` in sink `sink` after it was
+    /// closed, returning its local ID.
+    pub fn remove_server(&mut self, sink: usize, server_id: u32) -> Option<u32> {
+        let local_id = self.by_server.remove(&(sink, server_id))?;
+        self.by_local.remove(&local_id);
+        Some(local_id)
+    }
+
+    /// Number of open notifications
+    pub fn len(&self) -> usize {
+        self.by_local.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_local.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_id_map() {
+        let mut map = IdMap::default();
+        assert_eq!(map.insert(0, 100), 1);
+        assert_eq!(map.insert(0, 200), 2);
+        assert_eq!(map.server_id(1), Some((0, 100)));
+        assert_eq!(map.local_id(0, 200), Some(2));
+        map.bind(1, 0, 300);
+        assert_eq!(map.server_id(1), Some((0, 300)));
+        assert_eq!(map.local_id(0, 100), None);
+        assert_eq!(map.remove_server(0, 300), Some(1));
+        assert_eq!(map.server_id(1), None);
+        assert_eq!(map.remove_server(0, 300), None);
+        assert_eq!(map.len(), 1);
+    }
+    #[test]
+    fn test_id_map_reserve() {
+        let mut map = IdMap::default();
+        assert_eq!(map.reserve(), 1);
+        assert_eq!(map.insert(0, 100), 2);
+        // Reserved IDs are not open notifications
+        assert_eq!(map.server_id(1), None);
+        assert_eq!(map.len(), 1);
+        map.bind(1, 0, 200);
+        assert_eq!(map.local_id(0, 200), Some(1));
+        let reserved = map.reserve();
+        assert_eq!(reserved, 3);
+        map.unreserve(reserved);
+        assert_eq!(map.insert(0, 300), 4);
+        assert_eq!(map.len(), 3);
+    }
+    #[test]
+    fn test_id_map_sinks() {
+        let mut map = IdMap::default();
+        // The same ID in different sinks is a different notification
+        assert_eq!(map.insert(0, 5), 1);
+        assert_eq!(map.insert(1, 5), 2);
+        assert_eq!(map.local_id(1, 5), Some(2));
+        // Moving to another sink
+        map.bind(1, 1, 7);
+        assert_eq!(map.local_id(0, 5), None);
+        assert_eq!(map.server_id(1), Some((1, 7)));
+        assert_eq!(map.remove_server(1, 5), Some(2));
+        assert_eq!(map.len(), 1);
+        let info = NotificationInfo {
+            critical: true,
+            category: Some("im.received".to_owned()),
+            updated: Some(Instant::now()),
+            sound_file: None,
+            sequence: Some(3),
+            inline_reply: false,
+        };
+        map.set_info(1, info.clone());
+        assert_eq!(map.info(1), Some(&info));
+        assert_eq!(map.iter().collect::<Vec<_>>(), [(1, 1, 7, &info)]);
+    }
+}
@@ -1,38 +1,94 @@
 use bitflags::bitflags;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::Instant;
 use tokio::io::AsyncWriteExt as _;
 use tokio::sync::Mutex;
-use zbus::{dbus_proxy, zvariant::Type, zvariant::Value, Connection};
-#[dbus_proxy(
-    interface = "org.freedesktop.Notifications",
-    default_service = "org.freedesktop.Notifications",
-    default_path = "/org/freedesktop/Notifications"
-)]
-pub trait Notifications {
-    fn get_capabilities(&self) -> zbus::Result<(Vec<String>,)>;
-    fn notify(
-        &self,
-        app_name: String,
-        replaces_id: u32,
-        app_icon: &str,
-        summary: &str,
-        body: &str,
-        actions: &[String],
-        hints: &HashMap<&str, Value<'_>>,
-        expire_timeout: i32,
-    ) -> zbus::Result<u32>;
-    fn close_notification(&self, id: u32) -> zbus::Result<()>;
-    fn get_server_information(&self) -> zbus::Result<(String, String, String, String)>;
-    #[dbus_proxy(signal)]
-    fn notification_closed(&self, id: u32, reason: u32) -> Result<()>;
-    #[dbus_proxy(signal)]
-    fn action_invoked(&self, id: u32, action_key: String) -> Result<()>;
-    // Non-standard KDE extension
-    #[dbus_proxy(signal)]
-    fn notification_replied(&self, id: u32, text: String) -> Result<()>;
+use zbus::{zvariant::Type, zvariant::Value, Connection};
+
+mod actions;
+#[cfg(test)]
+mod adversarial;
+mod cli;
+mod clock;
+mod control;
+mod error;
+mod handover;
+mod history;
+mod idmap;
+mod metrics;
+#[cfg(test)]
+mod mock;
+mod policy;
+mod queue;
+mod ratelimit;
+mod sanitizer;
+mod server_info;
+mod sink;
+mod sound;
+mod trusted;
+use actions::ActionQueue;
+pub use actions::ActionStream;
+pub use cli::{ControlCommand, QubesCommand, SendCommand};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use control::{control_socket_path, known_qubes, send_control, ControlSocket};
+use error::is_transient;
+pub use error::{PolicyRejection, ProxyError};
+pub use handover::{announce_return, ReturnWatch};
+pub use history::{History, HistoryEntry};
+pub use idmap::{IdMap, NotificationInfo};
+pub use metrics::{Metrics, QubeStats};
+pub use policy::{
+    qube_type, Config, MissingSummary, QubePolicy, QuietHours, ReusedId, Target, CONFIG_PATH,
+};
+pub use queue::QubeSendQueue;
+pub use ratelimit::{RateLimiter, ReplaceCooldown, SignalDebounce};
+pub use sanitizer::Sanitizer;
+pub use server_info::{ServerInfo, DEFAULT_SPEC_VERSION};
+pub use sink::{DaemonSink, JsonLinesSink, NotificationSink, SinkFuture};
+pub use sound::{SoundFile, MAX_SOUND_FILE_SIZE};
+pub use trusted::TrustedStr;
+
+/// The D-Bus interface of notification daemons
+mod dbus {
+    // The arguments are those of the D-Bus method
+    #![allow(clippy::too_many_arguments)]
+    use std::collections::HashMap;
+    use zbus::{dbus_proxy, zvariant::Value};
+
+    #[dbus_proxy(
+        interface = "org.freedesktop.Notifications",
+        default_service = "org.freedesktop.Notifications",
+        default_path = "/org/freedesktop/Notifications"
+    )]
+    pub trait Notifications {
+        fn get_capabilities(&self) -> zbus::Result<(Vec<String>,)>;
+        fn notify(
+            &self,
+            app_name: String,
+            replaces_id: u32,
+            app_icon: &str,
+            summary: &str,
+            body: &str,
+            actions: &[String],
+            hints: &HashMap<String, Value<'_>>,
+            expire_timeout: i32,
+        ) -> zbus::Result<u32>;
+        fn close_notification(&self, id: u32) -> zbus::Result<()>;
+        fn get_server_information(&self) -> zbus::Result<(String, String, String, String)>;
+        #[dbus_proxy(signal)]
+        fn notification_closed(&self, id: u32, reason: u32) -> Result<()>;
+        #[dbus_proxy(signal)]
+        fn action_invoked(&self, id: u32, action_key: String) -> Result<()>;
+        // Non-standard KDE extension
+        #[dbus_proxy(signal)]
+        fn notification_replied(&self, id: u32, text: String) -> Result<()>;
+    }
 }
+pub use dbus::*;
 
 pub const MAX_MESSAGE_SIZE: u32 = 0x1_000_000; // max size in bytes
 
@@ -54,7 +110,61 @@ fn is_valid_action_name(action: &[u8]) -> bool {
             _ => return false,
         }
     }
-    return true;
+    true
+}
+
+/// Validate the keys and sanitize the labels of an actions array.  Pairs whose
+/// label is blank are dropped, since they would produce a button with no text.
+/// Pairs with a key or label longer than allowed by `policy` are dropped, or
+/// the whole array is rejected if `policy.reject_long_actions` is set.
+fn sanitize_actions(
+    untrusted_actions: &[String],
+    policy: &QubePolicy,
+) -> Result<Vec<String>, ProxyError> {
+    let mut actions = Vec::with_capacity(untrusted_actions.len());
+    for pair in untrusted_actions.chunks_exact(2) {
+        let too_long = if pair[0].len() > policy.max_action_key_len {
+            "key"
+        } else if pair[1].chars().count() > policy.max_action_label_len {
+            "label"
+        } else {
+            ""
+        };
+        if !too_long.is_empty() {
+            if policy.reject_long_actions {
+                return Err(ProxyError::Validation(format!(
+                    "Action {} too long",
+                    too_long
+                )));
+            }
+            eprintln!("Dropping action with too long {}", too_long);
+            continue;
+        }
+        if !is_valid_action_name(pair[0].as_bytes()) {
+            let invalid = trusted::find_invalid_char(&pair[0], |index, c| match c {
+                'a'..='z' | 'A'..='Z' => true,
+                '0'..='9' | '-' | '.' | '_' => index > 0,
+                _ => false,
+            });
+            return Err(ProxyError::Validation(match invalid {
+                Some(invalid) => format!("Invalid action name: {}", invalid),
+                None => "Invalid action name".to_owned(),
+            }));
+        }
+        if !policy.action_key_allowed(&pair[0]) {
+            eprintln!("Dropping action {:?}: not allowed", pair[0]);
+            continue;
+        }
+        let label = sanitize_str(&pair[1]);
+        if is_blank(&label) {
+            eprintln!("Dropping action {:?} with empty label", pair[0]);
+            continue;
+        }
+        // Sanitized by is_valid_action_name()
+        actions.push(TrustedStr::from_validated(pair[0].to_owned()).into_string());
+        actions.push(label)
+    }
+    Ok(actions)
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -86,7 +196,7 @@ pub enum ReplyMessage {
         /// ID of the dismissed notification.
         id: u32,
         /// Reason the notification was dismissed.
-        reason: u32,
+        reason: CloseReason,
     },
     /// An action was invoked.
     ActionInvoked {
@@ -95,10 +205,79 @@ pub enum ReplyMessage {
         /// Action that was invoked
         action: String,
     },
+    /// A notification was dropped by the proxy.  Since version 1, and only
+    /// sent if enabled for the qube.
+    Suppressed {
+        /// The sequence number of the method call that was dropped
+        sequence: u64,
+        /// Why the notification was dropped
+        reason: String,
+    },
+    /// The actions of a notification were dropped, because the notification
+    /// daemon does not support actions.  They will never be invoked.  Since
+    /// version 3.
+    ActionsUnavailable {
+        /// ID of the notification
+        id: u32,
+    },
+    /// The user replied to a notification with an `inline-reply` action.
+    /// Since version 5.
+    Replied {
+        /// ID of the notification replied to
+        id: u32,
+        /// The sanitized text of the reply
+        text: String,
+    },
+}
+
+/// Why a notification was closed, as reported by `NotificationClosed`
+///
+/// This is sent as the raw `u32` on the wire.  Codes outside the range the
+/// specification defines are treated as [`CloseReason::Undefined`].
+#[repr(u32)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(from = "u32", into = "u32")]
+pub enum CloseReason {
+    /// The notification expired.
+    Expired = 1,
+    /// The notification was dismissed by the user.
+    Dismissed = 2,
+    /// The notification was closed by a call to `CloseNotification`.
+    Closed = 3,
+    /// Undefined or reserved reason.
+    Undefined = 4,
+}
+
+impl From<u32> for CloseReason {
+    fn from(code: u32) -> Self {
+        match code {
+            1 => Self::Expired,
+            2 => Self::Dismissed,
+            3 => Self::Closed,
+            _ => Self::Undefined,
+        }
+    }
+}
+
+impl From<CloseReason> for u32 {
+    fn from(reason: CloseReason) -> Self {
+        reason as u32
+    }
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Expired => "expired",
+            Self::Dismissed => "dismissed",
+            Self::Closed => "closed-by-call",
+            Self::Undefined => "undefined",
+        })
+    }
 }
 
 #[repr(u8)]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Urgency {
     Low = 0,
     Normal = 1,
@@ -109,8 +288,29 @@ pub const MAX_SIZE: usize = 1usize << 21; // This is 2MiB, more than enough
 pub const MAX_WIDTH: i32 = 255;
 pub const MAX_HEIGHT: i32 = 255;
 
+/// Limits on the images a qube can send
+#[derive(Debug, Clone)]
+pub struct ImageLimits {
+    /// Maximum size of the image data in bytes.  Must not exceed `i32::MAX`.
+    pub max_size: usize,
+    /// Maximum width in pixels
+    pub max_width: i32,
+    /// Maximum height in pixels
+    pub max_height: i32,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self {
+            max_size: MAX_SIZE,
+            max_width: MAX_WIDTH,
+            max_height: MAX_HEIGHT,
+        }
+    }
+}
+
 pub const MAJOR_VERSION: u16 = 1;
-pub const MINOR_VERSION: u16 = 0;
+pub const MINOR_VERSION: u16 = 5;
 
 pub const fn merge_versions(major: u16, minor: u16) -> u32 {
     (major as u32) << 16 | (minor as u32)
@@ -139,6 +339,55 @@ pub struct ImageParameters {
     pub untrusted_data: Vec<u8>,
 }
 
+/// Log record for an image that was rejected.  Only the metadata is
+/// included, never the pixels.
+struct ImageRejection<'a> {
+    qube: &'a str,
+    reason: &'static str,
+    width: i32,
+    height: i32,
+    rowstride: i32,
+    has_alpha: bool,
+    bits_per_sample: i32,
+    channels: i32,
+    data_len: usize,
+}
+
+impl<'a> ImageRejection<'a> {
+    fn new(qube: &'a str, image: &ImageParameters) -> Self {
+        Self {
+            qube,
+            reason: "",
+            width: image.untrusted_width,
+            height: image.untrusted_height,
+            rowstride: image.untrusted_rowstride,
+            has_alpha: image.untrusted_has_alpha,
+            bits_per_sample: image.untrusted_bits_per_sample,
+            channels: image.untrusted_channels,
+            data_len: image.untrusted_data.len(),
+        }
+    }
+}
+
+impl std::fmt::Display for ImageRejection<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Rejected image from qube {}: {} (width {}, height {}, rowstride {}, \
+            alpha {}, bits per sample {}, channels {}, {} bytes of data)",
+            self.qube,
+            self.reason,
+            self.width,
+            self.height,
+            self.rowstride,
+            self.has_alpha,
+            self.bits_per_sample,
+            self.channels,
+            self.data_len,
+        )
+    }
+}
+
 fn serialize_image(
     ImageParameters {
         untrusted_width,
@@ -149,7 +398,11 @@ fn serialize_image(
         untrusted_channels,
         untrusted_data,
     }: ImageParameters,
+    limits: &ImageLimits,
 ) -> Result<Value<'static>, &'static str> {
+    // The D-Bus image format cannot describe more data than this
+    debug_assert!(limits.max_size <= i32::MAX as usize);
+
     // sanitize start
 
     // booleans do not need to be sanitized
@@ -163,7 +416,7 @@ fn serialize_image(
     let bits_per_sample = untrusted_bits_per_sample;
 
     // data cannot be too long
-    if untrusted_data.len() > MAX_SIZE {
+    if untrusted_data.len() > limits.max_size {
         return Err("Too much data");
     }
 
@@ -182,18 +435,31 @@ fn serialize_image(
     }
 
     // check that the image is not too large
-    if untrusted_width > MAX_WIDTH || untrusted_height > MAX_HEIGHT {
+    if untrusted_width > limits.max_width || untrusted_height > limits.max_height {
         return Err("Width or height too large");
     }
 
-    // check that the image fits in the buffer
-    if data.len() as i32 / untrusted_height < untrusted_rowstride {
+    // check that the image described by rowstride and height is within the
+    // size limit, independently of how much data was sent, and that the
+    // buffer holds all of it.  This is done with usize to avoid truncating
+    // the length; the casts are lossless as height and rowstride were checked
+    // to be positive.
+    let image_size = match (untrusted_rowstride as usize).checked_mul(untrusted_height as usize) {
+        Some(size) if size <= limits.max_size => size,
+        _ => return Err("Image too large"),
+    };
+    if data.len() < image_size {
         return Err("Image too large");
     }
+    // anything after the last row is not part of the image
+    let mut data = data;
+    data.truncate(image_size);
 
-    // check that the rows fit in the stride
-    if untrusted_rowstride / channels < untrusted_width {
-        return Err("Row stride too small");
+    // check that the rows fit in the stride: each row is width * channels
+    // bytes, and anything after that is padding
+    match untrusted_width.checked_mul(channels) {
+        Some(row_size) if row_size <= untrusted_rowstride => {}
+        _ => return Err("Row stride too small"),
     }
 
     let height = untrusted_height;
@@ -201,7 +467,7 @@ fn serialize_image(
     let rowstride = untrusted_rowstride;
     // sanitize end
 
-    return Ok(Value::from((
+    Ok(Value::from((
         width,
         height,
         rowstride,
@@ -209,7 +475,32 @@ fn serialize_image(
         bits_per_sample,
         channels,
         data,
-    )));
+    )))
+}
+
+/// The names the image hint had in the various versions of the specification
+const IMAGE_HINT_KEYS: [&str; 3] = ["image-data", "image_data", "icon_data"];
+
+/// The image hint key used by version `spec_version` of the specification
+fn image_hint_key(spec_version: (u32, u32)) -> &'static str {
+    match spec_version {
+        (1, 0) => "icon_data",
+        (1, 1) => "image_data",
+        _ => "image-data",
+    }
+}
+
+/// Validate `image` and build the hint that carries it to a daemon that
+/// implements version `spec_version` of the specification.
+fn image_hint(
+    image: ImageParameters,
+    limits: &ImageLimits,
+    spec_version: (u32, u32),
+) -> Result<(&'static str, Value<'static>), &'static str> {
+    Ok((
+        image_hint_key(spec_version),
+        serialize_image(image, limits)?,
+    ))
 }
 
 #[link(kind = "dylib", name = "qubes-pure")]
@@ -217,6 +508,12 @@ extern "C" {
     fn qubes_pure_code_point_safe_for_display(code_point: u32) -> bool;
 }
 
+/// Whether `c` can be displayed without confusing the user
+pub(crate) fn is_safe_for_display(c: char) -> bool {
+    // SAFETY: this function is not actually unsafe
+    unsafe { qubes_pure_code_point_safe_for_display(c.into()) }
+}
+
 /// This imposes the following restrictions:
 ///
 /// - Characters are limited to a safe subset of Unicode.
@@ -230,28 +527,25 @@ pub fn sanitize_str(arg: &str) -> String {
     let mut counter = 0;
     let mut lines = 0;
     while let Some(c) = iter.next() {
-        res.push(
-            // SAFETY: this function is not actually unsafe
-            if unsafe { qubes_pure_code_point_safe_for_display(c.into()) } || c == '\t' {
-                counter += 1;
-                c
-            } else if c == '\n' {
-                counter = 0;
-                lines += 1;
-                c
-            } else if c == '\r' {
-                if iter.peek() == Some(&'\n') {
-                    continue;
-                }
-                counter = 0;
-                lines += 1;
-                '\n'
-            } else {
-                // This is U+FFFD REPLACEMENT CHARACTER
-                counter += 1;
-                '\u{FFFD}'
-            },
-        );
+        res.push(if is_safe_for_display(c) || c == '\t' {
+            counter += 1;
+            c
+        } else if c == '\n' {
+            counter = 0;
+            lines += 1;
+            c
+        } else if c == '\r' {
+            if iter.peek() == Some(&'\n') {
+                continue;
+            }
+            counter = 0;
+            lines += 1;
+            '\n'
+        } else {
+            // This is U+FFFD REPLACEMENT CHARACTER
+            counter += 1;
+            '\u{FFFD}'
+        });
         if counter > 1000 {
             res.push('\n');
             counter = 0;
@@ -264,6 +558,24 @@ pub fn sanitize_str(arg: &str) -> String {
     res
 }
 
+/// Whether `s` would look empty: it contains nothing but whitespace and
+/// invisible characters, such as zero-width spaces.
+pub fn is_blank(s: &str) -> bool {
+    s.chars().all(|c| {
+        c.is_whitespace()
+            || matches!(
+                c,
+                '\u{00AD}' // soft hyphen
+                | '\u{180E}' // Mongolian vowel separator
+                | '\u{200B}'..='\u{200F}' // zero-width spaces, joiners, and marks
+                | '\u{202A}'..='\u{202E}' // bidirectional embeddings and overrides
+                | '\u{2060}'..='\u{2064}' // word joiner and invisible operators
+                | '\u{2066}'..='\u{2069}' // bidirectional isolates
+                | '\u{FEFF}' // zero-width no-break space
+            )
+    })
+}
+
 bitflags! {
     #[derive(Default)]
     pub struct Capabilities: u16 {
@@ -278,58 +590,524 @@ bitflags! {
         const ACTIONS         = 0b00100000000;
         const ACTION_ICONS    = 0b01000000000;
         const INLINE_REPLY    = 0b10000000000;
+        // Not in the specification: the daemon shows the `value` hint
+        const PROGRESS        = 0b100000000000;
+        // Vendor extensions for grouping notifications, see `grouping_hint`
+        const STACK_TAG       = 0b1000000000000;
+        const SYNCHRONOUS     = 0b10000000000000;
    }
 }
 
+/// The arguments of a `Notify` call, after sanitization
+#[derive(Debug, Clone)]
+pub struct PreparedNotification {
+    pub app_name: String,
+    /// The local ID of the notification to replace, or 0
+    pub replaces_id: u32,
+    pub app_icon: String,
+    pub summary: String,
+    pub body: String,
+    pub actions: Vec<String>,
+    pub hints: HashMap<String, Value<'static>>,
+    pub expire_timeout: i32,
+    /// The validated category, which is also in `hints`
+    pub category: Option<String>,
+    /// The validated sound file sent by the qube.  It is written to a file
+    /// of the proxy for the `sound-file` hint when the notification is shown.
+    pub sound_data: Option<Vec<u8>>,
+}
+
+/// The rate limiter for the replacements allowed by `policy`, if they do not
+/// count against the limit for new notifications
+fn replace_limiter(policy: &QubePolicy) -> Option<RateLimiter> {
+    let limit = policy.replace_rate_limit?;
+    Some(RateLimiter::new(limit, policy.rate_limit_window))
+}
+
+/// The next sequence number for a forwarded notification, from the counter
+/// file at `path`.  The file holds the last number taken, in decimal, and is
+/// locked while it is read and written, so the server processes that share
+/// it take one number each.
+fn next_seq_in(path: &Path) -> std::io::Result<u64> {
+    use std::io::{Read as _, Seek as _, Write as _};
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    // Released when the file is closed
+    file.lock()?;
+    let mut last = String::new();
+    file.read_to_string(&mut last)?;
+    let seq = match last.trim_end() {
+        "" => Some(1),
+        last => last
+            .parse::<u64>()
+            .ok()
+            .and_then(|last| last.checked_add(1)),
+    };
+    let seq = seq.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid counter in {}", path.display()),
+        )
+    })?;
+    // The new number is at least as long as the old one, so it overwrites
+    // all of it
+    file.rewind()?;
+    file.write_all(format!("{}\n", seq).as_bytes())?;
+    Ok(seq)
+}
+
+/// Index of the sink for the daemon the emitter was created for
+pub const PRIMARY_SINK: usize = 0;
+
 pub struct NotificationEmitter {
     proxy: NotificationsProxy<'static>,
-    capabilities: Capabilities,
-    prefix: String,
-    application_name: String,
+    /// Where notifications are shown, in order of preference.  The first one
+    /// is the daemon at `proxy`.
+    sinks: Vec<Box<dyn NotificationSink>>,
+    /// Also get every notification that a sink showed
+    mirrors: Vec<Box<dyn NotificationSink>>,
+    capabilities: Cell<Capabilities>,
+    capabilities_known: Cell<bool>,
+    qube_name: String,
+    /// Replaced when the configuration is reloaded
+    policy: RefCell<Rc<QubePolicy>>,
+    rate_limiter: RefCell<RateLimiter>,
+    /// For replacements of open notifications, if they have a budget of
+    /// their own
+    replace_limiter: RefCell<Option<RateLimiter>>,
+    replace_cooldown: RefCell<ReplaceCooldown>,
+    signal_debounce: RefCell<SignalDebounce>,
+    signal_limiter: RefCell<RateLimiter>,
+    ids: Mutex<IdMap>,
+    metrics: RefCell<Metrics>,
+    stats_since: Cell<Instant>,
+    /// Sequence number of the last notification forwarded.  Resetting the
+    /// statistics keeps it.
+    last_seq: Cell<Option<u64>>,
+    /// See [`Self::with_sequence_file`]
+    sequence_file: Option<PathBuf>,
+    actions: Rc<RefCell<ActionQueue>>,
+    /// Inline replies, sanitized
+    replies: Rc<RefCell<ActionQueue>>,
+    /// See [`Self::with_namespace`]
+    namespace: Option<String>,
+    history: RefCell<History>,
+    /// Notifications held until the quiet hours are over, with the local ID
+    /// the qube got for each, oldest first
+    held: RefCell<VecDeque<(u32, PreparedNotification)>>,
+    /// Shared with the emitters of other qubes, if any
+    send_queue: Option<QubeSendQueue>,
+    /// Fetched once per connection to the daemon, and on request
+    server_info: RefCell<ServerInfo>,
+    clock: Rc<dyn Clock>,
 }
 
 impl NotificationEmitter {
     pub fn capabilities(&self) -> Capabilities {
-        self.capabilities
-    }
-    pub async fn new(prefix: String, application_name: String) -> zbus::Result<Self> {
-        let connection = Connection::session().await?;
-        let proxy = NotificationsProxy::new(&connection).await?;
-        let capabilities_list = proxy.get_capabilities().await?.0;
-        let mut capabilities = Capabilities::default();
-        for capability_str in capabilities_list.into_iter() {
-            match &*capability_str {
-                "action-icons" => capabilities |= Capabilities::ACTION_ICONS,
-                "persistence" => capabilities |= Capabilities::PERSISTENCE,
-                "body-markup" => capabilities |= Capabilities::BODY_MARKUP,
-                "sound" => capabilities |= Capabilities::SOUND,
-                "body" => capabilities |= Capabilities::BODY,
-                "body-hyperlinks" => capabilities |= Capabilities::BODY_HYPERLINKS,
-                "body-images" => capabilities |= Capabilities::BODY_IMAGES,
-                "icon-static" => capabilities |= Capabilities::ICON_STATIC,
-                "actions" => capabilities |= Capabilities::ACTIONS,
-                "icon-multi" => capabilities |= Capabilities::ICON_MULTI,
-                "inline-reply" => capabilities |= Capabilities::INLINE_REPLY,
-                _ => eprintln!("Unknown capability {} detected", capability_str),
+        self.capabilities.get()
+    }
+    /// Whether the capabilities of the daemon are known.  Until they are,
+    /// the daemon is assumed to have none.
+    pub fn capabilities_known(&self) -> bool {
+        self.capabilities_known.get()
+    }
+    /// Fetch the capabilities of the daemon again
+    pub async fn refresh_capabilities(&self) -> zbus::Result<Capabilities> {
+        let capabilities = fetch_capabilities(&self.proxy).await?;
+        self.capabilities.set(capabilities);
+        self.capabilities_known.set(true);
+        Ok(capabilities)
+    }
+    /// Fetch the capabilities of the daemon until it works, if they are not
+    /// known yet.  The delay between attempts starts at the retry delay of
+    /// the policy and doubles up to a minute.
+    pub async fn fetch_missing_capabilities(&self) {
+        let mut delay = self.policy().retry_delay;
+        while !self.capabilities_known() {
+            self.clock.sleep_until(self.clock.now() + delay).await;
+            match self.refresh_capabilities().await {
+                Ok(_) => eprintln!("Got the capabilities of the daemon"),
+                Err(e) => eprintln!("Still cannot get the capabilities of the daemon: {}", e),
             }
+            delay = (delay * 2).min(std::time::Duration::from_secs(60));
         }
-        eprintln!(
-            "Server capabilities: body markup {}, persistence {}",
-            capabilities.contains(Capabilities::BODY_MARKUP),
-            capabilities.contains(Capabilities::PERSISTENCE),
-        );
+    }
+    /// The capabilities to advertise to the qube.  Links and images in the
+    /// body are always escaped, so their capabilities are hidden, and so is
+    /// markup if it is disabled by policy.
+    pub fn filtered_capabilities(&self) -> Capabilities {
+        let mut capabilities = self.capabilities();
+        capabilities.remove(Capabilities::BODY_HYPERLINKS | Capabilities::BODY_IMAGES);
+        if !self.policy().markup {
+            capabilities.remove(Capabilities::BODY_MARKUP)
+        }
+        if !self.policy().allow_body {
+            capabilities.remove(Capabilities::BODY | Capabilities::BODY_MARKUP)
+        }
+        capabilities
+    }
+    /// The policy currently in force
+    pub fn policy(&self) -> Rc<QubePolicy> {
+        self.policy.borrow().clone()
+    }
+    /// Apply a reloaded policy.  It only affects notifications sent from now
+    /// on: those already open keep their IDs, so they can still be closed
+    /// and their signals are still routed back to the qube, even if the new
+    /// policy would have blocked them.  The rate limiters restart only if
+    /// their settings changed.
+    pub fn set_policy(&self, policy: QubePolicy) {
+        let old = self.policy.replace(Rc::new(policy));
+        let new = self.policy();
+        if (old.rate_limit, old.rate_limit_window) != (new.rate_limit, new.rate_limit_window) {
+            *self.rate_limiter.borrow_mut() =
+                RateLimiter::new(new.rate_limit, new.rate_limit_window);
+        }
+        if (old.replace_rate_limit, old.rate_limit_window)
+            != (new.replace_rate_limit, new.rate_limit_window)
+        {
+            *self.replace_limiter.borrow_mut() = replace_limiter(&new);
+        }
+        if (old.signal_rate_limit, old.signal_rate_limit_window)
+            != (new.signal_rate_limit, new.signal_rate_limit_window)
+        {
+            *self.signal_limiter.borrow_mut() =
+                RateLimiter::new(new.signal_rate_limit, new.signal_rate_limit_window);
+        }
+        if old.signal_debounce != new.signal_debounce {
+            *self.signal_debounce.borrow_mut() = SignalDebounce::new(new.signal_debounce);
+        }
+    }
+    /// Counters for the notifications sent so far
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.borrow().clone()
+    }
+    /// Add `sink` as a fallback, used if all previous sinks fail.  Its index
+    /// is the number of sinks before it.
+    pub fn with_fallback(mut self, sink: Box<dyn NotificationSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+    /// Also send every notification that was shown to `sink`, such as a
+    /// [`JsonLinesSink`].  Mirrors do not affect delivery: their errors are
+    /// only logged.  They see every notification as a new one, and are never
+    /// asked to close any.
+    pub fn with_mirror(mut self, sink: Box<dyn NotificationSink>) -> Self {
+        self.mirrors.push(sink);
+        self
+    }
+    /// Queue notifications in `queue`, which bounds the notifications of the
+    /// qube waiting to be sent
+    pub fn with_send_queue(mut self, queue: QubeSendQueue) -> Self {
+        self.send_queue = Some(queue);
+        self
+    }
+    /// Keep the state that is visible outside of this proxy, such as the
+    /// grouping tags and sound files, apart from that of proxies for other
+    /// targets.  See [`Target::namespace`].
+    pub fn with_namespace(mut self, namespace: String) -> Self {
+        self.namespace = Some(namespace);
+        self
+    }
+    /// Take the sequence numbers of forwarded notifications from the counter
+    /// file at `path`, instead of the one in the runtime directory that all
+    /// the server processes of the user share
+    pub fn with_sequence_file(mut self, path: PathBuf) -> Self {
+        self.sequence_file = Some(path);
+        self
+    }
+    /// Use `clock` instead of the system clock for the time-based policies
+    pub fn with_clock(mut self, clock: Rc<dyn Clock>) -> Self {
+        self.stats_since.set(clock.now());
+        self.clock = clock;
+        self
+    }
+    /// Information about the notification daemon
+    pub fn server_info(&self) -> ServerInfo {
+        self.server_info.borrow().clone()
+    }
+    /// Fetch the capabilities and the information of the daemon again, and
+    /// log what changed.  A different daemon can take over the bus name
+    /// without the connection noticing, such as after a theme change.  If
+    /// either call fails, the cached information stays as it was.
+    pub async fn refresh_daemon_info(&self) -> zbus::Result<()> {
+        let server_info = fetch_server_info(&self.proxy).await?;
+        let old_capabilities = self.capabilities();
+        let capabilities = self.refresh_capabilities().await?;
+        if capabilities != old_capabilities {
+            eprintln!(
+                "Daemon capabilities changed from {:?} to {:?}",
+                old_capabilities, capabilities
+            )
+        }
+        let old_server_info = self.server_info.replace(server_info);
+        if *self.server_info.borrow() != old_server_info {
+            eprintln!(
+                "Server information changed from {:?} to {:?}",
+                old_server_info,
+                self.server_info.borrow()
+            )
+        }
+        Ok(())
+    }
+    /// Statistics about the notifications of the qube since the last call to
+    /// [`Self::reset_stats`]
+    pub fn qube_stats(&self) -> QubeStats {
+        let metrics = self.metrics.borrow();
+        QubeStats::new(&metrics, self.stats_since.get(), self.last_seq.get())
+    }
+    /// The last notifications forwarded, oldest first
+    pub fn history(&self) -> Vec<HistoryEntry> {
+        self.history.borrow().entries().cloned().collect()
+    }
+    /// The name of the qube whose notifications this emitter forwards
+    pub fn qube_name(&self) -> &str {
+        &self.qube_name
+    }
+    /// Whether the qube has open notifications or sent one in the current
+    /// rate limit window
+    pub async fn is_active(&self) -> bool {
+        !self.ids.lock().await.is_empty()
+            || !self.held.borrow().is_empty()
+            || self.rate_limiter.borrow().is_active(self.clock.now())
+    }
+    /// Reset the statistics and all other metrics
+    pub fn reset_stats(&self) {
+        *self.metrics.borrow_mut() = Metrics::default();
+        self.stats_since.set(self.clock.now())
+    }
+    /// Create an emitter for the notifications of the qube `qube_name`
+    pub async fn new(
+        connection: &Connection,
+        qube_name: String,
+        policy: QubePolicy,
+    ) -> zbus::Result<Self> {
+        let proxy = NotificationsProxy::new(connection).await?;
+        // The daemon may be starting up.  Rather than fail, assume it can do
+        // as little as possible until its capabilities can be fetched.
+        let (capabilities, capabilities_known) = match fetch_capabilities(&proxy).await {
+            Ok(capabilities) => (capabilities, true),
+            Err(e) => {
+                eprintln!("Cannot get capabilities, assuming none: {}", e);
+                (Capabilities::empty(), false)
+            }
+        };
+        let server_info = match fetch_server_info(&proxy).await {
+            Ok(server_info) => server_info,
+            Err(e) => {
+                eprintln!("Cannot get server information: {}", e);
+                ServerInfo::unknown()
+            }
+        };
+        let rate_limiter = RefCell::new(RateLimiter::new(
+            policy.rate_limit,
+            policy.rate_limit_window,
+        ));
+        let replace_limiter = RefCell::new(replace_limiter(&policy));
+        let actions = Rc::new(RefCell::new(ActionQueue::new(policy.action_queue_size)));
+        let replies = Rc::new(RefCell::new(ActionQueue::new(policy.action_queue_size)));
+        let replace_cooldown = RefCell::new(ReplaceCooldown::new(policy.replace_cooldown));
+        let signal_debounce = RefCell::new(SignalDebounce::new(policy.signal_debounce));
+        let history = RefCell::new(History::new(policy.history_size));
+        let signal_limiter = RefCell::new(RateLimiter::new(
+            policy.signal_rate_limit,
+            policy.signal_rate_limit_window,
+        ));
+        let primary = DaemonSink::from_proxy("primary".to_owned(), proxy.clone());
         Ok(Self {
             proxy,
-            capabilities,
-            prefix,
-            application_name,
+            sinks: vec![Box::new(primary)],
+            mirrors: vec![],
+            capabilities: Cell::new(capabilities),
+            capabilities_known: Cell::new(capabilities_known),
+            qube_name,
+            policy: RefCell::new(Rc::new(policy)),
+            rate_limiter,
+            replace_limiter,
+            replace_cooldown,
+            signal_debounce,
+            signal_limiter,
+            ids: Mutex::new(IdMap::default()),
+            metrics: RefCell::new(Metrics::default()),
+            stats_since: Cell::new(Instant::now()),
+            last_seq: Cell::new(None),
+            sequence_file: None,
+            actions,
+            replies,
+            namespace: None,
+            history,
+            held: RefCell::new(VecDeque::new()),
+            server_info: RefCell::new(server_info),
+            send_queue: None,
+            clock: Rc::new(SystemClock),
         })
     }
 }
 
+/// Parse the reply to `GetCapabilities`
+fn parse_capabilities(capabilities_list: Vec<String>) -> Capabilities {
+    let mut capabilities = Capabilities::default();
+    for capability_str in capabilities_list.into_iter() {
+        match &*capability_str {
+            "action-icons" => capabilities |= Capabilities::ACTION_ICONS,
+            "persistence" => capabilities |= Capabilities::PERSISTENCE,
+            "body-markup" => capabilities |= Capabilities::BODY_MARKUP,
+            "sound" => capabilities |= Capabilities::SOUND,
+            "body" => capabilities |= Capabilities::BODY,
+            "body-hyperlinks" => capabilities |= Capabilities::BODY_HYPERLINKS,
+            "body-images" => capabilities |= Capabilities::BODY_IMAGES,
+            "icon-static" => capabilities |= Capabilities::ICON_STATIC,
+            "actions" => capabilities |= Capabilities::ACTIONS,
+            "icon-multi" => capabilities |= Capabilities::ICON_MULTI,
+            "inline-reply" => capabilities |= Capabilities::INLINE_REPLY,
+            "progress" => capabilities |= Capabilities::PROGRESS,
+            "x-dunst-stack-tag" => capabilities |= Capabilities::STACK_TAG,
+            "x-canonical-private-synchronous" => capabilities |= Capabilities::SYNCHRONOUS,
+            _ => eprintln!("Unknown capability {} detected", capability_str),
+        }
+    }
+    capabilities
+}
+
+/// Separates the name of the qube from the summary chosen by the qube
+const PROVENANCE_SEPARATOR: &str = ": ";
+
+/// Key of the action for replying with text, for daemons with the
+/// `inline-reply` capability.  Other daemons show it as a normal action.
+const INLINE_REPLY_ACTION: &str = "inline-reply";
+
+/// The name of `qube`, or its display prefix, as shown to the user in front
+/// of its notifications.
+/// The summary after it is untrusted, so the name is the anchor: everything
+/// up to the first separator must be the name.  Real qube names cannot
+/// contain a colon, but names from elsewhere, such as the command line,
+/// can, so colons are replaced like the characters that sanitizing removes.
+fn provenance_label(qube: &str) -> String {
+    qube.replace(':', "\u{FFFD}")
+}
+
+/// Separates the summary from a body folded into it
+const FOLD_SEPARATOR: &str = " \u{2014} ";
+
+/// Append `body` to `summary`, on a single line, for daemons that do not
+/// show bodies.  The result is at most `max_len` characters long, unless
+/// `summary` already is, in which case it is returned as is.
+fn fold_body(summary: &str, body: &str, max_len: usize) -> String {
+    let used = summary.chars().count() + FOLD_SEPARATOR.chars().count();
+    // Room for at least one character of the body and the ellipsis
+    if body.is_empty() || used + 2 > max_len {
+        return summary.to_owned();
+    }
+    let mut folded = summary.to_owned() + FOLD_SEPARATOR;
+    let mut body = body.chars().map(|c| match c {
+        '\n' | '\t' => ' ',
+        c => c,
+    });
+    folded.extend(body.by_ref().take(max_len - used));
+    if body.next().is_some() {
+        folded.pop();
+        folded.push('\u{2026}')
+    }
+    folded
+}
+
+/// Fetch the capabilities of the daemon
+async fn fetch_capabilities(proxy: &NotificationsProxy<'_>) -> zbus::Result<Capabilities> {
+    let capabilities_list = call_lenient::<(Vec<String>,)>(proxy, "GetCapabilities")
+        .await?
+        .map_or_else(Vec::new, |(list,)| list);
+    let capabilities = parse_capabilities(capabilities_list);
+    eprintln!(
+        "Server capabilities: body markup {}, persistence {}",
+        capabilities.contains(Capabilities::BODY_MARKUP),
+        capabilities.contains(Capabilities::PERSISTENCE),
+    );
+    Ok(capabilities)
+}
+
+/// Fetch the information about the daemon
+async fn fetch_server_info(proxy: &NotificationsProxy<'_>) -> zbus::Result<ServerInfo> {
+    let reply = call_lenient(proxy, "GetServerInformation").await?;
+    Ok(reply.map_or_else(ServerInfo::unknown, ServerInfo::parse_lenient))
+}
+
+/// Call the method `method` of the daemon, which takes no arguments.  A reply
+/// of an unexpected type is logged and gives `None`, so that daemons with
+/// quirks can still be used.
+async fn call_lenient<T>(proxy: &NotificationsProxy<'_>, method: &str) -> zbus::Result<Option<T>>
+where
+    T: serde::de::DeserializeOwned + zbus::zvariant::Type,
+{
+    let reply = proxy.inner().call_method(method, &()).await?;
+    match reply.body::<T>() {
+        Ok(body) => Ok(Some(body)),
+        Err(e) => {
+            eprintln!(
+                "Ignoring unexpected reply to {} with signature {:?}: {}",
+                method,
+                reply.body_signature().map(|s| s.to_string()),
+                e
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// The identity under which [`self_test`] sends its probe.  Qube names
+/// cannot start with `@`, so it cannot be mistaken for a real qube.
+pub const SELF_TEST_QUBE: &str = "@self-test";
+
+/// Check that notifications can be shown on `connection` by sending a probe
+/// notification through the whole pipeline and closing it right away.  The
+/// probe has its own emitter, so it does not count in the statistics of any
+/// qube.
+pub async fn self_test(connection: &Connection) -> Result<(), ProxyError> {
+    let policy = QubePolicy {
+        retries: 0,
+        ..QubePolicy::default()
+    };
+    let emitter = NotificationEmitter::new(connection, SELF_TEST_QUBE.to_owned(), policy).await?;
+    let probe = Notification::V2 {
+        suppress_sound: true,
+        transient: true,
+        urgency: Some(Urgency::Low),
+        replaces_id: 0,
+        summary: "Notification proxy self-test".to_owned(),
+        body: String::new(),
+        actions: vec![],
+        category: None,
+        expire_timeout: 1000,
+        image: None,
+        hints: vec![],
+    };
+    let id = emitter.send_notification(probe).await?;
+    emitter.close(id).await?;
+    Ok(())
+}
+
+/// Close every notification open in `emitters`, such as for a "clear all"
+/// button.  Returns the messages to send to the qube of each emitter, in
+/// the same order.
+pub async fn close_all(emitters: &[&NotificationEmitter]) -> Vec<Vec<ReplyMessage>> {
+    let mut messages = vec![];
+    for emitter in emitters {
+        messages.push(emitter.close_all().await)
+    }
+    messages
+}
+
 #[derive(Debug, Clone)]
 pub struct MessageWriter(Rc<Mutex<tokio::io::Stdout>>);
 
+impl Default for MessageWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MessageWriter {
     pub fn new() -> Self {
         Self(Rc::new(Mutex::new(tokio::io::stdout())))
@@ -342,7 +1120,7 @@ impl MessageWriter {
             .await
             .expect("error writing to stdout");
         guard
-            .write_all(&*data)
+            .write_all(data)
             .await
             .expect("error writing to stdout");
         guard.flush().await.expect("error writing to stdout");
@@ -361,194 +1139,1191 @@ pub enum Notification {
         suppress_sound: bool,
         transient: bool,
         urgency: Option<Urgency>,
-        // This is just an ID, and it can't be validated in a non-racy way anyway.
-        // I assume that any decent notification daemon will handle an invalid ID
-        // value correctly, but this code should probably test for this at the start
-        // so that it cannot be used with a server that crashes in this case.
+        // This is an ID local to the qube, which the proxy translates to the ID
+        // used by the daemon.  If the notification is no longer open, a new one
+        // is created instead.
+        replaces_id: u32,
+        summary: String,
+        // Only a small subset of markup is allowed, and only if the daemon
+        // supports markup.  See `sanitize_markup`.
+        body: String,
+        actions: Vec<String>,
+        category: Option<String>,
+        expire_timeout: i32,
+        image: Option<ImageParameters>,
+    },
+    /// Since version 2
+    V2 {
+        suppress_sound: bool,
+        transient: bool,
+        urgency: Option<Urgency>,
         replaces_id: u32,
         summary: String,
-        // FIXME: support markup (strictly sanitized and validated) if the server
-        // supports it.
         body: String,
         actions: Vec<String>,
         category: Option<String>,
         expire_timeout: i32,
         image: Option<ImageParameters>,
+        /// Other hints.  Not trusted, and filtered by the proxy.
+        hints: Vec<(String, HintValue)>,
     },
 }
 
-impl NotificationEmitter {
-    #[inline]
-    /// Whether the server supports persistence
-    pub fn persistence(&self) -> bool {
-        self.capabilities.contains(Capabilities::PERSISTENCE)
-    }
-    #[inline]
-    /// Whether the server supports sound
-    pub fn sound(&self) -> bool {
-        self.capabilities.contains(Capabilities::SOUND)
-    }
-    #[inline]
-    /// Whether the server supports actions
-    pub fn actions(&self) -> bool {
-        self.capabilities.contains(Capabilities::ACTIONS)
+impl Notification {
+    /// Convert to the latest version
+    pub fn upgrade(self) -> Self {
+        match self {
+            Self::V1 {
+                suppress_sound,
+                transient,
+                urgency,
+                replaces_id,
+                summary,
+                body,
+                actions,
+                category,
+                expire_timeout,
+                image,
+            } => Self::V2 {
+                suppress_sound,
+                transient,
+                urgency,
+                replaces_id,
+                summary,
+                body,
+                actions,
+                category,
+                expire_timeout,
+                image,
+                hints: vec![],
+            },
+            v2 @ Self::V2 { .. } => v2,
+        }
     }
-    #[inline]
-    /// Whether the server supports body markup
-    pub fn body_markup(&self) -> bool {
-        self.capabilities.contains(Capabilities::BODY_MARKUP)
+
+    /// The local ID of the notification this one replaces, or 0
+    pub fn replaces_id(&self) -> u32 {
+        match self {
+            Self::V1 { replaces_id, .. } | Self::V2 { replaces_id, .. } => *replaces_id,
+        }
     }
-    #[inline]
-    /// Whether the server supports notification bodies
-    pub fn body(&self) -> bool {
-        self.capabilities.contains(Capabilities::BODY)
+
+    /// Whether the notification has any actions
+    pub fn has_actions(&self) -> bool {
+        match self {
+            Self::V1 { actions, .. } | Self::V2 { actions, .. } => !actions.is_empty(),
+        }
     }
-    pub async fn closed(&self) -> zbus::Result<NotificationClosedStream<'static>> {
-        self.proxy.receive_notification_closed().await
+}
+
+/// Value of a hint sent by a qube
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum HintValue {
+    Boolean(bool),
+    Byte(u8),
+    Int32(i32),
+    UInt32(u32),
+    String(String),
+    /// The contents of a file, such as for the `sound-file` hint.  Sent from
+    /// protocol version 1.4 on.
+    Bytes(Vec<u8>),
+}
+
+/// The value of a hint that the specification says is a boolean.  If
+/// `coerce` is set, the integers 0 and 1 are accepted as well.  Only use
+/// this for such hints: other hints are typed strictly.
+fn boolean_hint(value: &HintValue, coerce: bool) -> Option<bool> {
+    match *value {
+        HintValue::Boolean(value) => Some(value),
+        HintValue::Byte(value @ (0 | 1)) if coerce => Some(value == 1),
+        HintValue::Int32(value @ (0 | 1)) if coerce => Some(value == 1),
+        HintValue::UInt32(value @ (0 | 1)) if coerce => Some(value == 1),
+        _ => None,
     }
-    pub async fn invocations(&self) -> zbus::Result<ActionInvokedStream<'static>> {
-        self.proxy.receive_action_invoked().await
+}
+
+/// The percentage in a `value` hint, if it is valid
+fn progress_value(value: &HintValue) -> Option<u8> {
+    let percent: i64 = match *value {
+        HintValue::Byte(v) => v.into(),
+        HintValue::Int32(v) => v.into(),
+        HintValue::UInt32(v) => v.into(),
+        _ => return None,
+    };
+    match percent {
+        0..=100 => Some(percent as u8),
+        _ => None,
     }
-    pub async fn replies(&self) -> zbus::Result<NotificationRepliedStream<'static>> {
-        self.proxy.receive_notification_replied().await
+}
+
+/// Whether the percentage of a `value` hint is appended to the summary
+/// instead of being forwarded, because the daemon does not show progress
+fn render_progress(capabilities: Capabilities, policy: &QubePolicy) -> bool {
+    policy.render_progress_in_summary && !capabilities.contains(Capabilities::PROGRESS)
+}
+
+/// Whether `name` follows the XDG sound naming specification: lowercase
+/// words of ASCII letters and digits, separated by single dashes.
+fn is_valid_sound_name(name: &[u8]) -> bool {
+    if name.is_empty() || name.len() > 255 {
+        return false;
     }
-    pub async fn send_notification(
-        &self,
-        Notification::V1 {
-            suppress_sound,
-            transient,
-            urgency,
-            replaces_id,
-            summary: untrusted_summary,
-            body: untrusted_body,
-            actions: untrusted_actions,
-            category: untrusted_category,
-            expire_timeout,
-            image,
-        }: Notification,
-    ) -> zbus::Result<u32> {
-        if expire_timeout < -1 {
-            return Err(zbus::Error::Unsupported);
+    for word in name.split(|&c| c == b'-') {
+        if word.is_empty() {
+            return false;
         }
-
-        if untrusted_actions.len() & 1 != 0 {
-            return Err(zbus::Error::Failure(format!(
-                "Actions must have an even length, got {}",
-                untrusted_actions.len()
-            )));
+        for i in word {
+            match i {
+                b'a'..=b'z' | b'0'..=b'9' => {}
+                _ => return false,
+            }
         }
+    }
+    true
+}
 
-        // In the future this should be a validated application name prefixed
-        // by the qube name.
-        let application_name = self.application_name.clone();
-
-        // Ideally the icon would be associated with the calling application,
-        // with an image suitably processed by Qubes OS to indicate trust.
-        // However, there is no good way to do that in practice, so just pass
-        // an empty string to indicate "no icon".
-        let icon = "";
-        let actions = if self.actions() {
-            let mut actions = Vec::with_capacity(untrusted_actions.len());
-            for (count, s) in untrusted_actions.iter().enumerate() {
-                if count & 1 == 0 {
-                    if !is_valid_action_name(s.as_bytes()) {
-                        return Err(zbus::Error::Failure("Invalid action name".to_owned()));
-                    }
-                    // Sanitized by is_valid_action_name()
-                    actions.push(s.to_owned())
-                } else {
-                    actions.push(sanitize_str(&*s))
-                }
-            }
-            actions
-        } else {
-            vec![]
-        };
+/// Upper bound on the serialized size of `value`, in bytes
+fn estimated_value_size(value: &Value<'_>) -> usize {
+    // signature, length, and alignment overhead
+    8 + match value {
+        Value::Str(s) => s.len(),
+        Value::Array(a) => a.iter().map(estimated_value_size).sum(),
+        Value::Structure(s) => s.fields().iter().map(estimated_value_size).sum(),
+        Value::Value(v) => estimated_value_size(v),
+        _ => 8,
+    }
+}
 
-        // this is slow but I don't care, the D-Bus call is orders of magnitude slower
-        // Set up the hints
-        let mut hints = HashMap::new();
-        if let Some(urgency) = urgency {
-            // this is a hack to appease the borrow checker
-            let urgency = match urgency {
-                Urgency::Low => &0,
-                Urgency::Normal => &1,
-                Urgency::Critical => &2,
-            };
-            hints.insert(
-                "urgency",
-                <zbus::zvariant::Value<'_> as From<&'_ u8>>::from(urgency),
-            );
-        }
-        if suppress_sound && self.capabilities.contains(Capabilities::SOUND) {
-            hints.insert("suppress-sound", Value::from(&true));
-        }
-        if transient && self.persistence() {
-            hints.insert("transient", Value::from(&true));
-        }
-        if let Some(ref untrusted_category) = untrusted_category {
-            let category = untrusted_category.as_bytes();
-            match category.get(0) {
-                Some(b'a'..=b'z') => {}
-                _ => return Err(zbus::Error::MissingParameter("Invalid category")),
+/// Make a `Notify` call fit in `max_size` bytes, by first dropping the image
+/// and then truncating the body.  `strings` are the other string arguments.
+/// If `markup` is set, `body` is body markup sanitized by [`sanitize_markup`],
+/// and it is still sanitized once truncated.
+fn shed_to_fit(
+    max_size: usize,
+    strings: &[&str],
+    body: &mut String,
+    actions: &[String],
+    hints: &mut HashMap<String, Value<'_>>,
+    markup: bool,
+) -> Result<(), ProxyError> {
+    // Message header and the integer arguments
+    let fixed_size = 256
+        + strings.iter().map(|s| s.len() + 8).sum::<usize>()
+        + actions.iter().map(|s| s.len() + 8).sum::<usize>();
+    let hints_size = |hints: &HashMap<String, Value<'_>>| -> usize {
+        hints
+            .iter()
+            .map(|(key, value)| key.len() + 16 + estimated_value_size(value))
+            .sum()
+    };
+    let size = fixed_size + body.len() + 8 + hints_size(hints);
+    if size <= max_size {
+        return Ok(());
+    }
+    let mut dropped = false;
+    for key in IMAGE_HINT_KEYS {
+        dropped |= hints.remove(key).is_some();
+    }
+    if dropped {
+        eprintln!(
+            "Notification too large ({} bytes, limit {}), dropping image",
+            size, max_size
+        );
+    }
+    let size = fixed_size + 8 + hints_size(hints);
+    if size > max_size {
+        return Err(ProxyError::Validation("Notification too large".to_owned()));
+    }
+    let available = max_size - size;
+    if body.len() > available {
+        eprintln!(
+            "Notification too large, truncating body from {} to {} bytes",
+            body.len(),
+            available
+        );
+        let mut cut = available;
+        loop {
+            while !body.is_char_boundary(cut) {
+                cut -= 1;
             }
-            for i in &category[1..] {
-                match i {
-                    b'a'..=b'z' | b'.' => {}
-                    _ => return Err(zbus::Error::MissingParameter("Invalid category")),
+            // Do not cut an escaped character in half
+            if let Some(amp) = body[..cut].rfind('&') {
+                if !body[amp..cut].contains(';') {
+                    cut = amp
                 }
             }
-            // no underflow possible, category.get() checks for the empty slice
-            if category[category.len() - 1] == b'.' {
-                return Err(zbus::Error::MissingParameter("Invalid category"));
-            }
-            // sanitize end
-            hints.insert("category", Value::from(category));
-        }
-        if let Some(image) = image {
-            match serialize_image(image) {
-                Ok(value) => hints.insert("image-data", value),
-                Err(e) => return Err(zbus::Error::MissingParameter(e)),
+            // The tags that the cut leaves open are escaped, which makes
+            // them longer, so cut further until the result fits
+            let truncated = match markup {
+                true => sanitize_markup(&body[..cut]),
+                false => body[..cut].to_owned(),
             };
-        }
-        let mut escaped_body;
-        if self.body_markup() {
-            let body = sanitize_str(&*untrusted_body);
-            // Body markup must be escaped.  FIXME: validate it instead.
-            escaped_body = String::with_capacity(body.as_bytes().len());
-            // this is slow and can easily be made much faster with
-            // trivially correct `unsafe`, but the D-Bus call (which
-            // actually renders text on screen!) will be orders of
-            // magnitude slower so we do not care.
-            for i in body.chars() {
-                match i {
-                    '<' => escaped_body.push_str("&lt;"),
-                    '>' => escaped_body.push_str("&gt;"),
-                    '&' => escaped_body.push_str("&amp;"),
-                    '\'' => escaped_body.push_str("&apos;"),
-                    '"' => escaped_body.push_str("&quot;"),
-                    x => escaped_body.push(x),
-                }
+            if truncated.len() <= available {
+                *body = truncated;
+                break;
             }
-        } else {
-            escaped_body = sanitize_str(&*untrusted_body)
+            cut = cut.saturating_sub(truncated.len() - available)
         }
-        self.proxy
-            .notify(
-                application_name,
-                replaces_id,
-                icon,
-                &*(self.prefix.clone() + &*sanitize_str(&*untrusted_summary)),
-                &*escaped_body,
-                &*actions,
-                &hints,
-                expire_timeout,
-            )
-            .await
     }
+    Ok(())
 }
 
-#[cfg(test)]
+/// Whether `category` is a valid category name: lowercase ASCII letters
+/// separated by dots
+fn is_valid_category(category: &str) -> bool {
+    let category = category.as_bytes();
+    match category.first() {
+        Some(b'a'..=b'z') => {}
+        _ => return false,
+    }
+    for i in &category[1..] {
+        match i {
+            b'a'..=b'z' | b'.' => {}
+            _ => return false,
+        }
+    }
+    // no underflow possible, category.first() checks for the empty slice
+    category[category.len() - 1] != b'.'
+}
+
+/// Where the problems found while preparing a notification go
+struct Problems {
+    fail_fast: bool,
+    found: Vec<ProxyError>,
+}
+
+impl Problems {
+    /// Stop at the first problem
+    fn fail_fast() -> Self {
+        Self {
+            fail_fast: true,
+            found: vec![],
+        }
+    }
+    /// Keep going and remember every problem
+    fn collect() -> Self {
+        Self {
+            fail_fast: false,
+            found: vec![],
+        }
+    }
+    /// Report `error`.  Returns it back if preparation must stop.
+    fn report(&mut self, error: ProxyError) -> Result<(), ProxyError> {
+        if self.fail_fast {
+            return Err(error);
+        }
+        self.found.push(error);
+        Ok(())
+    }
+}
+
+/// Escape the characters of `body` that have a meaning in body markup
+fn escape_markup(body: &str) -> String {
+    let mut escaped_body = String::with_capacity(body.len());
+    // this is slow and can easily be made much faster with
+    // trivially correct `unsafe`, but the D-Bus call (which
+    // actually renders text on screen!) will be orders of
+    // magnitude slower so we do not care.
+    for i in body.chars() {
+        push_escaped(&mut escaped_body, i)
+    }
+    escaped_body
+}
+
+fn push_escaped(out: &mut String, c: char) {
+    match c {
+        '<' => out.push_str("&lt;"),
+        '>' => out.push_str("&gt;"),
+        '&' => out.push_str("&amp;"),
+        '\'' => out.push_str("&apos;"),
+        '"' => out.push_str("&quot;"),
+        x => out.push(x),
+    }
+}
+
+/// Tags kept by [`sanitize_markup`].  Attributes are never allowed.
+const MARKUP_TAGS: [&str; 6] = ["<b>", "<i>", "<u>", "</b>", "</i>", "</u>"];
+/// Entities kept by [`sanitize_markup`].  Numeric references could smuggle
+/// in the characters that sanitizing removed, so they are escaped.
+const MARKUP_ENTITIES: [&str; 5] = ["&amp;", "&lt;", "&gt;", "&apos;", "&quot;"];
+/// Tags nested deeper than this are escaped
+const MAX_MARKUP_DEPTH: usize = 8;
+
+/// Sanitize the body markup in `body`.  Bold, italic and underline tags and
+/// the predefined entities are kept where they are well-formed, and
+/// everything else is escaped as by [`escape_markup`]: a tag that is not
+/// closed, or closed out of order, a stray `>` or an unknown tag.  The result
+/// is always well-formed, and sanitizing it again does not change it.
+fn sanitize_markup(body: &str) -> String {
+    // Split the body at the allowed tags
+    let mut pieces = vec![];
+    let mut text_start = 0;
+    let mut i = 0;
+    while let Some(offset) = body[i..].find('<') {
+        let start = i + offset;
+        i = start + 1;
+        if let Some(tag) = MARKUP_TAGS
+            .iter()
+            .find(|tag| body[start..].starts_with(**tag))
+        {
+            pieces.push((false, &body[text_start..start]));
+            pieces.push((true, *tag));
+            i = start + tag.len();
+            text_start = i;
+        }
+    }
+    pieces.push((false, &body[text_start..]));
+    // Match each closing tag with the innermost open tag
+    let mut keep = vec![false; pieces.len()];
+    let mut open: Vec<(usize, &str)> = vec![];
+    for (index, &(is_tag, piece)) in pieces.iter().enumerate() {
+        if !is_tag {
+            continue;
+        }
+        match piece.strip_prefix("</") {
+            Some(name) if open.last().is_some_and(|&(_, open_name)| open_name == name) => {
+                let (open_index, _) = open.pop().unwrap();
+                keep[open_index] = true;
+                keep[index] = true;
+            }
+            Some(_) => {}
+            None if open.len() < MAX_MARKUP_DEPTH => open.push((index, &piece[1..])),
+            None => {}
+        }
+    }
+    let mut sanitized = String::with_capacity(body.len());
+    for ((is_tag, piece), keep) in pieces.into_iter().zip(keep) {
+        if keep {
+            sanitized.push_str(piece);
+            continue;
+        }
+        let mut rest = piece;
+        while let Some(c) = rest.chars().next() {
+            let entity = MARKUP_ENTITIES
+                .iter()
+                .find(|entity| rest.starts_with(**entity));
+            match entity {
+                Some(entity) if !is_tag => {
+                    sanitized.push_str(entity);
+                    rest = &rest[entity.len()..];
+                }
+                _ => {
+                    push_escaped(&mut sanitized, c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+    }
+    sanitized
+}
+
+/// The hint that groups the notifications of `qube`, and of its category
+/// `category` if given, on daemons that support one.  Dunst stacks
+/// notifications with the same stack tag, and the older synchronous hint has
+/// the same effect on daemons that only know that one.  The tag starts with
+/// the `namespace` of the target, if any, so that proxies sharing a daemon do
+/// not group each other's notifications.
+fn grouping_hint(
+    capabilities: Capabilities,
+    namespace: Option<&str>,
+    qube: &str,
+    category: Option<&str>,
+) -> Option<(&'static str, String)> {
+    let key = if capabilities.contains(Capabilities::STACK_TAG) {
+        "x-dunst-stack-tag"
+    } else if capabilities.contains(Capabilities::SYNCHRONOUS) {
+        "x-canonical-private-synchronous"
+    } else {
+        return None;
+    };
+    let mut tag = "qubes-".to_owned() + qube;
+    if let Some(namespace) = namespace {
+        tag = namespace.to_owned() + "/" + &tag
+    }
+    if let Some(category) = category {
+        tag = tag + "-" + category
+    }
+    Some((key, tag))
+}
+
+/// Prefix of the hints set by the proxy from trusted state.  Qubes cannot set
+/// them.
+pub const QUBES_HINT_PREFIX: &str = "x-qubes-";
+
+/// Validate the hints sent by a qube.  Only known hints of the expected type
+/// are kept, and only if the daemon can make use of them.
+fn filter_hints(
+    untrusted_hints: Vec<(String, HintValue)>,
+    capabilities: Capabilities,
+    policy: &QubePolicy,
+) -> Vec<(&'static str, Value<'static>)> {
+    let mut hints = vec![];
+    for (key, untrusted_value) in untrusted_hints {
+        match (&*key, untrusted_value) {
+            // Only the proxy may speak for Qubes OS
+            (key, value) if key.starts_with(QUBES_HINT_PREFIX) => {
+                eprintln!("Dropping reserved hint {:?} {:?}", key, value)
+            }
+            ("sound-name", HintValue::String(name)) => {
+                if !capabilities.contains(Capabilities::SOUND) {
+                    continue;
+                }
+                if !is_valid_sound_name(name.as_bytes()) {
+                    eprintln!("Dropping invalid sound name {:?}", name);
+                    continue;
+                }
+                if let Some(ref allowed) = policy.allowed_sound_names {
+                    if !allowed.contains(&name) {
+                        eprintln!("Dropping sound name {:?}: not allowed", name);
+                        continue;
+                    }
+                }
+                // sanitized by is_valid_sound_name()
+                let name = TrustedStr::from_validated(name).into_string();
+                hints.push(("sound-name", Value::from(name)))
+            }
+            (key @ ("resident" | "action-icons"), value) => {
+                let (key, capability) = match key {
+                    "resident" => ("resident", Capabilities::PERSISTENCE),
+                    _ => ("action-icons", Capabilities::ACTION_ICONS),
+                };
+                match boolean_hint(&value, policy.coerce_boolean_hints) {
+                    Some(flag) if capabilities.contains(capability) => {
+                        hints.push((key, Value::from(flag)))
+                    }
+                    Some(_) => {}
+                    None => eprintln!("Dropping invalid boolean hint {:?} {:?}", key, value),
+                }
+            }
+            ("value", value) => match progress_value(&value) {
+                // The caller puts it into the summary
+                Some(_) if render_progress(capabilities, policy) => {}
+                Some(percent) => hints.push(("value", Value::from(i32::from(percent)))),
+                None => eprintln!("Dropping invalid value hint {:?}", value),
+            },
+            (_, value) => eprintln!("Dropping unknown hint {:?} {:?}", key, value),
+        }
+    }
+    hints
+}
+
+impl NotificationEmitter {
+    #[inline]
+    /// Whether the server supports persistence
+    pub fn persistence(&self) -> bool {
+        self.capabilities().contains(Capabilities::PERSISTENCE)
+    }
+    #[inline]
+    /// Whether the server supports sound
+    pub fn sound(&self) -> bool {
+        self.capabilities().contains(Capabilities::SOUND)
+    }
+    #[inline]
+    /// Whether the server supports actions
+    pub fn actions(&self) -> bool {
+        self.capabilities().contains(Capabilities::ACTIONS)
+    }
+    #[inline]
+    /// The event to send to the qube after its notification `id`, which had
+    /// actions, was sent.  `minor_version` is the negotiated protocol minor
+    /// version.  Without the actions capability, the actions were dropped, so
+    /// the qube must not wait for them.
+    pub fn actions_unavailable_event(&self, minor_version: u16, id: u32) -> Option<ReplyMessage> {
+        if self.actions() || minor_version < 3 {
+            return None;
+        }
+        Some(ReplyMessage::ActionsUnavailable { id })
+    }
+    #[inline]
+    /// Whether the server supports body markup
+    pub fn body_markup(&self) -> bool {
+        self.capabilities().contains(Capabilities::BODY_MARKUP)
+    }
+    #[inline]
+    /// Whether the server supports notification bodies
+    pub fn body(&self) -> bool {
+        self.capabilities().contains(Capabilities::BODY)
+    }
+    pub async fn closed(&self) -> zbus::Result<NotificationClosedStream<'static>> {
+        self.proxy.receive_notification_closed().await
+    }
+    pub async fn invocations(&self) -> zbus::Result<ActionInvokedStream<'static>> {
+        self.proxy.receive_action_invoked().await
+    }
+    pub async fn replies(&self) -> zbus::Result<NotificationRepliedStream<'static>> {
+        self.proxy.receive_notification_replied().await
+    }
+    /// Handle the daemon closing the notification with daemon ID `server_id`.
+    /// Returns its local ID if it belongs to this qube.
+    pub async fn notification_closed(&self, server_id: u32) -> Option<u32> {
+        self.notification_closed_in(PRIMARY_SINK, server_id).await
+    }
+    /// Like [`Self::notification_closed`], for the sink with index `sink`
+    pub async fn notification_closed_in(&self, sink: usize, server_id: u32) -> Option<u32> {
+        let local_id = self.ids.lock().await.remove_server(sink, server_id);
+        let local_id = match local_id {
+            Some(local_id) => local_id,
+            None => {
+                self.signal_dropped("unknown-id", server_id);
+                return None;
+            }
+        };
+        self.replace_cooldown.borrow_mut().remove(local_id);
+        Some(local_id)
+    }
+    /// Count a signal about daemon ID `server_id` that was not delivered
+    fn signal_dropped(&self, reason: &'static str, server_id: u32) {
+        let mut metrics = self.metrics.borrow_mut();
+        let count = metrics.signals_dropped.entry(reason).or_default();
+        *count += 1;
+        // A flood of signals must not become a flood of log lines
+        if count.is_power_of_two() {
+            eprintln!(
+                "Dropped signal for notification {}: {} ({} so far)",
+                server_id, reason, count
+            )
+        }
+    }
+    /// Route an `ActionInvoked` signal for daemon ID `server_id` to the stream
+    /// returned by [`Self::invoked_actions`].  Signals for notifications of other qubes
+    /// are ignored.  Repeats of a recent signal and signals over the signal
+    /// rate limit of the policy are dropped.
+    pub async fn action_invoked(&self, server_id: u32, action_key: String) {
+        self.action_invoked_in(PRIMARY_SINK, server_id, action_key)
+            .await
+    }
+    /// Like [`Self::action_invoked`], for the sink with index `sink`
+    pub async fn action_invoked_in(&self, sink: usize, server_id: u32, action_key: String) {
+        let local_id = match self.ids.lock().await.local_id(sink, server_id) {
+            Some(id) => id,
+            None => return self.signal_dropped("unknown-id", server_id),
+        };
+        let now = self.clock.now();
+        if !self
+            .signal_debounce
+            .borrow_mut()
+            .check(local_id, &action_key, now)
+        {
+            return self.signal_dropped("debounced", server_id);
+        }
+        if !self.signal_limiter.borrow_mut().check(now) {
+            return self.signal_dropped("rate-limited", server_id);
+        }
+        if self.actions.borrow_mut().push(local_id, action_key) {
+            self.metrics.borrow_mut().actions_dropped += 1;
+        }
+    }
+    /// The actions invoked on the notifications of this qube.  There should
+    /// only be one consumer, as each action is only delivered once.
+    pub fn invoked_actions(&self) -> ActionStream {
+        ActionStream(self.actions.clone())
+    }
+    /// Route a `NotificationReplied` signal for daemon ID `server_id` to the
+    /// stream returned by [`Self::inline_replies`].  Only notifications that
+    /// were shown with an inline reply action can be replied to.  The text
+    /// of the reply is sanitized, as the daemon is not trusted to do it.
+    pub async fn notification_replied(&self, server_id: u32, text: String) {
+        self.notification_replied_in(PRIMARY_SINK, server_id, text)
+            .await
+    }
+    /// Like [`Self::notification_replied`], for the sink with index `sink`
+    pub async fn notification_replied_in(&self, sink: usize, server_id: u32, text: String) {
+        let local_id = {
+            let ids = self.ids.lock().await;
+            let local_id = match ids.local_id(sink, server_id) {
+                Some(id) => id,
+                None => return self.signal_dropped("unknown-id", server_id),
+            };
+            if !ids.info(local_id).is_some_and(|info| info.inline_reply) {
+                return self.signal_dropped("unexpected-reply", server_id);
+            }
+            local_id
+        };
+        if !self.signal_limiter.borrow_mut().check(self.clock.now()) {
+            return self.signal_dropped("rate-limited", server_id);
+        }
+        let text = TrustedStr::sanitize(&text).into_string();
+        if self.replies.borrow_mut().push(local_id, text) {
+            self.metrics.borrow_mut().replies_dropped += 1;
+        }
+    }
+    /// The inline replies to the notifications of this qube, as
+    /// `(local_id, text)` pairs.  Like [`Self::invoked_actions`], there should
+    /// only be one consumer.
+    pub fn inline_replies(&self) -> ActionStream {
+        ActionStream(self.replies.clone())
+    }
+    /// The message telling the qube about the reply `text` to its
+    /// notification `id`.  `minor_version` is the negotiated protocol minor
+    /// version.  Qubes that do not know about replies see the inline reply
+    /// action being invoked instead.
+    pub fn reply_event(&self, minor_version: u16, id: u32, text: String) -> ReplyMessage {
+        if minor_version < 5 {
+            return ReplyMessage::ActionInvoked {
+                id,
+                action: INLINE_REPLY_ACTION.to_owned(),
+            };
+        }
+        ReplyMessage::Replied { id, text }
+    }
+    /// The local ID of the open notification with daemon ID `server_id`, if it
+    /// belongs to this qube.
+    pub async fn local_id(&self, server_id: u32) -> Option<u32> {
+        self.ids.lock().await.local_id(PRIMARY_SINK, server_id)
+    }
+    /// Replace the notification `local_id` if it is still open, and create a
+    /// new notification otherwise.  Returns the local ID of the notification.
+    pub async fn notify_or_replace(
+        &self,
+        local_id: Option<u32>,
+        mut notification: Notification,
+    ) -> Result<u32, ProxyError> {
+        match &mut notification {
+            Notification::V1 { replaces_id, .. } | Notification::V2 { replaces_id, .. } => {
+                *replaces_id = local_id.unwrap_or(0)
+            }
+        }
+        self.send_notification(notification).await
+    }
+    /// Run `notification` through the whole sanitization pipeline and return
+    /// exactly what would be passed to the daemon, without sending anything.
+    /// Previews do not count against the rate limit.
+    pub fn preview(&self, notification: Notification) -> Result<PreparedNotification, ProxyError> {
+        self.sanitizer(&self.policy()).sanitize(notification)
+    }
+    /// Like [`Self::preview`], but instead of stopping at the first problem,
+    /// report every problem of `notification` at once
+    pub fn validate(
+        &self,
+        notification: Notification,
+    ) -> Result<PreparedNotification, Vec<ProxyError>> {
+        self.sanitizer(&self.policy()).validate(notification)
+    }
+    /// What sending `notification` would change rather than reject.  See
+    /// [`Sanitizer::warnings`].
+    pub fn validation_warnings(&self, notification: &Notification) -> Vec<ProxyError> {
+        self.sanitizer(&self.policy()).warnings(notification)
+    }
+    /// The sanitizer for the notifications of the qube, with the current
+    /// policy and what is known about the daemon
+    pub fn sanitizer<'a>(&'a self, policy: &'a QubePolicy) -> Sanitizer<'a> {
+        let capabilities = Some(self.capabilities()).filter(|_| self.capabilities_known());
+        Sanitizer::new(policy, &self.qube_name, capabilities)
+            .with_spec_version(self.server_info.borrow().spec_version)
+            .with_namespace(self.namespace.as_deref())
+    }
+    /// Send the notification in `message` from the qube and return the
+    /// messages to reply with: the result of the call, followed by the event
+    /// it caused, if any.  `minor_version` is the negotiated protocol minor
+    /// version.
+    pub async fn reply_to(&self, minor_version: u16, message: Message) -> Vec<ReplyMessage> {
+        let sequence = message.id;
+        let has_actions = message.notification.has_actions();
+        match self.send_message(message).await {
+            Ok(id) => {
+                let event = Some(id)
+                    .filter(|_| has_actions)
+                    .and_then(|id| self.actions_unavailable_event(minor_version, id));
+                std::iter::once(ReplyMessage::Id { id, sequence })
+                    .chain(event)
+                    .collect()
+            }
+            Err(e) => {
+                eprintln!("Notification {} not sent: {}", sequence, e);
+                let event = self.policy().suppression_event(minor_version, &e, sequence);
+                std::iter::once(e.into_reply(sequence))
+                    .chain(event)
+                    .collect()
+            }
+        }
+    }
+    /// Send the notification in `message` from the qube, returning its local
+    /// ID.  A new notification whose sequence number is that of one that is
+    /// still open is handled as the policy says.
+    pub async fn send_message(&self, message: Message) -> Result<u32, ProxyError> {
+        let Message {
+            id: sequence,
+            mut notification,
+        } = message;
+        if notification.replaces_id() == 0 {
+            let reused = self
+                .ids
+                .lock()
+                .await
+                .iter()
+                .find(|(_, _, _, info)| info.sequence == Some(sequence))
+                .map(|(local_id, _, _, _)| local_id);
+            if let Some(local_id) = reused {
+                match self.policy().reused_id {
+                    ReusedId::New => eprintln!(
+                        "Message {} reuses the ID of open notification {}, showing a new one",
+                        sequence, local_id
+                    ),
+                    ReusedId::Replace => {
+                        eprintln!(
+                            "Message {} reuses the ID of open notification {}, replacing it",
+                            sequence, local_id
+                        );
+                        match &mut notification {
+                            Notification::V1 { replaces_id, .. }
+                            | Notification::V2 { replaces_id, .. } => *replaces_id = local_id,
+                        }
+                    }
+                }
+            }
+        }
+        let local_id = self.send_notification(notification).await?;
+        // Only the last notification sent with a sequence number keeps it, so
+        // the one replaced is never a matter of chance
+        let mut ids = self.ids.lock().await;
+        let holders: Vec<_> = ids
+            .iter()
+            .filter(|&(id, _, _, info)| id == local_id || info.sequence == Some(sequence))
+            .map(|(id, _, _, info)| (id, info.clone()))
+            .collect();
+        for (id, info) in holders {
+            let sequence = Some(sequence).filter(|_| id == local_id);
+            ids.set_info(id, NotificationInfo { sequence, ..info })
+        }
+        Ok(local_id)
+    }
+    /// Send a notification, returning its local ID.  Replacements are
+    /// prepared from scratch like new notifications, so nothing of the
+    /// notification they replace, such as its image, is carried over.
+    pub async fn send_notification(&self, notification: Notification) -> Result<u32, ProxyError> {
+        let result = self.forward(notification).await;
+        let mut metrics = self.metrics.borrow_mut();
+        match result {
+            Ok(_) => metrics.sent += 1,
+            Err(ProxyError::PolicyRejected(PolicyRejection::RateLimited)) => {
+                metrics.rate_limited += 1
+            }
+            Err(ProxyError::Validation(_)) => metrics.validation_failed += 1,
+            Err(ProxyError::PolicyRejected(PolicyRejection::QueueFull)) => metrics.queue_shed += 1,
+            Err(_) => {}
+        }
+        result
+    }
+    async fn forward(&self, notification: Notification) -> Result<u32, ProxyError> {
+        let replaces_id = notification.replaces_id();
+        // Replacing anything but an open notification shows a new one, so it
+        // has to come out of the budget for new notifications
+        let replacing = replaces_id != 0 && self.ids.lock().await.server_id(replaces_id).is_some();
+        let allowed = match *self.replace_limiter.borrow_mut() {
+            Some(ref mut limiter) if replacing => limiter.check(self.clock.now()),
+            _ => self.rate_limiter.borrow_mut().check(self.clock.now()),
+        };
+        if !allowed {
+            return Err(ProxyError::PolicyRejected(PolicyRejection::RateLimited));
+        }
+        let policy = self.policy();
+        let sanitizer = self.sanitizer(&policy).recording(&self.metrics);
+        let notification = sanitizer.sanitize(notification)?;
+        self.deliver(notification).await
+    }
+    /// Show `notification`, which was sanitized, in the first sink that can,
+    /// or hold it if it came during quiet hours
+    async fn deliver(&self, notification: PreparedNotification) -> Result<u32, ProxyError> {
+        let critical = matches!(notification.hints.get("urgency"), Some(Value::U8(2)));
+        if critical || self.quiet_hours_remaining().is_none() {
+            return self.show(notification, None).await;
+        }
+        if self.policy().drop_during_quiet_hours {
+            return Err(ProxyError::PolicyRejected(PolicyRejection::QuietHours));
+        }
+        self.hold(notification).await
+    }
+    /// How long the quiet hours of the policy still last, if they are in
+    /// effect
+    fn quiet_hours_remaining(&self) -> Option<std::time::Duration> {
+        let quiet_hours = self.policy().quiet_hours?;
+        quiet_hours.remaining(self.clock.time_of_day())
+    }
+    /// Hold `notification` until [`Self::release_held`] shows it.  The qube
+    /// gets its local ID right away, so it does not wait for the quiet hours
+    /// to be over.
+    async fn hold(&self, notification: PreparedNotification) -> Result<u32, ProxyError> {
+        let replaces_id = notification.replaces_id;
+        let mut ids = self.ids.lock().await;
+        let mut held = self.held.borrow_mut();
+        // Updates of a held notification replace it in the queue
+        let waiting = held
+            .iter_mut()
+            .find(|(local_id, _)| replaces_id != 0 && *local_id == replaces_id);
+        if let Some((_, waiting)) = waiting {
+            *waiting = notification;
+            return Ok(replaces_id);
+        }
+        if held.len() >= self.policy().quiet_hours_queue_size {
+            return Err(ProxyError::PolicyRejected(PolicyRejection::QueueFull));
+        }
+        let local_id = match ids.server_id(replaces_id) {
+            Some(_) => replaces_id,
+            None => ids.reserve(),
+        };
+        held.push_back((local_id, notification));
+        Ok(local_id)
+    }
+    /// Show the notifications held during quiet hours, once they are over.
+    /// Returns the messages that tell the qube about those that could not be
+    /// shown after all.
+    pub async fn release_held(&self) -> Vec<ReplyMessage> {
+        if self.quiet_hours_remaining().is_some() {
+            return vec![];
+        }
+        let held: Vec<_> = self.held.borrow_mut().drain(..).collect();
+        let mut messages = vec![];
+        for (local_id, notification) in held {
+            if let Err(e) = self.show(notification, Some(local_id)).await {
+                eprintln!("Cannot show held notification {}: {}", local_id, e);
+                self.ids.lock().await.unreserve(local_id);
+                messages.push(ReplyMessage::Dismissed {
+                    id: local_id,
+                    reason: CloseReason::Undefined,
+                })
+            }
+        }
+        messages
+    }
+    /// Show `notification` in the first sink that can.  If it was held, it
+    /// gets the local ID `held_id` that the qube got for it, unless it
+    /// replaces a notification that is still open.
+    async fn show(
+        &self,
+        mut notification: PreparedNotification,
+        held_id: Option<u32>,
+    ) -> Result<u32, ProxyError> {
+        let replaces_id = notification.replaces_id;
+        // Updates that come too quickly are collapsed into the last one
+        let early = match replaces_id {
+            0 => None,
+            local_id => self
+                .replace_cooldown
+                .borrow_mut()
+                .begin(local_id, self.clock.now()),
+        };
+        let urgency = match notification.hints.get("urgency") {
+            Some(Value::U8(0)) => Urgency::Low,
+            Some(Value::U8(2)) => Urgency::Critical,
+            _ => Urgency::Normal,
+        };
+        let queue_full = || ProxyError::PolicyRejected(PolicyRejection::QueueFull);
+        let mut ticket = match self.send_queue {
+            Some(ref queue) => Some(queue.enter(urgency).ok_or_else(queue_full)?),
+            None => None,
+        };
+        let waiting = async {
+            if let Some((due, token)) = early {
+                self.clock.sleep_until(due).await;
+                if !self.replace_cooldown.borrow().is_latest(replaces_id, token) {
+                    return None;
+                }
+            }
+            // The lock is held over the call, so that the notification cannot
+            // be closed between looking up its ID and recording the new one.
+            Some(self.ids.lock().await)
+        };
+        let ids = match ticket {
+            Some(ref mut ticket) => tokio::select! {
+                biased;
+                () = ticket.shed() => return Err(queue_full()),
+                ids = waiting => ids,
+            },
+            None => waiting.await,
+        };
+        let mut ids = match ids {
+            Some(ids) => ids,
+            None => return Ok(replaces_id),
+        };
+        // Delivery cannot be interrupted, so the notification leaves the queue
+        drop(ticket);
+        let owner = match replaces_id {
+            0 => None,
+            local_id => ids.server_id(local_id),
+        };
+        // Removed again if no sink shows the notification
+        let sound_file = notification.sound_data.as_deref().and_then(|data| {
+            let extension = sound::validate_sound(data, usize::MAX).ok()?;
+            match SoundFile::create(data, extension, self.namespace.as_deref()) {
+                Ok(file) => Some(Rc::new(file)),
+                Err(e) => {
+                    eprintln!("Cannot write sound file: {}", e);
+                    None
+                }
+            }
+        });
+        if let Some(ref file) = sound_file {
+            let path = file.path().to_string_lossy().into_owned();
+            notification
+                .hints
+                .insert("sound-file".to_owned(), Value::from(path));
+        }
+        let mut delivered = None;
+        let mut last_error = None;
+        for (index, sink) in self.sinks.iter().enumerate() {
+            let sink_replaces_id = match owner {
+                Some((owner, id)) if owner == index => id,
+                _ => 0,
+            };
+            match self
+                .notify_sink(&**sink, &notification, sink_replaces_id)
+                .await
+            {
+                Ok(server_id) => {
+                    delivered = Some((index, server_id));
+                    break;
+                }
+                Err(e) => {
+                    if index + 1 < self.sinks.len() {
+                        eprintln!("Sink {} failed ({}), falling back", sink.name(), e)
+                    }
+                    last_error = Some(e)
+                }
+            }
+        }
+        let (sink, server_id) = match delivered {
+            Some(delivered) => delivered,
+            None => return Err(last_error.expect("there is at least one sink")),
+        };
+        *self
+            .metrics
+            .borrow_mut()
+            .delivered
+            .entry(self.sinks[sink].name().to_owned())
+            .or_default() += 1;
+        // A notification that was open in a sink that failed moves to the
+        // sink that showed it
+        let local_id = match (owner, held_id) {
+            (Some(_), _) => {
+                ids.bind(replaces_id, sink, server_id);
+                replaces_id
+            }
+            (None, Some(local_id)) => {
+                ids.bind(local_id, sink, server_id);
+                local_id
+            }
+            (None, None) => ids.insert(sink, server_id),
+        };
+        let critical = matches!(notification.hints.get("urgency"), Some(Value::U8(2)));
+        let category = notification.category.clone();
+        // Only the primary daemon is known to support inline replies
+        let inline_reply = sink == PRIMARY_SINK
+            && self.capabilities().contains(Capabilities::INLINE_REPLY)
+            && notification
+                .actions
+                .iter()
+                .step_by(2)
+                .any(|key| key == INLINE_REPLY_ACTION);
+        let info = NotificationInfo {
+            critical,
+            category,
+            updated: Some(self.clock.now()),
+            sound_file,
+            sequence: None,
+            inline_reply,
+        };
+        ids.set_info(local_id, info);
+        self.replace_cooldown
+            .borrow_mut()
+            .sent(local_id, self.clock.now());
+        for mirror in &self.mirrors {
+            if let Err(e) = mirror.notify(&notification, 0).await {
+                eprintln!("Mirror {} failed: {}", mirror.name(), e)
+            }
+        }
+        let seq = self.next_seq();
+        self.last_seq.set(Some(seq));
+        eprintln!(
+            "Notification {} from {}: local ID {}, {} ID {}",
+            seq,
+            self.qube_name,
+            local_id,
+            self.sinks[sink].name(),
+            server_id
+        );
+        self.history.borrow_mut().push(HistoryEntry {
+            time: self.clock.now(),
+            local_id,
+            seq,
+            summary: notification.summary,
+            body: Some(notification.body).filter(|_| self.policy().history_bodies),
+        });
+        Ok(local_id)
+    }
+    /// Close the open notifications of the qube after it shut down, once the
+    /// grace period of its policy is over.  If `returned` completes first,
+    /// because the qube came back, nothing is closed.  Returns whether the
+    /// notifications were closed.
+    pub async fn close_after_shutdown(
+        &self,
+        returned: impl std::future::Future<Output = ()>,
+    ) -> bool {
+        let deadline = self.clock.now() + self.policy().shutdown_grace;
+        tokio::select! {
+            biased;
+            () = returned => return false,
+            () = self.clock.sleep_until(deadline) => {}
+        }
+        self.close_where(|info| !(info.critical && self.policy().keep_critical_on_shutdown))
+            .await;
+        true
+    }
+    /// Close the notification `local_id`.  Returns `false` if it was not
+    /// open.
+    pub async fn close(&self, local_id: u32) -> Result<bool, ProxyError> {
+        let mut ids = self.ids.lock().await;
+        // Held notifications, and held updates of open ones, are dropped
+        let held = {
+            let mut held = self.held.borrow_mut();
+            let count = held.len();
+            held.retain(|&(id, _)| id != local_id);
+            held.len() != count
+        };
+        let (sink, server_id) = match ids.server_id(local_id) {
+            Some(ids) => ids,
+            None if held => {
+                ids.unreserve(local_id);
+                return Ok(true);
+            }
+            None => return Ok(false),
+        };
+        self.sinks[sink].close(server_id).await?;
+        ids.remove_server(sink, server_id);
+        self.replace_cooldown.borrow_mut().remove(local_id);
+        Ok(true)
+    }
+    /// Close the open notifications of the qube with category `category`.
+    /// Each server process serves a single qube, so there is no qube to
+    /// select.  Like [`Self::close_all`], this returns the messages that tell
+    /// the qube.
+    pub async fn close_category(&self, category: &str) -> Vec<ReplyMessage> {
+        let closed = self
+            .close_where(|info| info.category.as_deref() == Some(category))
+            .await;
+        closed
+            .into_iter()
+            .map(|id| ReplyMessage::Dismissed {
+                id,
+                reason: CloseReason::Closed,
+            })
+            .collect()
+    }
+    /// Close every open notification of the qube and forget about them.
+    /// The signals of the daemon about them can no longer be routed, so this
+    /// returns the messages that tell the qube instead.
+    pub async fn close_all(&self) -> Vec<ReplyMessage> {
+        let mut closed = self.close_where(|_| true).await;
+        let mut ids = self.ids.lock().await;
+        for (local_id, _) in self.held.borrow_mut().drain(..) {
+            // Updates of notifications that were just closed
+            if !closed.contains(&local_id) {
+                ids.unreserve(local_id);
+                closed.push(local_id)
+            }
+        }
+        closed
+            .into_iter()
+            .map(|id| ReplyMessage::Dismissed {
+                id,
+                reason: CloseReason::Closed,
+            })
+            .collect()
+    }
+    /// Close the notifications that were last updated longer ago than the
+    /// maximum lifetime of the policy, returning their local IDs.  Critical
+    /// notifications are kept unless the policy says otherwise.
+    pub async fn close_expired(&self) -> Vec<u32> {
+        let max_lifetime = match self.policy().max_lifetime {
+            Some(max_lifetime) => max_lifetime,
+            None => return vec![],
+        };
+        let now = self.clock.now();
+        self.close_where(|info| {
+            (!info.critical || self.policy().expire_critical)
+                && info
+                    .updated
+                    .is_some_and(|updated| now.saturating_duration_since(updated) >= max_lifetime)
+        })
+        .await
+    }
+    /// Close the open notifications for which `filter` returns `true` and
+    /// forget about them, returning their local IDs
+    async fn close_where(&self, filter: impl Fn(&NotificationInfo) -> bool) -> Vec<u32> {
+        let mut ids = self.ids.lock().await;
+        let open: Vec<_> = ids
+            .iter()
+            .filter(|(_, _, _, info)| filter(info))
+            .map(|(local_id, sink, server_id, _)| (local_id, sink, server_id))
+            .collect();
+        for &(local_id, sink, server_id) in &open {
+            if let Err(e) = self.sinks[sink].close(server_id).await {
+                eprintln!("Cannot close notification {}: {}", local_id, e)
+            }
+            ids.remove_server(sink, server_id);
+            self.replace_cooldown.borrow_mut().remove(local_id);
+        }
+        open.into_iter().map(|(local_id, _, _)| local_id).collect()
+    }
+    /// The next sequence number for a forwarded notification.  Unlike local
+    /// and server IDs, these are shared by all the server processes of the
+    /// user, so they identify a notification in the logs on their own.  If
+    /// the counter file cannot be used, the number is only unique within
+    /// this process, which is logged with it.
+    fn next_seq(&self) -> u64 {
+        static LOCAL_SEQ: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+        let path = match self.sequence_file {
+            Some(ref path) => Ok(path.clone()),
+            None => sound::runtime_dir(None).map(|dir| dir.join("sequence")),
+        };
+        match path.and_then(|path| next_seq_in(&path)) {
+            Ok(seq) => seq,
+            Err(e) => {
+                let seq = LOCAL_SEQ.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                eprintln!("Sequence number {} is local to this process: {}", seq, e);
+                seq
+            }
+        }
+    }
+    /// Show `notification` in `sink`, retrying while the sink is unavailable
+    async fn notify_sink(
+        &self,
+        sink: &dyn NotificationSink,
+        notification: &PreparedNotification,
+        replaces_id: u32,
+    ) -> Result<u32, ProxyError> {
+        let mut delay = self.policy().retry_delay;
+        let mut retries = 0;
+        loop {
+            match sink.notify(notification, replaces_id).await {
+                // 0 is never a valid ID, and recording it would make the
+                // signals for it ambiguous
+                Ok(0) => {
+                    eprintln!(
+                        "Sink {} returned the invalid notification ID 0",
+                        sink.name()
+                    );
+                    return Err(ProxyError::DaemonUnavailable);
+                }
+                Ok(id) => return Ok(id),
+                // Only errors that guarantee that the daemon never saw the
+                // notification are retried, so it cannot be shown twice.
+                Err(e) if is_transient(&e) => {
+                    if retries >= self.policy().retries {
+                        eprintln!("Giving up after {} retries: {}", retries, e);
+                        return Err(ProxyError::DaemonUnavailable);
+                    }
+                    eprintln!("Daemon unavailable ({}), retrying in {:?}", e, delay);
+                    self.clock.sleep_until(self.clock.now() + delay).await;
+                    delay *= 2;
+                    retries += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
     #[test]
@@ -575,6 +2350,88 @@ mod tests {
         assert_eq!(&v[..4], &[0, 0, 0, 0][..])
     }
     #[test]
+    fn test_close_reason() {
+        use bincode::Options as _;
+        assert_eq!(CloseReason::from(1), CloseReason::Expired);
+        assert_eq!(CloseReason::from(2), CloseReason::Dismissed);
+        assert_eq!(CloseReason::from(3), CloseReason::Closed);
+        assert_eq!(CloseReason::from(4), CloseReason::Undefined);
+        assert_eq!(CloseReason::from(0), CloseReason::Undefined);
+        assert_eq!(CloseReason::from(7), CloseReason::Undefined);
+        for code in 1..=4 {
+            assert_eq!(u32::from(CloseReason::from(code)), code);
+        }
+        // The wire format is unchanged: still a bare u32
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_native_endian()
+            .reject_trailing_bytes();
+        assert_eq!(
+            options.serialize(&CloseReason::Closed).unwrap(),
+            options.serialize(&3u32).unwrap()
+        );
+    }
+    #[test]
+    fn test_empty_action_label_dropped() {
+        let actions: Vec<String> = ["ok", "", "cancel", "Cancel", "retry", " \t "]
+            .iter()
+            .map(|&s| s.to_owned())
+            .collect();
+        let policy = QubePolicy::default();
+        assert_eq!(
+            sanitize_actions(&actions, &policy).unwrap(),
+            ["cancel", "Cancel"]
+        );
+        let actions = vec!["1bad".to_owned(), "Bad".to_owned()];
+        assert!(sanitize_actions(&actions, &policy).is_err());
+    }
+    #[test]
+    fn test_action_length_limits() {
+        let mut policy = QubePolicy {
+            max_action_key_len: 4,
+            max_action_label_len: 6,
+            ..QubePolicy::default()
+        };
+        let actions: Vec<String> = ["open", "Öffnen", "close", "Close", "ok", "Okay!!!"]
+            .iter()
+            .map(|&s| s.to_owned())
+            .collect();
+        // Labels are counted in characters, not bytes
+        assert_eq!(
+            sanitize_actions(&actions, &policy).unwrap(),
+            ["open", "Öffnen"]
+        );
+        policy.reject_long_actions = true;
+        match sanitize_actions(&actions[..4], &policy) {
+            Err(ProxyError::Validation(message)) => assert_eq!(message, "Action key too long"),
+            e => panic!("unexpected result {:?}", e),
+        }
+        let label_only = [&actions[..2], &actions[4..]].concat();
+        match sanitize_actions(&label_only, &policy) {
+            Err(ProxyError::Validation(message)) => assert_eq!(message, "Action label too long"),
+            e => panic!("unexpected result {:?}", e),
+        }
+    }
+    #[test]
+    fn test_action_key_allowlist() {
+        let actions: Vec<String> = ["default", "Open", "run", "Run", "dismiss", "Dismiss"]
+            .iter()
+            .map(|&s| s.to_owned())
+            .collect();
+        let mut policy = QubePolicy {
+            allowed_action_keys: Some(vec!["default".to_owned(), "dismiss".to_owned()]),
+            ..QubePolicy::default()
+        };
+        assert_eq!(
+            sanitize_actions(&actions, &policy).unwrap(),
+            ["default", "Open", "dismiss", "Dismiss"]
+        );
+        policy.allowed_action_keys = Some(vec![]);
+        assert!(sanitize_actions(&actions, &policy).unwrap().is_empty());
+        policy.allowed_action_keys = None;
+        assert_eq!(sanitize_actions(&actions, &policy).unwrap(), actions);
+    }
+    #[test]
     fn test_enum_extensibility() {
         #[derive(Serialize, Deserialize)]
         enum A {
@@ -595,4 +2452,2018 @@ mod tests {
         assert!(matches!(deserialized, D::B { x: true }));
         assert_eq!(serialized, options.serialize(&D::B { x: true }).unwrap());
     }
+    fn image(width: i32, height: i32, rowstride: i32, len: usize) -> ImageParameters {
+        ImageParameters {
+            untrusted_width: width,
+            untrusted_height: height,
+            untrusted_rowstride: rowstride,
+            untrusted_has_alpha: false,
+            untrusted_bits_per_sample: 8,
+            untrusted_channels: 3,
+            untrusted_data: vec![0; len],
+        }
+    }
+    #[test]
+    fn test_image_large_max_size() {
+        let limits = ImageLimits {
+            max_size: i32::MAX as usize,
+            ..ImageLimits::default()
+        };
+        // 256MiB of data, far more than the default limit allows
+        let len = 1usize << 28;
+        assert_eq!(
+            serialize_image(image(255, 255, 3, len), &limits).unwrap_err(),
+            "Row stride too small"
+        );
+        assert_eq!(
+            serialize_image(image(255, 255, 765, len), &ImageLimits::default()).unwrap_err(),
+            "Too much data"
+        );
+        let limits = ImageLimits {
+            max_size: 1 << 20,
+            ..ImageLimits::default()
+        };
+        assert_eq!(
+            serialize_image(image(255, 255, 765, (1 << 20) + 1), &limits).unwrap_err(),
+            "Too much data"
+        );
+        assert!(serialize_image(image(2, 2, 6, 12), &limits).is_ok());
+        assert_eq!(
+            serialize_image(image(2, 2, 6, 11), &limits).unwrap_err(),
+            "Image too large"
+        );
+    }
+    #[test]
+    fn test_image_large_rowstride() {
+        let limits = ImageLimits {
+            max_size: 1 << 20,
+            ..ImageLimits::default()
+        };
+        // Claims far more data than the limit, whatever was actually sent
+        for len in [0, 12, 1 << 20] {
+            assert_eq!(
+                serialize_image(image(2, 255, i32::MAX, len), &limits).unwrap_err(),
+                "Image too large"
+            );
+        }
+        // Exactly at the limit
+        let rowstride = (1 << 20) / 255;
+        assert!(serialize_image(image(2, 255, rowstride, 1 << 20), &limits).is_ok());
+        assert_eq!(
+            serialize_image(image(2, 255, rowstride + 1, 1 << 20), &limits).unwrap_err(),
+            "Image too large"
+        );
+    }
+    #[test]
+    fn test_image_rowstride() {
+        let limits = ImageLimits::default();
+        assert!(serialize_image(image(2, 2, 6, 12), &limits).is_ok());
+        // Padding at the end of rows is fine
+        assert!(serialize_image(image(2, 2, 7, 14), &limits).is_ok());
+        assert_eq!(
+            serialize_image(image(2, 2, 5, 10), &limits).unwrap_err(),
+            "Row stride too small"
+        );
+        let mut alpha = image(2, 2, 8, 16);
+        alpha.untrusted_has_alpha = true;
+        alpha.untrusted_channels = 4;
+        assert!(serialize_image(alpha, &limits).is_ok());
+        let mut alpha = image(2, 2, 7, 14);
+        alpha.untrusted_has_alpha = true;
+        alpha.untrusted_channels = 4;
+        assert_eq!(
+            serialize_image(alpha, &limits).unwrap_err(),
+            "Row stride too small"
+        );
+    }
+    #[test]
+    fn test_sound_name_hint() {
+        let hint = |name: &str| vec![("sound-name".to_owned(), HintValue::String(name.to_owned()))];
+        let mut policy = QubePolicy::default();
+        let sound = Capabilities::SOUND;
+        let hints = filter_hints(hint("message-new-instant"), sound, &policy);
+        assert_eq!(hints.len(), 1);
+        assert_eq!(hints[0].0, "sound-name");
+        assert_eq!(hints[0].1, Value::from("message-new-instant"));
+        for name in ["Message", "message--new", "-message", "message-", "a/b", ""] {
+            assert!(filter_hints(hint(name), sound, &policy).is_empty());
+        }
+        // Not sent to a daemon that does not play sounds
+        assert!(filter_hints(hint("bell"), Capabilities::BODY, &policy).is_empty());
+        // Wrong type
+        let wrong = vec![("sound-name".to_owned(), HintValue::Boolean(true))];
+        assert!(filter_hints(wrong, sound, &policy).is_empty());
+        policy.allowed_sound_names = Some(vec!["bell".to_owned()]);
+        assert_eq!(filter_hints(hint("bell"), sound, &policy).len(), 1);
+        assert!(filter_hints(hint("message-new-instant"), sound, &policy).is_empty());
+    }
+    #[test]
+    fn test_oversized_notification_shed() {
+        let image = serialize_image(image(255, 255, 765, 255 * 765), &ImageLimits::default());
+        let mut hints = HashMap::new();
+        hints.insert("image-data".to_owned(), image.unwrap());
+        hints.insert("urgency".to_owned(), Value::from(1u8));
+        let mut body = "a".repeat(1000);
+        // Everything fits
+        shed_to_fit(1 << 27, &["summary"], &mut body, &[], &mut hints, false).unwrap();
+        assert_eq!(hints.len(), 2);
+        // The image does not fit
+        shed_to_fit(1 << 16, &["summary"], &mut body, &[], &mut hints, false).unwrap();
+        assert!(!hints.contains_key("image-data"));
+        assert!(hints.contains_key("urgency"));
+        assert_eq!(body.len(), 1000);
+        // Neither does the body
+        let mut body = "&amp;".repeat(1 << 14);
+        shed_to_fit(1 << 12, &["summary"], &mut body, &[], &mut hints, false).unwrap();
+        assert!(!body.is_empty() && body.len() < 1 << 12);
+        assert!(body.ends_with("&amp;"));
+        // Even the summary is too large
+        let summary = "s".repeat(1 << 12);
+        assert!(shed_to_fit(1 << 12, &[&summary], &mut body, &[], &mut hints, false).is_err());
+        // Markup nested as deep as allowed, one byte too large.  Every tag
+        // that the cut leaves open is escaped, and the result still fits.
+        let deep =
+            "<b>".repeat(MAX_MARKUP_DEPTH) + &"x".repeat(100) + &"</b>".repeat(MAX_MARKUP_DEPTH);
+        assert_eq!(sanitize_markup(&deep), deep);
+        let mut hints = HashMap::new();
+        let fixed_size = 256 + "summary".len() + 8 + 8;
+        let max_size = fixed_size + deep.len() - 1;
+        let mut body = deep.clone();
+        shed_to_fit(max_size, &["summary"], &mut body, &[], &mut hints, true).unwrap();
+        assert!(body.len() < deep.len());
+        assert!(fixed_size + body.len() <= max_size);
+        assert_eq!(sanitize_markup(&body), body);
+        assert_eq!(body.matches("&lt;b&gt;").count(), MAX_MARKUP_DEPTH);
+    }
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic]
+    fn test_image_max_size_fits_i32() {
+        let limits = ImageLimits {
+            max_size: i32::MAX as usize + 1,
+            ..ImageLimits::default()
+        };
+        let _ = serialize_image(image(1, 1, 3, 3), &limits);
+    }
+    #[tokio::test]
+    async fn test_notify_or_replace() {
+        let daemon = mock::MockDaemon::new(&["body"]).await;
+        daemon.state.lock().unwrap().last_id = 100;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        // No ID: a new notification is created
+        let id = emitter
+            .notify_or_replace(None, notification("1"))
+            .await
+            .unwrap();
+        assert_eq!(id, 1);
+        // Open notification: it is replaced
+        assert_eq!(
+            emitter
+                .notify_or_replace(Some(id), notification("2"))
+                .await
+                .unwrap(),
+            id
+        );
+        // Closed notification: a new one is created
+        assert_eq!(emitter.notification_closed(101).await, Some(id));
+        let new_id = emitter
+            .notify_or_replace(Some(id), notification("3"))
+            .await
+            .unwrap();
+        assert_ne!(new_id, id);
+        let replaces: Vec<u32> = daemon
+            .notifications()
+            .iter()
+            .map(|n| n.replaces_id)
+            .collect();
+        assert_eq!(replaces, [0, 101, 0]);
+        assert_eq!(emitter.local_id(102).await, Some(new_id));
+        // IDs of other clients are not passed on
+        assert_eq!(emitter.notification_closed(1).await, None);
+    }
+    #[tokio::test]
+    async fn test_rejected_image_logged() {
+        let daemon = mock::MockDaemon::new(&["body"]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let notification = v2("a", |fields| fields.image = Some(self::image(2, 2, 3, 12)));
+        assert!(emitter.send_notification(notification).await.is_err());
+        let metrics = emitter.metrics();
+        assert_eq!(metrics.images_rejected["Row stride too small"], 1);
+        assert_eq!(daemon.notifications().len(), 0);
+        let image = self::image(2, 2, 3, 12);
+        let mut rejection = ImageRejection::new("work", &image);
+        rejection.reason = "Row stride too small";
+        assert_eq!(
+            rejection.to_string(),
+            "Rejected image from qube work: Row stride too small (width 2, height 2, \
+            rowstride 3, alpha false, bits per sample 8, channels 3, 12 bytes of data)"
+        );
+    }
+    #[tokio::test]
+    async fn test_action_streams() {
+        use futures_util::StreamExt as _;
+        let daemon = mock::MockDaemon::new(&["actions"]).await;
+        let policy = QubePolicy {
+            action_queue_size: 3,
+            ..QubePolicy::default()
+        };
+        let emitter_a =
+            NotificationEmitter::new(&daemon.connection, "a".to_owned(), policy.clone())
+                .await
+                .unwrap();
+        let emitter_b = NotificationEmitter::new(&daemon.connection, "b".to_owned(), policy)
+            .await
+            .unwrap();
+        // Daemon IDs 1 and 3 belong to a, 2 to b
+        assert_eq!(
+            emitter_a
+                .send_notification(notification("1"))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            emitter_b
+                .send_notification(notification("2"))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            emitter_a
+                .send_notification(notification("3"))
+                .await
+                .unwrap(),
+            2
+        );
+        let mut actions_a = emitter_a.invoked_actions();
+        let mut actions_b = emitter_b.invoked_actions();
+        for (server_id, key) in [(1, "x"), (2, "y"), (3, "z"), (1, "w"), (4, "v")] {
+            emitter_a.action_invoked(server_id, key.to_owned()).await;
+            emitter_b.action_invoked(server_id, key.to_owned()).await;
+        }
+        assert_eq!(actions_a.next().await, Some((1, "x".to_owned())));
+        assert_eq!(actions_a.next().await, Some((2, "z".to_owned())));
+        assert_eq!(actions_a.next().await, Some((1, "w".to_owned())));
+        assert_eq!(actions_b.next().await, Some((1, "y".to_owned())));
+        // A slow reader loses the oldest actions
+        for key in ["1", "2", "3", "4"] {
+            emitter_b.action_invoked(2, key.to_owned()).await;
+        }
+        assert_eq!(emitter_b.metrics().actions_dropped, 1);
+        assert_eq!(emitter_a.metrics().actions_dropped, 0);
+        drop(emitter_b);
+        let rest: Vec<_> = actions_b.collect().await;
+        assert_eq!(
+            rest,
+            [
+                (1, "2".to_owned()),
+                (1, "3".to_owned()),
+                (1, "4".to_owned())
+            ]
+        );
+    }
+    #[tokio::test]
+    async fn test_signal_storm() {
+        use futures_util::StreamExt as _;
+        let daemon = mock::MockDaemon::new(&["actions"]).await;
+        let policy = QubePolicy {
+            signal_rate_limit: 3,
+            ..QubePolicy::default()
+        };
+        let clock = Rc::new(ManualClock::new());
+        let emitter = emitter(&daemon, policy).await.with_clock(clock.clone());
+        emitter.send_notification(notification("a")).await.unwrap();
+        let mut actions = emitter.invoked_actions();
+        for _ in 0..100 {
+            emitter.action_invoked(1, "default".to_owned()).await;
+        }
+        emitter.action_invoked(1, "other".to_owned()).await;
+        assert_eq!(actions.next().await, Some((1, "default".to_owned())));
+        assert_eq!(actions.next().await, Some((1, "other".to_owned())));
+        assert_eq!(emitter.metrics().signals_dropped["debounced"], 99);
+        // Once the debounce period is over, the same action is delivered again
+        clock.advance(std::time::Duration::from_millis(100));
+        emitter.action_invoked(1, "default".to_owned()).await;
+        assert_eq!(actions.next().await, Some((1, "default".to_owned())));
+        // Distinct actions are still subject to the signal rate limit
+        emitter.action_invoked(1, "third".to_owned()).await;
+        assert_eq!(emitter.metrics().signals_dropped["debounced"], 99);
+        assert_eq!(emitter.metrics().signals_dropped["rate-limited"], 1);
+        clock.advance(std::time::Duration::from_secs(1));
+        emitter.action_invoked(1, "third".to_owned()).await;
+        assert_eq!(actions.next().await, Some((1, "third".to_owned())));
+    }
+    #[tokio::test]
+    async fn test_unknown_signals_counted() {
+        let daemon = mock::MockDaemon::new(&["actions"]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let id = emitter.send_notification(notification("a")).await.unwrap();
+        // Signals for notifications of other qubes, or of none at all
+        for server_id in 2..5 {
+            emitter
+                .action_invoked(server_id, "default".to_owned())
+                .await;
+            assert_eq!(emitter.notification_closed(server_id).await, None);
+        }
+        assert_eq!(emitter.metrics().signals_dropped["unknown-id"], 6);
+        assert_eq!(emitter.notification_closed(1).await, Some(id));
+        // Once closed, the notification is unknown as well
+        emitter.action_invoked(1, "default".to_owned()).await;
+        let metrics = emitter.metrics();
+        assert_eq!(metrics.signals_dropped["unknown-id"], 7);
+        assert_eq!(metrics.signals_dropped.len(), 1);
+    }
+    #[tokio::test]
+    async fn test_transient_timeout_clamped() {
+        let daemon = mock::MockDaemon::new(&["persistence"]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        for (transient, timeout, expected) in [
+            (true, 0, 10_000),
+            (true, 100_000, 10_000),
+            (true, 5, 5),
+            (true, -1, -1),
+            (false, 0, 0),
+        ] {
+            let notification = v2("a", |fields| {
+                fields.transient = transient;
+                fields.expire_timeout = timeout;
+            });
+            emitter.send_notification(notification).await.unwrap();
+            let received = daemon.notifications().pop().unwrap();
+            assert_eq!(received.expire_timeout, expected);
+        }
+    }
+    #[tokio::test]
+    async fn test_blocked_category() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            denied_categories: vec!["im.received".to_owned()],
+            allow_uncategorized: false,
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        let with_category = |category: Option<&str>| {
+            v2("a", |fields| fields.category = category.map(str::to_owned))
+        };
+        let sent = emitter.send_notification(with_category(Some("email.arrived")));
+        assert!(sent.await.is_ok());
+        let sent = emitter.send_notification(with_category(Some("im.received")));
+        match sent.await {
+            Err(e @ ProxyError::PolicyRejected(PolicyRejection::CategoryBlocked(Some(_)))) => {
+                assert_eq!(e.suppress_reason(), Some("blocked-category"))
+            }
+            e => panic!("unexpected result {:?}", e),
+        }
+        let sent = emitter.send_notification(with_category(None));
+        assert!(matches!(
+            sent.await,
+            Err(ProxyError::PolicyRejected(
+                PolicyRejection::CategoryBlocked(None)
+            ))
+        ));
+        assert_eq!(daemon.notifications().len(), 1);
+        assert_eq!(emitter.metrics().categories_blocked, 2);
+    }
+    #[test]
+    fn test_image_hint_key() {
+        for (spec, key) in [
+            ("1.0", "icon_data"),
+            ("1.1", "image_data"),
+            ("1.2", "image-data"),
+            ("1.3", "image-data"),
+        ] {
+            let spec_version = server_info::parse_spec_version(spec).unwrap();
+            let (hint, _) =
+                image_hint(image(2, 2, 6, 12), &ImageLimits::default(), spec_version).unwrap();
+            assert_eq!(hint, key);
+        }
+        assert!(image_hint(image(2, 2, 3, 12), &ImageLimits::default(), (1, 2)).is_err());
+    }
+    #[tokio::test]
+    async fn test_replace_coalesced() {
+        let daemon = mock::MockDaemon::new(&["body"]).await;
+        let policy = QubePolicy {
+            replace_cooldown: std::time::Duration::from_millis(100),
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        let id = emitter.send_notification(notification("1")).await.unwrap();
+        let (a, b, c) = tokio::join!(
+            emitter.notify_or_replace(Some(id), notification("2")),
+            emitter.notify_or_replace(Some(id), notification("3")),
+            emitter.notify_or_replace(Some(id), notification("4")),
+        );
+        assert_eq!((a.unwrap(), b.unwrap(), c.unwrap()), (id, id, id));
+        let summaries: Vec<String> = daemon
+            .notifications()
+            .into_iter()
+            .map(|n| n.summary)
+            .collect();
+        assert_eq!(summaries, ["test: 1", "test: 4"]);
+    }
+    #[tokio::test]
+    async fn test_body_capability() {
+        let with_body = |body: &str| v2("Mail", |fields| fields.body = body.to_owned());
+        let daemon = mock::MockDaemon::new(&["actions"]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let prepared = emitter.preview(with_body("from\nBob")).unwrap();
+        assert_eq!(prepared.summary, "test: Mail");
+        assert_eq!(prepared.body, "");
+        let policy = QubePolicy {
+            fold_body: true,
+            max_folded_summary_len: 16,
+            body_footer: true,
+            ..QubePolicy::default()
+        };
+        emitter.set_policy(policy);
+        let prepared = emitter.preview(with_body("from\nBob")).unwrap();
+        assert_eq!(prepared.summary, "test: Mail \u{2014} from Bob");
+        assert_eq!(prepared.body, "");
+        let prepared = emitter.preview(with_body("from\tBob Smith")).unwrap();
+        assert_eq!(prepared.summary, "test: Mail \u{2014} from Bob\u{2026}");
+        assert!(emitter.preview(with_body("")).is_ok());
+        assert_eq!(fold_body("Mail", "", 16), "Mail");
+        assert_eq!(fold_body("A long summary", "body", 16), "A long summary");
+        // Daemons that show bodies are not affected
+        let daemon = mock::MockDaemon::new(&["body"]).await;
+        let emitter = self::emitter(&daemon, QubePolicy::default()).await;
+        let prepared = emitter.preview(with_body("from\nBob")).unwrap();
+        assert_eq!(prepared.summary, "test: Mail");
+        assert_eq!(prepared.body, "from\nBob");
+    }
+    #[tokio::test]
+    async fn test_preview() {
+        let daemon = mock::MockDaemon::new(&["body", "body-markup", "actions"]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let notification = v2("hello", |fields| {
+            fields.body = "<b>x</b>".to_owned();
+            fields.actions = vec!["default".to_owned(), "Open".to_owned()];
+            fields.urgency = Some(Urgency::Critical);
+        });
+        let prepared = emitter.preview(notification).unwrap();
+        assert_eq!(prepared.app_name, "Qubes VM test");
+        assert_eq!(prepared.summary, "test: hello");
+        assert_eq!(prepared.body, "<b>x</b>");
+        assert_eq!(prepared.actions, ["default", "Open"]);
+        assert_eq!(prepared.hints["urgency"], Value::from(2u8));
+        assert_eq!(prepared.expire_timeout, -1);
+        // Nothing was sent, and the image is rejected without being counted
+        let notification = v2("a", |fields| fields.image = Some(self::image(2, 2, 3, 12)));
+        assert!(emitter.preview(notification).is_err());
+        assert!(daemon.notifications().is_empty());
+        assert!(emitter.metrics().images_rejected.is_empty());
+    }
+    #[tokio::test]
+    async fn test_validate_reports_all() {
+        let daemon = mock::MockDaemon::new(&["actions"]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let bad = || {
+            v2(" ", |fields| {
+                fields.actions = vec!["default".to_owned()];
+                fields.image = Some(self::image(1000, 1, 3000, 3000));
+            })
+        };
+        let problems = emitter.validate(bad()).unwrap_err();
+        let problems: Vec<_> = problems.iter().map(ProxyError::to_string).collect();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].contains("even length"));
+        assert!(problems[1].contains("Empty summary"));
+        assert!(problems[2].contains("Width or height too large"));
+        // Only the first one is reported when sending
+        assert!(matches!(
+            emitter.send_notification(bad()).await,
+            Err(ProxyError::Validation(e)) if e.contains("even length")
+        ));
+        assert!(emitter.validate(notification("a")).is_ok());
+        assert!(daemon.notifications().is_empty());
+    }
+    #[tokio::test]
+    async fn test_image_limits_per_qube() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let with_image = || {
+            v2("a", |fields| {
+                fields.image = Some(self::image(1000, 1, 3000, 3000))
+            })
+        };
+        let untrusted = emitter(&daemon, QubePolicy::default()).await;
+        let policy = QubePolicy {
+            image_limits: ImageLimits {
+                max_width: 1024,
+                ..ImageLimits::default()
+            },
+            ..QubePolicy::default()
+        };
+        let screenshots =
+            NotificationEmitter::new(&daemon.connection, "screenshots".to_owned(), policy)
+                .await
+                .unwrap();
+        assert!(untrusted.send_notification(with_image()).await.is_err());
+        assert_eq!(
+            untrusted.metrics().images_rejected["Width or height too large"],
+            1
+        );
+        screenshots.send_notification(with_image()).await.unwrap();
+        assert!(daemon.notifications()[0].hints.contains_key("image-data"));
+    }
+    #[tokio::test]
+    async fn test_hints_merged() {
+        let daemon = mock::MockDaemon::new(&["sound"]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let notification = v2("a", |fields| {
+            fields.urgency = Some(Urgency::Critical);
+            fields.category = Some("im.received".to_owned());
+            fields.hints = vec![(
+                "sound-name".to_owned(),
+                HintValue::String("bell".to_owned()),
+            )];
+        });
+        emitter.send_notification(notification).await.unwrap();
+        let received = daemon.notifications();
+        let hints = &received[0].hints;
+        assert_eq!(hints.len(), 4);
+        assert_eq!(*hints["urgency"], Value::from(2u8));
+        assert_eq!(*hints["category"], Value::from(b"im.received".to_vec()));
+        assert_eq!(*hints["sound-name"], Value::from("bell"));
+    }
+    #[tokio::test]
+    async fn test_self_test() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        self_test(&daemon.connection).await.unwrap();
+        let received = daemon.notifications();
+        assert_eq!(received.len(), 1);
+        assert_eq!(
+            received[0].summary,
+            "@self-test: Notification proxy self-test"
+        );
+        assert_eq!(daemon.state.lock().unwrap().closed, [1]);
+        // A daemon that fails is reported
+        daemon.state.lock().unwrap().failures = 1;
+        assert!(self_test(&daemon.connection).await.is_err());
+    }
+    #[tokio::test]
+    async fn test_vmname_hint() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let spoofed = v2("a", |fields| {
+            fields.hints = vec![
+                (
+                    "x-qubes-vmname".to_owned(),
+                    HintValue::String("dom0".to_owned()),
+                ),
+                (
+                    "x-qubes-label-color".to_owned(),
+                    HintValue::String("0x000000".to_owned()),
+                ),
+            ]
+        });
+        let prepared = emitter.preview(spoofed).unwrap();
+        assert_eq!(prepared.hints["x-qubes-vmname"], Value::from("test"));
+        assert!(!prepared.hints.contains_key("x-qubes-label-color"));
+    }
+    #[tokio::test]
+    async fn test_reverse_path() {
+        let daemon = mock::MockDaemon::new(&["actions"]).await;
+        let a = NotificationEmitter::new(&daemon.connection, "a".to_owned(), QubePolicy::default());
+        let a = a.await.unwrap();
+        let b = NotificationEmitter::new(&daemon.connection, "b".to_owned(), QubePolicy::default());
+        let b = b.await.unwrap();
+        let mut to_a = mock::ReverseChannel::new(&a).await;
+        let mut to_b = mock::ReverseChannel::new(&b).await;
+        b.send_notification(notification("b")).await.unwrap();
+        a.send_notification(notification("a")).await.unwrap();
+        let id = a.send_notification(notification("a")).await.unwrap();
+        assert_eq!(id, 2);
+        // Daemon ID 3 is the second notification of a
+        daemon.invoke_action(3, "default").await;
+        daemon.close(3, 2).await;
+        let messages = to_a.receive(&a).await;
+        assert_eq!(messages.len(), 2, "{:?}", messages);
+        assert!(messages.iter().any(|message| matches!(
+            message,
+            ReplyMessage::ActionInvoked { id: 2, action } if action == "default"
+        )));
+        assert!(messages.iter().any(|message| matches!(
+            message,
+            ReplyMessage::Dismissed {
+                id: 2,
+                reason: CloseReason::Dismissed
+            }
+        )));
+        assert!(to_b.receive(&b).await.is_empty());
+    }
+    #[tokio::test]
+    async fn test_rate_limited_suppression_event() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            rate_limit: 1,
+            report_suppressed: true,
+            ..QubePolicy::default()
+        };
+        let emitter = self::emitter(&daemon, policy).await;
+        let mut channel = mock::ReverseChannel::new(&emitter).await;
+        let message = |id| Message {
+            id,
+            notification: notification("a"),
+        };
+        channel.send(&emitter, MINOR_VERSION, message(1)).await;
+        channel.send(&emitter, MINOR_VERSION, message(2)).await;
+        // Version 0 qubes do not understand the event
+        channel.send(&emitter, 0, message(3)).await;
+        let messages = channel.receive(&emitter).await;
+        assert!(matches!(
+            messages[0],
+            ReplyMessage::Id { id: 1, sequence: 1 }
+        ));
+        assert!(matches!(
+            messages[1],
+            ReplyMessage::DBusError { sequence: 2, .. }
+        ));
+        assert!(matches!(
+            &messages[2],
+            ReplyMessage::Suppressed { sequence: 2, reason } if reason == "rate-limited"
+        ));
+        assert!(matches!(
+            messages[3],
+            ReplyMessage::DBusError { sequence: 3, .. }
+        ));
+        assert_eq!(messages.len(), 4, "{:?}", messages);
+        // Reporting is opt-in
+        let mut policy = (*emitter.policy()).clone();
+        policy.report_suppressed = false;
+        emitter.set_policy(policy);
+        channel.send(&emitter, MINOR_VERSION, message(4)).await;
+        let messages = channel.receive(&emitter).await;
+        assert_eq!(messages.len(), 1, "{:?}", messages);
+    }
+    #[tokio::test]
+    async fn test_inline_reply() {
+        let with_reply = || {
+            v2("Message from Bob", |fields| {
+                fields.actions = vec!["inline-reply".to_owned(), "Reply".to_owned()];
+            })
+        };
+        for capabilities in [&["actions", "inline-reply"][..], &["actions"]] {
+            let capable = capabilities.contains(&"inline-reply");
+            let daemon = mock::MockDaemon::new(capabilities).await;
+            let emitter = self::emitter(&daemon, QubePolicy::default()).await;
+            let mut channel = mock::ReverseChannel::new(&emitter).await;
+            let id = emitter.send_notification(with_reply()).await.unwrap();
+            let plain = emitter.send_notification(notification("a")).await.unwrap();
+            // Either way, the daemon gets the action
+            assert_eq!(daemon.notifications()[0].actions, ["inline-reply", "Reply"]);
+            daemon.reply(1, "On my way\u{202e}\x1b[2J").await;
+            // Notifications without the action cannot be replied to
+            daemon.reply(2, "Spoofed").await;
+            daemon.invoke_action(1, "inline-reply").await;
+            let messages = channel.receive(&emitter).await;
+            let replies: Vec<_> = messages
+                .iter()
+                .filter_map(|message| match message {
+                    ReplyMessage::Replied { id, text } => Some((*id, &**text)),
+                    _ => None,
+                })
+                .collect();
+            if capable {
+                assert_eq!(replies, [(id, "On my way\u{FFFD}\u{FFFD}[2J")]);
+            } else {
+                assert!(replies.is_empty());
+            }
+            assert!(!replies.iter().any(|&(reply_id, _)| reply_id == plain));
+            // A daemon without inline replies shows them as a normal action
+            assert!(messages.iter().any(|message| matches!(
+                message,
+                ReplyMessage::ActionInvoked { id: 1, action } if action == "inline-reply"
+            )));
+            let dropped = emitter.metrics().signals_dropped;
+            let unexpected = if capable { 1 } else { 2 };
+            assert_eq!(dropped["unexpected-reply"], unexpected);
+        }
+        // Replies the qube does not read are dropped, oldest first
+        let daemon = mock::MockDaemon::new(&["actions", "inline-reply"]).await;
+        let policy = QubePolicy {
+            action_queue_size: 1,
+            ..QubePolicy::default()
+        };
+        let emitter = self::emitter(&daemon, policy).await;
+        emitter.send_notification(with_reply()).await.unwrap();
+        emitter.notification_replied(1, "a".to_owned()).await;
+        emitter.notification_replied(1, "b".to_owned()).await;
+        let metrics = emitter.metrics();
+        assert_eq!((metrics.replies_dropped, metrics.actions_dropped), (1, 0));
+        // Qubes that do not know about replies see the action instead
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let emitter = self::emitter(&daemon, QubePolicy::default()).await;
+        assert!(matches!(
+            emitter.reply_event(4, 1, "Hi".to_owned()),
+            ReplyMessage::ActionInvoked { id: 1, action } if action == "inline-reply"
+        ));
+    }
+    #[tokio::test]
+    async fn test_reload_keeps_open_notifications() {
+        let daemon = mock::MockDaemon::new(&["actions"]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let mut channel = mock::ReverseChannel::new(&emitter).await;
+        let first = emitter.send_notification(notification("a")).await.unwrap();
+        let second = emitter.send_notification(notification("a")).await.unwrap();
+        // Block the qube entirely
+        emitter.set_policy(QubePolicy {
+            allow_uncategorized: false,
+            allowed_categories: Some(vec![]),
+            ..QubePolicy::default()
+        });
+        assert!(matches!(
+            emitter.send_notification(notification("a")).await,
+            Err(ProxyError::PolicyRejected(
+                PolicyRejection::CategoryBlocked(None)
+            ))
+        ));
+        assert!(emitter.close(first).await.unwrap());
+        assert_eq!(daemon.state.lock().unwrap().closed, [1]);
+        daemon.invoke_action(2, "default").await;
+        daemon.close(2, 2).await;
+        let messages = channel.receive(&emitter).await;
+        assert!(messages.iter().any(|message| matches!(
+            message,
+            ReplyMessage::ActionInvoked { id, action } if *id == second && action == "default"
+        )));
+        assert!(messages.iter().any(|message| matches!(
+            message,
+            ReplyMessage::Dismissed { id, reason: CloseReason::Dismissed } if *id == second
+        )));
+    }
+    #[tokio::test]
+    async fn test_send_queue_sheds() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let queue = QubeSendQueue::new(2);
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let emitter = emitter.with_send_queue(queue.clone());
+        let with_urgency =
+            |summary: &str, level| v2(summary, |fields| fields.urgency = Some(level));
+        // Keep everything queued until both notifications were shed
+        let lock = emitter.ids.lock().await;
+        let (low, normal, critical, new_low, ()) = tokio::join!(
+            emitter.send_notification(with_urgency("1", Urgency::Low)),
+            emitter.send_notification(with_urgency("2", Urgency::Normal)),
+            emitter.send_notification(with_urgency("3", Urgency::Critical)),
+            emitter.send_notification(with_urgency("4", Urgency::Low)),
+            async {
+                while queue.shed() < 2 {
+                    tokio::task::yield_now().await
+                }
+                drop(lock)
+            },
+        );
+        let shed = |result: &Result<u32, ProxyError>| {
+            matches!(
+                result,
+                Err(ProxyError::PolicyRejected(PolicyRejection::QueueFull))
+            )
+        };
+        // The oldest of the least urgent makes room for the critical one,
+        // then the new low urgency one is less urgent than everything queued
+        assert!(shed(&low), "{:?}", low);
+        assert!(normal.is_ok());
+        assert!(critical.is_ok());
+        assert!(shed(&new_low));
+        assert_eq!(emitter.metrics().queue_shed, 2);
+        assert!(queue.is_empty());
+        let state = daemon.state.lock().unwrap();
+        let summaries: Vec<_> = state.notifications.iter().map(|n| &*n.summary).collect();
+        assert_eq!(summaries, ["test: 2", "test: 3"]);
+    }
+    #[tokio::test]
+    async fn test_category_icon() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            app_icon: "qube".to_owned(),
+            category_icons: vec![("email.arrived".to_owned(), "mail-unread".to_owned())],
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        let with_category =
+            |category: &str| v2("a", |fields| fields.category = Some(category.to_owned()));
+        let prepared = emitter.preview(with_category("email.arrived")).unwrap();
+        assert_eq!(prepared.app_icon, "mail-unread");
+        let prepared = emitter.preview(with_category("im.received")).unwrap();
+        assert_eq!(prepared.app_icon, "qube");
+        assert_eq!(emitter.preview(notification("a")).unwrap().app_icon, "qube");
+    }
+    #[tokio::test]
+    async fn test_off_spec_daemon() {
+        let daemon = mock::MockDaemon::off_spec().await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        assert_eq!(emitter.capabilities(), Capabilities::default());
+        assert_eq!(emitter.server_info(), ServerInfo::unknown());
+    }
+    #[tokio::test]
+    async fn test_refresh_daemon_info() {
+        let daemon = mock::MockDaemon::new(&["body"]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        assert_eq!(emitter.capabilities(), Capabilities::BODY);
+        daemon.state.lock().unwrap().capabilities = vec!["actions".to_owned()];
+        // Cached until refreshed
+        assert_eq!(emitter.capabilities(), Capabilities::BODY);
+        emitter.refresh_daemon_info().await.unwrap();
+        assert_eq!(emitter.capabilities(), Capabilities::ACTIONS);
+        assert_eq!(emitter.server_info().name, "Mock");
+        // A failure keeps what is cached
+        daemon.state.lock().unwrap().capabilities_failures = 1;
+        assert!(emitter.refresh_daemon_info().await.is_err());
+        assert_eq!(emitter.capabilities(), Capabilities::ACTIONS);
+    }
+    #[tokio::test]
+    async fn test_force_transient() {
+        let daemon = mock::MockDaemon::new(&["persistence"]).await;
+        let policy = QubePolicy {
+            force_transient: true,
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        let id = emitter.send_notification(notification("a")).await.unwrap();
+        let received = daemon.notifications();
+        assert_eq!(*received[0].hints["transient"], Value::from(true));
+        // Tracked until the daemon closes it, like any other notification
+        assert_eq!(emitter.ids.lock().await.len(), 1);
+        assert_eq!(emitter.notification_closed(1).await, Some(id));
+        assert!(emitter.ids.lock().await.is_empty());
+        // Not forced on other qubes
+        let emitter = self::emitter(&daemon, QubePolicy::default()).await;
+        let prepared = emitter.preview(notification("a")).unwrap();
+        assert!(!prepared.hints.contains_key("transient"));
+    }
+    #[tokio::test]
+    async fn test_history() {
+        let daemon = mock::MockDaemon::new(&["body"]).await;
+        let policy = QubePolicy {
+            history_size: 2,
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        let with_body = |summary: &str| v2(summary, |fields| fields.body = "secret".to_owned());
+        for summary in ["a", "b", "c"] {
+            emitter.send_notification(with_body(summary)).await.unwrap();
+        }
+        let history = emitter.history();
+        let summaries: Vec<_> = history.iter().map(|entry| &*entry.summary).collect();
+        assert_eq!(summaries, ["test: b", "test: c"]);
+        // Bodies are only kept if the policy allows it
+        assert!(history.iter().all(|entry| entry.body.is_none()));
+        let policy = QubePolicy {
+            history_bodies: true,
+            ..QubePolicy::default()
+        };
+        let emitter = self::emitter(&daemon, policy).await;
+        emitter.send_notification(with_body("a")).await.unwrap();
+        assert_eq!(emitter.history()[0].body.as_deref(), Some("secret"));
+    }
+    #[tokio::test]
+    async fn test_grouping_hint() {
+        let dunst = [
+            "body",
+            "x-dunst-stack-tag",
+            "x-canonical-private-synchronous",
+        ];
+        let daemon = mock::MockDaemon::new(&dunst).await;
+        let policy = QubePolicy {
+            group_notifications: true,
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy.clone()).await;
+        let spoofed = v2("a", |fields| {
+            fields.category = Some("email.arrived".to_owned());
+            fields.hints = vec![(
+                "x-dunst-stack-tag".to_owned(),
+                HintValue::String("qubes-dom0".to_owned()),
+            )]
+        });
+        let prepared = emitter.preview(spoofed).unwrap();
+        assert_eq!(
+            prepared.hints["x-dunst-stack-tag"],
+            Value::from("qubes-test")
+        );
+        assert!(!prepared
+            .hints
+            .contains_key("x-canonical-private-synchronous"));
+        let policy = QubePolicy {
+            group_by_category: true,
+            ..policy
+        };
+        let emitter = self::emitter(&daemon, policy).await;
+        let categorized = v2("a", |fields| {
+            fields.category = Some("email.arrived".to_owned());
+        });
+        let prepared = emitter.preview(categorized).unwrap();
+        assert_eq!(
+            prepared.hints["x-dunst-stack-tag"],
+            Value::from("qubes-test-email.arrived")
+        );
+        let caps = Capabilities::SYNCHRONOUS;
+        let hint = grouping_hint(caps, None, "work", None).unwrap();
+        assert_eq!(
+            hint,
+            ("x-canonical-private-synchronous", "qubes-work".to_owned())
+        );
+        let hint = grouping_hint(caps, Some("gui2"), "work", Some("im")).unwrap();
+        assert_eq!(hint.1, "gui2/qubes-work-im");
+        assert!(grouping_hint(Capabilities::BODY, None, "work", None).is_none());
+    }
+    #[tokio::test]
+    async fn test_error_kinds() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            rate_limit: 2,
+            denied_categories: vec!["im".to_owned()],
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        let malformed = v2("a", |fields| fields.expire_timeout = -2);
+        let e = emitter.send_notification(malformed).await.unwrap_err();
+        assert!(matches!(e, ProxyError::Validation(_)));
+        assert_eq!(e.code(), "invalid");
+        let blocked = v2("a", |fields| {
+            fields.category = Some("im.received".to_owned())
+        });
+        let e = emitter.send_notification(blocked).await.unwrap_err();
+        assert!(matches!(
+            e,
+            ProxyError::PolicyRejected(PolicyRejection::CategoryBlocked(Some(_)))
+        ));
+        assert_eq!(e.code(), "blocked-category");
+        let e = emitter
+            .send_notification(notification("a"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e,
+            ProxyError::PolicyRejected(PolicyRejection::RateLimited)
+        ));
+        assert_eq!(e.code(), "rate-limited");
+    }
+    #[tokio::test]
+    async fn test_capabilities_unavailable() {
+        let daemon = mock::MockDaemon::new(&["actions", "body-markup"]).await;
+        daemon.state.lock().unwrap().capabilities_failures = 2;
+        let clock = Rc::new(ManualClock::new());
+        let emitter = emitter(&daemon, QubePolicy::default())
+            .await
+            .with_clock(clock);
+        assert!(!emitter.capabilities_known());
+        assert_eq!(emitter.capabilities(), Capabilities::empty());
+        let notification = v2("<b>", |fields| {
+            fields.body = "<i>".to_owned();
+            fields.actions = vec!["default".to_owned(), "Open".to_owned()];
+        });
+        emitter.send_notification(notification).await.unwrap();
+        let received = daemon.notifications();
+        // The daemon might interpret markup after all
+        assert_eq!(received[0].body, "&lt;i&gt;");
+        assert!(received[0].actions.is_empty());
+        emitter.fetch_missing_capabilities().await;
+        assert!(emitter.capabilities_known());
+        assert!(emitter.actions() && emitter.body_markup());
+    }
+    #[tokio::test]
+    async fn test_actions_unsupported() {
+        let daemon = mock::MockDaemon::new(&["body"]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let notification = v2("a", |fields| {
+            fields.actions = vec!["default".to_owned(), "Open".to_owned()];
+        });
+        assert!(notification.has_actions());
+        let id = emitter.send_notification(notification).await.unwrap();
+        assert!(daemon.notifications()[0].actions.is_empty());
+        match emitter.actions_unavailable_event(3, id) {
+            Some(ReplyMessage::ActionsUnavailable { id: event_id }) => assert_eq!(event_id, id),
+            e => panic!("unexpected event {:?}", e),
+        }
+        // Older clients do not understand the event
+        assert!(emitter.actions_unavailable_event(2, id).is_none());
+        let daemon = mock::MockDaemon::new(&["actions"]).await;
+        let emitter = self::emitter(&daemon, QubePolicy::default()).await;
+        assert!(emitter.actions_unavailable_event(3, id).is_none());
+    }
+    #[tokio::test]
+    async fn test_default_urgency() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            default_urgency: Some(Urgency::Normal),
+            max_urgency: Urgency::Normal,
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        let urgency = |urgency| {
+            let notification = v2("a", |fields| fields.urgency = urgency);
+            emitter
+                .preview(notification)
+                .unwrap()
+                .hints
+                .remove("urgency")
+        };
+        assert_eq!(urgency(None), Some(Value::from(1u8)));
+        assert_eq!(urgency(Some(Urgency::Low)), Some(Value::from(0u8)));
+        assert_eq!(urgency(Some(Urgency::Critical)), Some(Value::from(1u8)));
+        // The default is clamped too
+        let policy = QubePolicy {
+            default_urgency: Some(Urgency::Low),
+            min_urgency: Urgency::Normal,
+            ..QubePolicy::default()
+        };
+        assert_eq!(policy.urgency(None), Some(Urgency::Normal));
+        assert_eq!(QubePolicy::default().urgency(None), None);
+    }
+    #[tokio::test]
+    async fn test_qube_stats() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            rate_limit: 3,
+            denied_categories: vec!["im".to_owned()],
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        let start = emitter.qube_stats().since;
+        emitter.send_notification(notification("a")).await.unwrap();
+        let bad_image = v2("b", |fields| fields.image = Some(self::image(2, 2, 3, 12)));
+        assert!(emitter.send_notification(bad_image).await.is_err());
+        let blocked = v2("c", |fields| {
+            fields.category = Some("im.received".to_owned())
+        });
+        assert!(emitter.send_notification(blocked).await.is_err());
+        assert!(emitter.send_notification(notification("d")).await.is_err());
+        let stats = emitter.qube_stats();
+        assert_eq!(
+            stats,
+            QubeStats {
+                since: start,
+                sent: 1,
+                rate_limited: 1,
+                blocked: 1,
+                validation_failed: 1,
+                images_rejected: 1,
+                last_seq: Some(emitter.history()[0].seq),
+            }
+        );
+        emitter.reset_stats();
+        let stats = emitter.qube_stats();
+        assert!(stats.since >= start);
+        assert_eq!(
+            (stats.sent, stats.rate_limited, stats.images_rejected),
+            (0, 0, 0)
+        );
+    }
+    #[tokio::test]
+    async fn test_rate_limit_manual_clock() {
+        use std::time::Duration;
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            rate_limit: 2,
+            rate_limit_window: Duration::from_secs(10),
+            ..QubePolicy::default()
+        };
+        let clock = Rc::new(ManualClock::new());
+        let emitter = emitter(&daemon, policy).await.with_clock(clock.clone());
+        let send = || emitter.send_notification(notification("a"));
+        assert!(send().await.is_ok());
+        clock.advance(Duration::from_secs(9));
+        assert!(send().await.is_ok());
+        assert!(matches!(
+            send().await,
+            Err(ProxyError::PolicyRejected(PolicyRejection::RateLimited))
+        ));
+        // The window ends exactly 10 seconds after it started
+        clock.advance(Duration::from_secs(1));
+        assert!(send().await.is_ok());
+        clock.advance(Duration::from_millis(9999));
+        assert!(send().await.is_ok());
+        assert!(matches!(
+            send().await,
+            Err(ProxyError::PolicyRejected(PolicyRejection::RateLimited))
+        ));
+        assert_eq!(emitter.qube_stats().rate_limited, 2);
+    }
+    #[tokio::test]
+    async fn test_replace_rate_limit() {
+        use std::time::Duration;
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            rate_limit: 2,
+            replace_rate_limit: Some(5),
+            replace_cooldown: Duration::ZERO,
+            ..QubePolicy::default()
+        };
+        let clock = Rc::new(ManualClock::new());
+        let emitter = emitter(&daemon, policy).await.with_clock(clock.clone());
+        let rate_limited = |result| {
+            matches!(
+                result,
+                Err(ProxyError::PolicyRejected(PolicyRejection::RateLimited))
+            )
+        };
+        let progress = emitter.send_notification(notification("0%")).await;
+        let progress = progress.unwrap();
+        for _ in 0..5 {
+            let sent = emitter.notify_or_replace(Some(progress), notification("1%"));
+            assert_eq!(sent.await.unwrap(), progress);
+        }
+        let sent = emitter.notify_or_replace(Some(progress), notification("2%"));
+        assert!(rate_limited(sent.await));
+        // Replacements did not use up the budget for new notifications
+        assert!(emitter.send_notification(notification("a")).await.is_ok());
+        assert!(rate_limited(
+            emitter.send_notification(notification("b")).await
+        ));
+        // Pretending to replace a notification that is not open does not help
+        let sent = emitter.notify_or_replace(Some(42), notification("c"));
+        assert!(rate_limited(sent.await));
+        clock.advance(Duration::from_secs(10));
+        let sent = emitter.notify_or_replace(Some(progress), notification("3%"));
+        assert_eq!(sent.await.unwrap(), progress);
+        // Without a budget of their own, replacements share the limit
+        let mut policy = (*emitter.policy()).clone();
+        policy.replace_rate_limit = None;
+        emitter.set_policy(policy);
+        assert!(emitter.send_notification(notification("d")).await.is_ok());
+        assert!(emitter.send_notification(notification("e")).await.is_ok());
+        let sent = emitter.notify_or_replace(Some(progress), notification("4%"));
+        assert!(rate_limited(sent.await));
+    }
+    #[test]
+    fn test_is_blank() {
+        assert!(is_blank(""));
+        assert!(is_blank(" \t\n"));
+        assert!(is_blank("\u{200B}\u{200B}"));
+        assert!(is_blank(" \u{FEFF}\u{2060} "));
+        assert!(!is_blank("\u{200B}a"));
+    }
+    #[tokio::test]
+    async fn test_blank_summary() {
+        let daemon = mock::MockDaemon::new(&["body"]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        for summary in ["", "  \t ", "\u{200B}\u{200C}\u{200D}"] {
+            match emitter.preview(notification(summary)) {
+                Err(ProxyError::Validation(message)) => assert_eq!(message, "Empty summary"),
+                e => panic!("unexpected result {:?}", e),
+            }
+        }
+        // Blank bodies are sent as empty
+        let blank_body = v2("a", |fields| fields.body = "\u{200B} ".to_owned());
+        assert_eq!(emitter.preview(blank_body).unwrap().body, "");
+        let policy = QubePolicy {
+            allow_empty_summary: true,
+            ..QubePolicy::default()
+        };
+        let emitter = self::emitter(&daemon, policy).await;
+        assert!(emitter.preview(notification("\u{200B}")).is_ok());
+    }
+    #[tokio::test]
+    async fn test_fallback_sink() {
+        let primary = mock::MockDaemon::new(&[]).await;
+        let fallback = mock::MockDaemon::new(&[]).await;
+        // The primary daemon is down for good
+        primary.state.lock().unwrap().failures = u32::MAX;
+        let policy = QubePolicy {
+            retries: 0,
+            ..QubePolicy::default()
+        };
+        let sink = DaemonSink::new("fallback".to_owned(), &fallback.connection)
+            .await
+            .unwrap();
+        let emitter = emitter(&primary, policy)
+            .await
+            .with_fallback(Box::new(sink));
+        let id = emitter.send_notification(notification("a")).await.unwrap();
+        assert!(primary.notifications().is_empty());
+        assert_eq!(fallback.notifications()[0].summary, "test: a");
+        assert_eq!(emitter.metrics().delivered["fallback"], 1);
+        // Signals are routed by sink
+        assert_eq!(emitter.notification_closed(1).await, None);
+        assert_eq!(emitter.notification_closed_in(1, 1).await, Some(id));
+        // With no fallback left, the error of the last sink is returned
+        fallback.state.lock().unwrap().failures = u32::MAX;
+        let sent = emitter.send_notification(notification("b")).await;
+        assert!(matches!(sent, Err(ProxyError::DaemonUnavailable)));
+    }
+    #[tokio::test]
+    async fn test_zero_id() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        daemon.state.lock().unwrap().zero_ids = 1;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let sent = emitter.send_notification(notification("a")).await;
+        assert!(matches!(sent, Err(ProxyError::DaemonUnavailable)));
+        // No mapping was created for ID 0
+        assert_eq!(emitter.notification_closed(0).await, None);
+        assert!(!emitter.close(1).await.unwrap());
+        let id = emitter.send_notification(notification("b")).await.unwrap();
+        assert_eq!(emitter.notification_closed(1).await, Some(id));
+    }
+    #[tokio::test]
+    async fn test_shutdown_grace() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            shutdown_grace: std::time::Duration::from_secs(30),
+            keep_critical_on_shutdown: true,
+            ..QubePolicy::default()
+        };
+        let clock = Rc::new(ManualClock::new());
+        let emitter = emitter(&daemon, policy).await.with_clock(clock);
+        emitter.send_notification(notification("a")).await.unwrap();
+        let critical = v2("b", |fields| fields.urgency = Some(Urgency::Critical));
+        let critical_id = emitter.send_notification(critical).await.unwrap();
+        // The qube comes back within the grace period
+        assert!(!emitter.close_after_shutdown(async {}).await);
+        assert!(daemon.state.lock().unwrap().closed.is_empty());
+        // It does not
+        let never = std::future::pending();
+        assert!(emitter.close_after_shutdown(never).await);
+        assert_eq!(daemon.state.lock().unwrap().closed, [1]);
+        // Critical notifications are kept
+        assert_eq!(
+            emitter.ids.lock().await.server_id(critical_id),
+            Some((0, 2))
+        );
+    }
+    #[tokio::test]
+    async fn test_shutdown_grace_return() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            shutdown_grace: std::time::Duration::from_secs(30),
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        emitter.send_notification(notification("a")).await.unwrap();
+        let watch = ReturnWatch::listen(None, emitter.qube_name()).unwrap();
+        // The next server for the qube starts
+        assert!(announce_return(None, "test").await);
+        assert!(!emitter.close_after_shutdown(watch.returned()).await);
+        assert!(daemon.state.lock().unwrap().closed.is_empty());
+    }
+    #[tokio::test]
+    async fn test_close_category() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let with_category =
+            |category: &str| v2("a", |fields| fields.category = Some(category.to_owned()));
+        let mail = emitter.send_notification(with_category("email.arrived"));
+        let mail = mail.await.unwrap();
+        for _ in 0..2 {
+            let sent = emitter.send_notification(with_category("im.received"));
+            sent.await.unwrap();
+        }
+        assert!(emitter.close_category("email").await.is_empty());
+        let messages = emitter.close_category("im.received").await;
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|message| matches!(
+            message,
+            ReplyMessage::Dismissed { id, reason: CloseReason::Closed } if *id != mail
+        )));
+        let mut closed = daemon.state.lock().unwrap().closed.clone();
+        closed.sort();
+        assert_eq!(closed, [2, 3]);
+        let ids = emitter.ids.lock().await;
+        assert_eq!(ids.len(), 1);
+        assert_eq!(ids.server_id(mail), Some((0, 1)));
+    }
+    #[tokio::test]
+    async fn test_quiet_hours() {
+        let hour = std::time::Duration::from_secs(3600);
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            quiet_hours: Some(QuietHours {
+                start: 22 * hour,
+                end: 7 * hour,
+            }),
+            ..QubePolicy::default()
+        };
+        let clock = Rc::new(ManualClock::at_time_of_day(21 * hour));
+        let emitter = emitter(&daemon, policy).await.with_clock(clock.clone());
+        let start = clock.now();
+        let a = emitter.send_notification(notification("a")).await.unwrap();
+        clock.advance(2 * hour);
+        // Held until the end of the quiet hours, but the qube does not wait
+        let b = emitter.send_notification(notification("b")).await.unwrap();
+        assert_eq!(clock.now(), start + 2 * hour);
+        assert_eq!(daemon.notifications().len(), 1);
+        assert!(emitter.is_active().await);
+        let critical = v2("c", |fields| fields.urgency = Some(Urgency::Critical));
+        let c = emitter.send_notification(critical).await.unwrap();
+        assert_eq!(daemon.notifications().len(), 2);
+        // Updates replace what is held
+        let update = |local_id, summary: &str| v2(summary, |fields| fields.replaces_id = local_id);
+        let b2 = emitter.send_notification(update(b, "b2")).await.unwrap();
+        assert_eq!(b2, b);
+        let a2 = emitter.send_notification(update(a, "a2")).await.unwrap();
+        assert_eq!(a2, a);
+        let d = emitter.send_notification(notification("d")).await.unwrap();
+        assert!(emitter.close(d).await.unwrap());
+        assert!(emitter.release_held().await.is_empty());
+        assert_eq!(daemon.notifications().len(), 2);
+        clock.advance(8 * hour);
+        assert!(emitter.release_held().await.is_empty());
+        let summaries: Vec<_> = daemon
+            .notifications()
+            .into_iter()
+            .map(|n| n.summary)
+            .collect();
+        assert_eq!(summaries, ["test: a", "test: c", "test: b2", "test: a2"]);
+        let ids = emitter.ids.lock().await;
+        assert_eq!(ids.server_id(b), Some((0, 3)));
+        assert_eq!(ids.server_id(a), Some((0, 1)));
+        assert_eq!(ids.server_id(c), Some((0, 2)));
+        assert_eq!(ids.server_id(d), None);
+        drop(ids);
+        // The queue is bounded
+        clock.advance(16 * hour);
+        let mut policy = (*emitter.policy()).clone();
+        policy.quiet_hours_queue_size = 1;
+        emitter.set_policy(policy);
+        emitter.send_notification(notification("e")).await.unwrap();
+        assert!(matches!(
+            emitter.send_notification(notification("f")).await,
+            Err(ProxyError::PolicyRejected(PolicyRejection::QueueFull))
+        ));
+        assert_eq!(emitter.close_all().await.len(), 4);
+        // Or dropped
+        let mut policy = (*emitter.policy()).clone();
+        policy.drop_during_quiet_hours = true;
+        emitter.set_policy(policy);
+        assert!(matches!(
+            emitter.send_notification(notification("d")).await,
+            Err(ProxyError::PolicyRejected(PolicyRejection::QuietHours))
+        ));
+        assert_eq!(daemon.notifications().len(), 4);
+    }
+    #[tokio::test]
+    async fn test_max_lifetime() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            max_lifetime: Some(std::time::Duration::from_secs(60)),
+            replace_cooldown: std::time::Duration::ZERO,
+            ..QubePolicy::default()
+        };
+        let clock = Rc::new(ManualClock::new());
+        let emitter = emitter(&daemon, policy).await.with_clock(clock.clone());
+        let stuck = emitter.send_notification(notification("a")).await.unwrap();
+        let critical = v2("b", |fields| fields.urgency = Some(Urgency::Critical));
+        let critical = emitter.send_notification(critical).await.unwrap();
+        clock.advance(std::time::Duration::from_secs(30));
+        let updated = emitter.send_notification(notification("c")).await.unwrap();
+        let updated = emitter.notify_or_replace(Some(updated), notification("c"));
+        let updated = updated.await.unwrap();
+        assert!(emitter.close_expired().await.is_empty());
+        clock.advance(std::time::Duration::from_secs(30));
+        assert_eq!(emitter.close_expired().await, [stuck]);
+        assert_eq!(daemon.state.lock().unwrap().closed, [1]);
+        let ids = emitter.ids.lock().await;
+        assert!(ids.server_id(stuck).is_none());
+        assert!(ids.server_id(critical).is_some());
+        assert!(ids.server_id(updated).is_some());
+    }
+    #[tokio::test]
+    async fn test_progress_value() {
+        let policy = QubePolicy {
+            render_progress_in_summary: true,
+            ..QubePolicy::default()
+        };
+        let download = v2("Downloading", |fields| {
+            fields.hints = vec![("value".to_owned(), HintValue::Int32(42))]
+        });
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let emitter = emitter(&daemon, policy.clone()).await;
+        let prepared = emitter.preview(download).unwrap();
+        assert_eq!(prepared.summary, "test: Downloading (42%)");
+        assert!(!prepared.hints.contains_key("value"));
+        let download = v2("Downloading", |fields| {
+            fields.hints = vec![("value".to_owned(), HintValue::UInt32(42))]
+        });
+        let daemon = mock::MockDaemon::new(&["progress"]).await;
+        let emitter = self::emitter(&daemon, policy).await;
+        let prepared = emitter.preview(download).unwrap();
+        assert_eq!(prepared.summary, "test: Downloading");
+        assert_eq!(prepared.hints["value"], Value::from(42i32));
+        // Out of range
+        let hint = vec![("value".to_owned(), HintValue::Int32(101))];
+        assert!(filter_hints(hint, Capabilities::PROGRESS, &emitter.policy()).is_empty());
+    }
+    #[tokio::test]
+    async fn test_replace_drops_image() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            replace_cooldown: std::time::Duration::ZERO,
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        let with_image = v2("a", |fields| fields.image = Some(self::image(2, 2, 6, 12)));
+        let id = emitter.send_notification(with_image).await.unwrap();
+        let text_only = notification("b");
+        assert_eq!(
+            emitter
+                .notify_or_replace(Some(id), text_only)
+                .await
+                .unwrap(),
+            id
+        );
+        let received = daemon.notifications();
+        assert!(received[0].hints.contains_key("image-data"));
+        assert_eq!(received[1].replaces_id, 1);
+        for key in IMAGE_HINT_KEYS {
+            assert!(!received[1].hints.contains_key(key));
+        }
+    }
+    #[tokio::test]
+    async fn test_body_footer() {
+        let daemon = mock::MockDaemon::new(&["body", "body-markup"]).await;
+        let policy = QubePolicy {
+            body_footer: true,
+            max_message_size: 1024,
+            ..QubePolicy::default()
+        };
+        // Real qube names cannot contain markup, but the footer does not rely
+        // on that
+        let emitter = NotificationEmitter::new(&daemon.connection, "a&b".to_owned(), policy)
+            .await
+            .unwrap();
+        let with_body = |body: &str| v2("s", |fields| fields.body = body.to_owned());
+        let prepared = emitter.preview(with_body("x<y")).unwrap();
+        assert_eq!(prepared.body, "x&lt;y\n\u{2014} from a&amp;b");
+        let prepared = emitter.preview(with_body("")).unwrap();
+        assert_eq!(prepared.body, "\u{2014} from a&amp;b");
+        // The body is truncated to make room for the footer
+        let prepared = emitter.preview(with_body(&"&".repeat(1024))).unwrap();
+        assert!(prepared.body.len() < 1024);
+        assert!(prepared.body.starts_with("&amp;"));
+        assert!(prepared.body.ends_with("&amp;\n\u{2014} from a&amp;b"));
+    }
+    #[tokio::test]
+    async fn test_duplicate_body() {
+        let daemon = mock::MockDaemon::new(&["body"]).await;
+        let policy = QubePolicy {
+            drop_duplicate_body: true,
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        let with_body =
+            |summary: &str, body: &str| v2(summary, |fields| fields.body = body.to_owned());
+        let prepared = emitter.preview(with_body("New mail", "New mail")).unwrap();
+        assert_eq!(prepared.summary, "test: New mail");
+        assert_eq!(prepared.body, "");
+        // Compared after sanitization
+        let prepared = emitter.preview(with_body("New\u{7}mail", "New\u{8}mail"));
+        assert_eq!(prepared.unwrap().body, "");
+        let prepared = emitter.preview(with_body("New mail", "From Alice"));
+        assert_eq!(prepared.unwrap().body, "From Alice");
+        // Off by default
+        let emitter = self::emitter(&daemon, QubePolicy::default()).await;
+        let prepared = emitter.preview(with_body("New mail", "New mail"));
+        assert_eq!(prepared.unwrap().body, "New mail");
+    }
+    #[tokio::test]
+    async fn test_markup_off() {
+        let daemon = mock::MockDaemon::new(&["body", "body-markup", "body-hyperlinks"]).await;
+        let policy = QubePolicy {
+            markup: false,
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        assert!(emitter.capabilities().contains(Capabilities::BODY_MARKUP));
+        assert_eq!(emitter.filtered_capabilities(), Capabilities::BODY);
+        let notification = v2("a", |fields| {
+            fields.body = "<a href=\"x\">&'</a>".to_owned()
+        });
+        assert_eq!(
+            emitter.preview(notification).unwrap().body,
+            "&lt;a href=&quot;x&quot;&gt;&amp;&apos;&lt;/a&gt;"
+        );
+        let daemon = mock::MockDaemon::new(&["body-markup"]).await;
+        let emitter = self::emitter(&daemon, QubePolicy::default()).await;
+        assert_eq!(emitter.filtered_capabilities(), Capabilities::BODY_MARKUP);
+    }
+    #[tokio::test]
+    async fn test_sequence_numbers() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let path = std::env::temp_dir().join(format!("sequence-test-{}", std::process::id()));
+        let work =
+            NotificationEmitter::new(&daemon.connection, "work".to_owned(), Default::default());
+        let work = work.await.unwrap().with_sequence_file(path.clone());
+        let personal = NotificationEmitter::new(
+            &daemon.connection,
+            "personal".to_owned(),
+            Default::default(),
+        );
+        let personal = personal.await.unwrap().with_sequence_file(path.clone());
+        let last_seq = |emitter: &NotificationEmitter| emitter.history().last().unwrap().seq;
+        let mut seqs = vec![];
+        for _ in 0..3 {
+            work.send_notification(notification("a")).await.unwrap();
+            seqs.push(last_seq(&work));
+            personal.send_notification(notification("b")).await.unwrap();
+            seqs.push(last_seq(&personal));
+        }
+        // Both qubes use local IDs 1 to 3, but the sequence numbers are
+        // unique to a notification and follow the order it was forwarded in
+        assert_eq!(seqs, [1, 2, 3, 4, 5, 6]);
+        assert_eq!(personal.qube_stats().last_seq, Some(6));
+        // Another server process takes the next number
+        assert_eq!(next_seq_in(&path).unwrap(), 7);
+        work.send_notification(notification("c")).await.unwrap();
+        assert_eq!(last_seq(&work), 8);
+        // Even when they take them at the same time
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let seqs: Vec<_> = (0..25).map(|_| next_seq_in(&path).unwrap()).collect();
+                    seqs
+                })
+            })
+            .collect();
+        let mut taken: Vec<_> = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect();
+        taken.sort();
+        assert_eq!(taken, (9..109).collect::<Vec<_>>());
+        std::fs::write(&path, "x\n").unwrap();
+        assert!(next_seq_in(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[tokio::test]
+    async fn test_allow_body_off() {
+        let daemon = mock::MockDaemon::new(&["body", "body-markup", "actions"]).await;
+        let policy = QubePolicy {
+            allow_body: false,
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        assert_eq!(emitter.filtered_capabilities(), Capabilities::ACTIONS);
+        let notification = v2("a", |fields| fields.body = "untrusted text".to_owned());
+        emitter.send_notification(notification).await.unwrap();
+        let received = daemon.notifications();
+        assert_eq!(received[0].summary, "test: a");
+        assert_eq!(received[0].body, "");
+    }
+    #[test]
+    fn test_sanitize_markup() {
+        let cases = [
+            ("<b>a</b> <i>b</i> <u>c</u>", "<b>a</b> <i>b</i> <u>c</u>"),
+            (
+                "<b>bold <i>it</i> > end",
+                "&lt;b&gt;bold <i>it</i> &gt; end",
+            ),
+            ("<i>x</i></b>", "<i>x</i>&lt;/b&gt;"),
+            ("<b><i>x</b></i>", "&lt;b&gt;<i>x&lt;/b&gt;</i>"),
+            (
+                "<b class=\"x\">y</b>",
+                "&lt;b class=&quot;x&quot;&gt;y&lt;/b&gt;",
+            ),
+            (
+                "<a href=\"x\">y</a>",
+                "&lt;a href=&quot;x&quot;&gt;y&lt;/a&gt;",
+            ),
+            (
+                "a &amp; b & c &#x202e; <",
+                "a &amp; b &amp; c &amp;#x202e; &lt;",
+            ),
+            ("<b", "&lt;b"),
+        ];
+        for (body, sanitized) in cases {
+            assert_eq!(sanitize_markup(body), sanitized, "{:?}", body);
+            assert_eq!(sanitize_markup(sanitized), sanitized, "{:?}", body);
+        }
+        let deep = "<b>".repeat(MAX_MARKUP_DEPTH + 1) + &"</b>".repeat(MAX_MARKUP_DEPTH + 1);
+        let sanitized = sanitize_markup(&deep);
+        assert!(sanitized.starts_with(&"<b>".repeat(MAX_MARKUP_DEPTH)));
+        assert_eq!(sanitized.matches("&lt;b&gt;").count(), 1);
+        assert_eq!(sanitized.matches("&lt;/b&gt;").count(), 1);
+    }
+    #[tokio::test]
+    async fn test_partial_markup() {
+        let daemon = mock::MockDaemon::new(&["body", "body-markup"]).await;
+        let emitter = self::emitter(&daemon, QubePolicy::default()).await;
+        let notification = v2("a", |fields| {
+            fields.body = "<b>unclosed, a > stray and <i>fine</i>".to_owned()
+        });
+        assert_eq!(
+            emitter.preview(notification).unwrap().body,
+            "&lt;b&gt;unclosed, a &gt; stray and <i>fine</i>"
+        );
+    }
+    #[tokio::test]
+    async fn test_provenance_unambiguous() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let work = emitter(&daemon, QubePolicy::default()).await;
+        // Pretending to be from another qube does not hide the real one
+        let prepared = work.preview(notification("vault: Password")).unwrap();
+        assert_eq!(prepared.summary, "test: vault: Password");
+        let (qube, _) = prepared.summary.split_once(PROVENANCE_SEPARATOR).unwrap();
+        assert_eq!(qube, "test");
+        let odd =
+            NotificationEmitter::new(&daemon.connection, "a: b".to_owned(), QubePolicy::default());
+        let odd = odd.await.unwrap();
+        let prepared = odd.preview(notification("c")).unwrap();
+        let (qube, _) = prepared.summary.split_once(PROVENANCE_SEPARATOR).unwrap();
+        assert_eq!(qube, "a\u{FFFD} b");
+        assert_eq!(prepared.app_name, "Qubes VM a\u{FFFD} b");
+    }
+    #[tokio::test]
+    async fn test_sound_file() {
+        let daemon = mock::MockDaemon::new(&["sound"]).await;
+        let policy = QubePolicy {
+            sound_files: true,
+            max_sound_file_size: 64,
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        let with_sound = |data: Vec<u8>| {
+            v2("a", |fields| {
+                fields.hints = vec![("sound-file".to_owned(), HintValue::Bytes(data))]
+            })
+        };
+        // The smallest WAV file there is: no samples, and not even a format
+        let mut wav = b"RIFF\x04\0\0\0WAVE".to_vec();
+        let id = emitter.send_notification(with_sound(wav.clone())).await;
+        let id = id.unwrap();
+        let path = {
+            let state = daemon.state.lock().unwrap();
+            let path = &state.notifications[0].hints["sound-file"];
+            String::try_from(path.clone()).unwrap()
+        };
+        // The file belongs to the proxy, and is gone once the notification is
+        assert!(!path.contains("test"));
+        assert_eq!(std::fs::read(&path).unwrap(), wav);
+        assert!(emitter.close(id).await.unwrap());
+        assert!(!std::path::Path::new(&path).exists());
+        wav[4] = 64;
+        wav.resize(72, 0);
+        assert!(matches!(
+            emitter.send_notification(with_sound(wav)).await,
+            Err(ProxyError::Validation(_))
+        ));
+        assert!(matches!(
+            emitter.send_notification(with_sound(b"ID3".to_vec())).await,
+            Err(ProxyError::Validation(_))
+        ));
+        // Qubes cannot name a file of their own
+        let notification = v2("b", |fields| {
+            let path = HintValue::String("/etc/shadow".to_owned());
+            fields.hints = vec![("sound-file".to_owned(), path)]
+        });
+        emitter.send_notification(notification).await.unwrap();
+        let received = daemon.notifications();
+        assert_eq!(received.len(), 2);
+        assert!(!received[1].hints.contains_key("sound-file"));
+    }
+    #[tokio::test]
+    async fn test_reused_id() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let policy = QubePolicy {
+            replace_cooldown: std::time::Duration::ZERO,
+            ..QubePolicy::default()
+        };
+        let emitter = emitter(&daemon, policy).await;
+        let message = |id, summary| Message {
+            id,
+            notification: self::notification(summary),
+        };
+        let first = emitter.send_message(message(7, "a")).await.unwrap();
+        // By default, a fresh notification
+        let second = emitter.send_message(message(7, "b")).await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(emitter.ids.lock().await.len(), 2);
+        let mut policy = (*emitter.policy()).clone();
+        policy.reused_id = ReusedId::Replace;
+        emitter.set_policy(policy);
+        // The latest notification with the ID is the one replaced
+        let third = emitter.send_message(message(7, "c")).await.unwrap();
+        assert_eq!(third, second);
+        assert_eq!(emitter.ids.lock().await.len(), 2);
+        assert_eq!(daemon.notifications()[2].replaces_id, 2);
+        // IDs of closed notifications are free again
+        assert!(emitter.close(first).await.unwrap());
+        assert!(emitter.close(second).await.unwrap());
+        let fourth = emitter.send_message(message(7, "d")).await.unwrap();
+        assert_ne!(fourth, second);
+        assert_eq!(daemon.notifications()[3].replaces_id, 0);
+    }
+    #[tokio::test]
+    async fn test_close_all() {
+        let daemon = mock::MockDaemon::new(&[]).await;
+        let mut emitters = vec![];
+        for qube in ["a", "b", "c"] {
+            let emitter = NotificationEmitter::new(
+                &daemon.connection,
+                qube.to_owned(),
+                QubePolicy::default(),
+            );
+            emitters.push(emitter.await.unwrap());
+        }
+        for emitter in &emitters {
+            emitter.send_notification(notification("x")).await.unwrap();
+        }
+        emitters[1]
+            .send_notification(notification("y"))
+            .await
+            .unwrap();
+        let messages = close_all(&emitters.iter().collect::<Vec<_>>()).await;
+        let closed_by_call = |message: &ReplyMessage, expected| {
+            matches!(
+                *message,
+                ReplyMessage::Dismissed { id, reason: CloseReason::Closed } if id == expected
+            )
+        };
+        let expected = [vec![1], vec![1, 2], vec![1]];
+        for (messages, ids) in messages.iter().zip(expected) {
+            assert_eq!(messages.len(), ids.len());
+            for id in ids {
+                let found = messages.iter().any(|message| closed_by_call(message, id));
+                assert!(found, "{:?}", messages);
+            }
+        }
+        let mut closed = daemon.state.lock().unwrap().closed.clone();
+        closed.sort();
+        assert_eq!(closed, [1, 2, 3, 4]);
+        for emitter in &emitters {
+            assert!(!emitter.close(1).await.unwrap());
+            assert!(emitter.close_all().await.is_empty());
+        }
+    }
+    #[tokio::test]
+    async fn test_validation_offsets() {
+        let daemon = mock::MockDaemon::new(&["actions"]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let notification = v2("a", |fields| {
+            fields.actions = vec!["op\u{e9}n".to_owned(), "Open".to_owned()];
+            fields.category = Some("im.r\u{e9}ceived".to_owned())
+        });
+        let messages: Vec<_> = match emitter.validate(notification) {
+            Err(problems) => problems.iter().map(ProxyError::to_string).collect(),
+            Ok(_) => panic!("invalid notification accepted"),
+        };
+        assert!(
+            messages.contains(
+                &"Invalid notification: Invalid action name: U+00E9 at character index 2"
+                    .to_owned()
+            ),
+            "{:?}",
+            messages
+        );
+        assert!(
+            messages.contains(
+                &"Invalid notification: Invalid category: U+00E9 at character index 4".to_owned()
+            ),
+            "{:?}",
+            messages
+        );
+    }
+    #[tokio::test]
+    async fn test_display_prefix() {
+        let daemon = mock::MockDaemon::new(&["body"]).await;
+        let config = Config::parse("[bank]\ndisplay_prefix = \u{1f3e6}\nbody_footer = on").unwrap();
+        let policy = config.policy_for("bank");
+        let bank = NotificationEmitter::new(&daemon.connection, "bank".to_owned(), policy);
+        let bank = bank.await.unwrap();
+        let notification = v2("Payment", |fields| fields.body = "Sent".to_owned());
+        bank.send_notification(notification).await.unwrap();
+        let state = daemon.state.lock().unwrap();
+        let received = &state.notifications[0];
+        assert_eq!(received.summary, "\u{1f3e6}: Payment");
+        assert_eq!(received.app_name, "Qubes VM \u{1f3e6}");
+        assert_eq!(received.body, "Sent\n\u{2014} from \u{1f3e6}");
+        // The real name still identifies the qube
+        assert_eq!(*received.hints["x-qubes-vmname"], Value::from("bank"));
+        assert!(!received.summary.contains("bank"));
+        assert!(Config::parse("display_prefix = a\u{7}").is_err());
+    }
+    #[test]
+    fn test_boolean_hints() {
+        let policy = QubePolicy::default();
+        let hint = |key: &str, value| vec![(key.to_owned(), value)];
+        let filter = |hints, policy| filter_hints(hints, Capabilities::PERSISTENCE, policy);
+        let kept = filter(hint("resident", HintValue::Byte(1)), &policy);
+        assert_eq!(kept, [("resident", Value::from(true))]);
+        let kept = filter(hint("resident", HintValue::UInt32(0)), &policy);
+        assert_eq!(kept, [("resident", Value::from(false))]);
+        assert!(filter(hint("resident", HintValue::Int32(2)), &policy).is_empty());
+        assert!(filter(hint("resident", HintValue::String("1".to_owned())), &policy).is_empty());
+        let strict = QubePolicy {
+            coerce_boolean_hints: false,
+            ..QubePolicy::default()
+        };
+        assert!(filter(hint("resident", HintValue::Byte(1)), &strict).is_empty());
+        let kept = filter(hint("resident", HintValue::Boolean(true)), &strict);
+        assert_eq!(kept, [("resident", Value::from(true))]);
+        // Integers are not booleans for any other hint
+        assert!(filter(hint("sound-name", HintValue::Byte(1)), &policy).is_empty());
+    }
+    #[tokio::test]
+    async fn test_transient_coerced() {
+        let daemon = mock::MockDaemon::new(&["persistence"]).await;
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let with_transient = |value| {
+            v2("a", |fields| {
+                fields.hints.push(("transient".to_owned(), value))
+            })
+        };
+        let prepared = emitter.preview(with_transient(HintValue::Byte(1))).unwrap();
+        assert_eq!(prepared.hints["transient"], Value::from(true));
+        let prepared = emitter
+            .preview(with_transient(HintValue::Int32(2)))
+            .unwrap();
+        assert!(!prepared.hints.contains_key("transient"));
+    }
+    #[tokio::test]
+    async fn test_json_mirror() {
+        #[derive(Clone, Default)]
+        struct Buffer(Rc<RefCell<Vec<u8>>>);
+        impl std::io::Write for Buffer {
+            fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(data);
+                Ok(data.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let daemon = mock::MockDaemon::new(&["body", "actions"]).await;
+        let buffer = Buffer::default();
+        let sink = JsonLinesSink::new("json".to_owned(), Box::new(buffer.clone()));
+        let emitter = emitter(&daemon, QubePolicy::default()).await;
+        let emitter = emitter.with_mirror(Box::new(sink));
+        let notification = v2("\"Hi\"\u{7}", |fields| {
+            fields.body = "line\nline\\\u{202e}".to_owned();
+            fields.actions = vec!["default".to_owned(), "Open".to_owned()];
+        });
+        emitter.send_notification(notification).await.unwrap();
+        {
+            let state = daemon.state.lock().unwrap();
+            assert_eq!(state.notifications[0].summary, "test: \"Hi\"\u{FFFD}");
+            assert_eq!(state.notifications[0].body, "line\nline\\\u{FFFD}");
+        }
+        let line = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(
+            line,
+            "{\"id\":1,\"replaces_id\":0,\"app_name\":\"Qubes VM test\",\"app_icon\":\"\",\
+            \"summary\":\"test: \\\"Hi\\\"\u{FFFD}\",\"body\":\"line\\nline\\\\\u{FFFD}\",\
+            \"actions\":[\"default\",\"Open\"],\"hints\":{\"x-qubes-vmname\":\"test\"},\
+            \"expire_timeout\":-1}\n"
+        );
+        // A mirror failing does not affect delivery
+        struct Broken;
+        impl std::io::Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let broken = JsonLinesSink::new("broken".to_owned(), Box::new(Broken));
+        let emitter = emitter.with_mirror(Box::new(broken));
+        assert_eq!(
+            emitter
+                .send_notification(self::notification("a"))
+                .await
+                .unwrap(),
+            2
+        );
+    }
+    pub(crate) async fn emitter(
+        daemon: &mock::MockDaemon,
+        policy: QubePolicy,
+    ) -> NotificationEmitter {
+        NotificationEmitter::new(&daemon.connection, "test".to_owned(), policy)
+            .await
+            .unwrap()
+    }
+    /// The fields of a [`Notification::V2`], for tests to fill in
+    pub(crate) struct V2Fields {
+        pub suppress_sound: bool,
+        pub transient: bool,
+        pub urgency: Option<Urgency>,
+        pub replaces_id: u32,
+        pub summary: String,
+        pub body: String,
+        pub actions: Vec<String>,
+        pub category: Option<String>,
+        pub expire_timeout: i32,
+        pub image: Option<ImageParameters>,
+        pub hints: Vec<(String, HintValue)>,
+    }
+    /// A notification like [`notification`], with the changes `edit` makes
+    pub(crate) fn v2(summary: &str, edit: impl FnOnce(&mut V2Fields)) -> Notification {
+        let mut fields = V2Fields {
+            suppress_sound: false,
+            transient: false,
+            urgency: None,
+            replaces_id: 0,
+            summary: summary.to_owned(),
+            body: "".to_owned(),
+            actions: vec![],
+            category: None,
+            expire_timeout: -1,
+            image: None,
+            hints: vec![],
+        };
+        edit(&mut fields);
+        let V2Fields {
+            suppress_sound,
+            transient,
+            urgency,
+            replaces_id,
+            summary,
+            body,
+            actions,
+            category,
+            expire_timeout,
+            image,
+            hints,
+        } = fields;
+        Notification::V2 {
+            suppress_sound,
+            transient,
+            urgency,
+            replaces_id,
+            summary,
+            body,
+            actions,
+            category,
+            expire_timeout,
+            image,
+            hints,
+        }
+    }
+    pub(crate) fn notification(summary: &str) -> Notification {
+        Notification::V1 {
+            suppress_sound: false,
+            transient: false,
+            urgency: None,
+            replaces_id: 0,
+            summary: summary.to_owned(),
+            body: "".to_owned(),
+            actions: vec![],
+            category: None,
+            expire_timeout: -1,
+            image: None,
+        }
+    }
+    #[tokio::test]
+    async fn test_independent_instances() {
+        // Two targets that happen to share a daemon, each with a proxy for
+        // the same qube
+        let daemon = mock::MockDaemon::new(&["body", "x-dunst-stack-tag"]).await;
+        let policy = QubePolicy {
+            rate_limit: 1,
+            group_notifications: true,
+            ..QubePolicy::default()
+        };
+        let instance = |namespace: &str| {
+            let emitter =
+                NotificationEmitter::new(&daemon.connection, "work".to_owned(), policy.clone());
+            let namespace = namespace.to_owned();
+            async { emitter.await.unwrap().with_namespace(namespace) }
+        };
+        let gui1 = instance("gui1").await;
+        let gui2 = instance("gui2").await;
+        assert_eq!(gui1.send_notification(notification("1")).await.unwrap(), 1);
+        assert!(matches!(
+            gui1.send_notification(notification("2")).await,
+            Err(ProxyError::PolicyRejected(PolicyRejection::RateLimited))
+        ));
+        // The rate limit and the IDs of one instance do not affect the other
+        assert_eq!(gui2.send_notification(notification("3")).await.unwrap(), 1);
+        let received = daemon.notifications();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].summary, "work: 1");
+        assert_eq!(received[1].summary, "work: 3");
+        // Nor do they stack each other's notifications
+        let tag =
+            |index: usize| String::try_from(received[index].hints["x-dunst-stack-tag"].clone());
+        assert_eq!(tag(0).unwrap(), "gui1/qubes-work");
+        assert_eq!(tag(1).unwrap(), "gui2/qubes-work");
+        // Each only handles the signals for its own notifications
+        assert_eq!(gui1.notification_closed(2).await, None);
+        assert_eq!(gui2.notification_closed(2).await, Some(1));
+        assert_eq!(gui1.notification_closed(1).await, Some(1));
+    }
+    #[tokio::test]
+    async fn test_retry_transient_failure() {
+        let policy = QubePolicy {
+            retries: 2,
+            retry_delay: std::time::Duration::from_millis(1),
+            ..QubePolicy::default()
+        };
+        let daemon = mock::MockDaemon::new(&["body"]).await;
+        let emitter = emitter(&daemon, policy).await;
+        daemon.state.lock().unwrap().failures = 2;
+        assert_eq!(
+            emitter.send_notification(notification("a")).await.unwrap(),
+            1
+        );
+        assert_eq!(daemon.notifications().len(), 1);
+        daemon.state.lock().unwrap().failures = 3;
+        assert!(matches!(
+            emitter.send_notification(notification("b")).await,
+            Err(ProxyError::DaemonUnavailable)
+        ));
+        assert_eq!(daemon.notifications().len(), 1);
+    }
 }
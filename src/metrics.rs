@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Counters of what happened to the notifications of a qube
+#[derive(Debug, Default, Clone)]
+pub struct Metrics {
+    /// Notifications accepted for display
+    pub sent: u64,
+    /// Notifications dropped by the rate limit
+    pub rate_limited: u64,
+    /// Notifications rejected as malformed, including rejected images
+    pub validation_failed: u64,
+    /// Images that were rejected, by reason
+    pub images_rejected: HashMap<&'static str, u64>,
+    /// Invoked actions dropped because the qube did not read them quickly enough
+    pub actions_dropped: u64,
+    /// Inline replies dropped because the qube did not read them quickly enough
+    pub replies_dropped: u64,
+    /// Signals from the daemon not delivered to the qube, by reason:
+    /// `unknown-id` for notifications that are not open or not of this qube,
+    /// `unexpected-reply` for replies to notifications shown without an
+    /// inline reply action, `debounced` for repeats of an earlier signal, and
+    /// `rate-limited` for signals over the signal rate limit
+    pub signals_dropped: HashMap<&'static str, u64>,
+    /// Notifications dropped because of their category
+    pub categories_blocked: u64,
+    /// Notifications shed because the send queue of the qube was full
+    pub queue_shed: u64,
+    /// Notifications shown, by the name of the sink that showed them
+    pub delivered: HashMap<String, u64>,
+}
+
+/// Summary of the notifications of a qube since its statistics were last
+/// reset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QubeStats {
+    /// When the statistics were last reset, or the emitter was created
+    pub since: Instant,
+    pub sent: u64,
+    pub rate_limited: u64,
+    /// Notifications dropped by policy, such as because of their category
+    pub blocked: u64,
+    pub validation_failed: u64,
+    pub images_rejected: u64,
+    /// Sequence number of the last notification forwarded for the qube, even
+    /// before the statistics were reset
+    pub last_seq: Option<u64>,
+}
+
+impl QubeStats {
+    pub(crate) fn new(metrics: &Metrics, since: Instant, last_seq: Option<u64>) -> Self {
+        Self {
+            since,
+            sent: metrics.sent,
+            rate_limited: metrics.rate_limited,
+            blocked: metrics.categories_blocked,
+            validation_failed: metrics.validation_failed,
+            images_rejected: metrics.images_rejected.values().sum(),
+            last_seq,
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! In-process notification daemon for tests, connected over a peer-to-peer
+//! D-Bus connection.
+#![allow(dead_code)]
+
+use crate::{ActionInvokedStream, ActionStream, Message, NotificationEmitter, ReplyMessage};
+use crate::{NotificationClosedStream, NotificationRepliedStream};
+use futures_util::StreamExt as _;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use zbus::zvariant::OwnedValue;
+use zbus::{dbus_interface, Connection, ConnectionBuilder, Guid, SignalContext};
+
+/// A notification received by the mock daemon
+#[derive(Debug, Clone)]
+pub struct ReceivedNotification {
+    pub app_name: String,
+    pub replaces_id: u32,
+    pub app_icon: String,
+    pub summary: String,
+    pub body: String,
+    pub actions: Vec<String>,
+    pub hints: HashMap<String, OwnedValue>,
+    pub expire_timeout: i32,
+}
+
+#[derive(Debug, Default)]
+pub struct MockState {
+    /// Capabilities reported by `GetCapabilities`
+    pub capabilities: Vec<String>,
+    /// Every notification received, in order
+    pub notifications: Vec<ReceivedNotification>,
+    /// IDs passed to `CloseNotification`, in order
+    pub closed: Vec<u32>,
+    /// Number of upcoming `Notify` calls that fail as if the daemon was not running
+    pub failures: u32,
+    /// Likewise for `GetCapabilities`
+    pub capabilities_failures: u32,
+    /// Number of upcoming `Notify` calls that return the invalid ID 0
+    pub zero_ids: u32,
+    /// The last ID handed out
+    pub last_id: u32,
+}
+
+pub struct MockNotificationServer(Arc<Mutex<MockState>>);
+
+#[dbus_interface(name = "org.freedesktop.Notifications")]
+impl MockNotificationServer {
+    fn get_capabilities(&self) -> zbus::fdo::Result<Vec<String>> {
+        let mut state = self.0.lock().unwrap();
+        if state.capabilities_failures > 0 {
+            state.capabilities_failures -= 1;
+            return Err(zbus::fdo::Error::ServiceUnknown("starting".to_owned()));
+        }
+        Ok(state.capabilities.clone())
+    }
+    // The arguments are those of the D-Bus method
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: String,
+        replaces_id: u32,
+        app_icon: String,
+        summary: String,
+        body: String,
+        actions: Vec<String>,
+        hints: HashMap<String, OwnedValue>,
+        expire_timeout: i32,
+    ) -> zbus::fdo::Result<u32> {
+        let mut state = self.0.lock().unwrap();
+        if state.failures > 0 {
+            state.failures -= 1;
+            return Err(zbus::fdo::Error::ServiceUnknown("restarting".to_owned()));
+        }
+        state.notifications.push(ReceivedNotification {
+            app_name,
+            replaces_id,
+            app_icon,
+            summary,
+            body,
+            actions,
+            hints,
+            expire_timeout,
+        });
+        if state.zero_ids > 0 {
+            state.zero_ids -= 1;
+            return Ok(0);
+        }
+        if replaces_id != 0 {
+            return Ok(replaces_id);
+        }
+        state.last_id += 1;
+        Ok(state.last_id)
+    }
+    fn close_notification(&self, id: u32) {
+        self.0.lock().unwrap().closed.push(id)
+    }
+    fn get_server_information(&self) -> (String, String, String, String) {
+        (
+            "Mock".to_owned(),
+            "Qubes OS".to_owned(),
+            "0.0.1".to_owned(),
+            "1.2".to_owned(),
+        )
+    }
+    #[dbus_interface(signal)]
+    async fn action_invoked(
+        ctxt: &SignalContext<'_>,
+        id: u32,
+        action_key: &str,
+    ) -> zbus::Result<()>;
+    #[dbus_interface(signal)]
+    async fn notification_closed(
+        ctxt: &SignalContext<'_>,
+        id: u32,
+        reason: u32,
+    ) -> zbus::Result<()>;
+    #[dbus_interface(signal)]
+    async fn notification_replied(
+        ctxt: &SignalContext<'_>,
+        id: u32,
+        text: &str,
+    ) -> zbus::Result<()>;
+}
+
+/// A daemon whose replies do not quite follow the specification
+pub struct OffSpecNotificationServer;
+
+#[dbus_interface(name = "org.freedesktop.Notifications")]
+impl OffSpecNotificationServer {
+    fn get_capabilities(&self) -> (Vec<String>, u32) {
+        (vec!["body".to_owned()], 1)
+    }
+    fn get_server_information(&self) -> (String, String, String, String, String) {
+        let s = |s: &str| s.to_owned();
+        (s("Quirky"), s("Qubes OS"), s("0.0.1"), s("1.2"), s("extra"))
+    }
+}
+
+const PATH: &str = "/org/freedesktop/Notifications";
+
+/// A mock daemon and a connection to it
+pub struct MockDaemon {
+    /// Connection to the daemon, for use by the code under test
+    pub connection: Connection,
+    /// Keeps the daemon side of the connection alive
+    pub server: Connection,
+    pub state: Arc<Mutex<MockState>>,
+}
+
+impl MockDaemon {
+    pub async fn new(capabilities: &[&str]) -> Self {
+        let state = Arc::new(Mutex::new(MockState {
+            capabilities: capabilities.iter().map(|&s| s.to_owned()).collect(),
+            ..MockState::default()
+        }));
+        Self::serve(state.clone(), MockNotificationServer(state)).await
+    }
+
+    /// A daemon with off-spec replies to its informational methods
+    pub async fn off_spec() -> Self {
+        Self::serve(Arc::default(), OffSpecNotificationServer).await
+    }
+
+    async fn serve(state: Arc<Mutex<MockState>>, interface: impl zbus::Interface) -> Self {
+        let (client, server) = tokio::net::UnixStream::pair().unwrap();
+        let guid = Guid::generate();
+        let server = ConnectionBuilder::unix_stream(server)
+            .server(&guid)
+            .p2p()
+            .serve_at(PATH, interface)
+            .unwrap()
+            .build();
+        let client = ConnectionBuilder::unix_stream(client).p2p().build();
+        let (server, connection) = tokio::try_join!(server, client).unwrap();
+        Self {
+            connection,
+            server,
+            state,
+        }
+    }
+
+    /// Emit `ActionInvoked` for daemon ID `id`
+    pub async fn invoke_action(&self, id: u32, action_key: &str) {
+        let ctxt = SignalContext::new(&self.server, PATH).unwrap();
+        MockNotificationServer::action_invoked(&ctxt, id, action_key)
+            .await
+            .unwrap()
+    }
+
+    /// Emit `NotificationClosed` for daemon ID `id`
+    pub async fn close(&self, id: u32, reason: u32) {
+        let ctxt = SignalContext::new(&self.server, PATH).unwrap();
+        MockNotificationServer::notification_closed(&ctxt, id, reason)
+            .await
+            .unwrap()
+    }
+
+    /// Emit `NotificationReplied` for daemon ID `id`
+    pub async fn reply(&self, id: u32, text: &str) {
+        let ctxt = SignalContext::new(&self.server, PATH).unwrap();
+        MockNotificationServer::notification_replied(&ctxt, id, text)
+            .await
+            .unwrap()
+    }
+
+    /// Notifications received so far
+    pub fn notifications(&self) -> Vec<ReceivedNotification> {
+        self.state.lock().unwrap().notifications.clone()
+    }
+}
+
+/// The messages a qube gets about its notifications, with the signals of the
+/// daemon routed to its emitter the way the server does it
+pub struct ReverseChannel {
+    closed: NotificationClosedStream<'static>,
+    invoked: ActionInvokedStream<'static>,
+    replied: NotificationRepliedStream<'static>,
+    actions: ActionStream,
+    replies: ActionStream,
+    /// Replies to the calls of the qube, not yet received
+    pending: Vec<ReplyMessage>,
+}
+
+impl ReverseChannel {
+    /// Subscribe to the signals for `emitter`.  Must be created before the
+    /// signals are emitted.
+    pub async fn new(emitter: &NotificationEmitter) -> Self {
+        Self {
+            closed: emitter.closed().await.unwrap(),
+            invoked: emitter.invocations().await.unwrap(),
+            replied: emitter.replies().await.unwrap(),
+            actions: emitter.invoked_actions(),
+            replies: emitter.inline_replies(),
+            pending: vec![],
+        }
+    }
+
+    /// Send `message` from a qube speaking protocol minor version
+    /// `minor_version`, the way the server does it.  The replies are returned
+    /// by the next call to [`Self::receive`].
+    pub async fn send(
+        &mut self,
+        emitter: &NotificationEmitter,
+        minor_version: u16,
+        message: Message,
+    ) {
+        let replies = emitter.reply_to(minor_version, message).await;
+        self.pending.extend(replies)
+    }
+
+    /// Route signals until none arrives for a while, returning the messages
+    /// sent to the qube
+    pub async fn receive(&mut self, emitter: &NotificationEmitter) -> Vec<ReplyMessage> {
+        let mut messages = std::mem::take(&mut self.pending);
+        loop {
+            // The streams lose the order of the signals.  Handling actions
+            // first keeps an action from being dropped because the
+            // notification was closed right after it.
+            tokio::select! {
+                biased;
+                Some(signal) = self.invoked.next() => {
+                    let args = signal.args().unwrap();
+                    emitter.action_invoked(args.id, args.action_key).await
+                }
+                Some((id, action)) = self.actions.next() => {
+                    messages.push(ReplyMessage::ActionInvoked { id, action })
+                }
+                Some(signal) = self.replied.next() => {
+                    let args = signal.args().unwrap();
+                    emitter.notification_replied(args.id, args.text).await
+                }
+                Some((id, text)) = self.replies.next() => {
+                    messages.push(ReplyMessage::Replied { id, text })
+                }
+                Some(signal) = self.closed.next() => {
+                    let args = signal.args().unwrap();
+                    if let Some(id) = emitter.notification_closed(args.id).await {
+                        messages.push(ReplyMessage::Dismissed { id, reason: args.reason.into() })
+                    }
+                }
+                () = tokio::time::sleep(Duration::from_millis(50)) => return messages,
+            }
+        }
+    }
+}
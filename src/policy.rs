@@ -0,0 +1,974 @@
+use crate::{ImageLimits, ProxyError, ReplyMessage, TrustedStr, Urgency};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Default location of the configuration file
+pub const CONFIG_PATH: &str = "/etc/qubes/notification-proxy.conf";
+
+/// Settings that apply to the notifications of a single qube
+#[derive(Debug, Clone)]
+pub struct QubePolicy {
+    /// Maximum number of notifications per rate limit window.  0, the default,
+    /// means unlimited.
+    pub rate_limit: u32,
+    /// Length of a rate limit window.
+    pub rate_limit_window: Duration,
+    /// Maximum number of replacements of open notifications per rate limit
+    /// window, such as progress updates, which are much less disruptive than
+    /// new notifications.  0 means unlimited, and `None` means replacements
+    /// count against `rate_limit`.
+    pub replace_rate_limit: Option<u32>,
+    /// Whether to tell the qube when one of its notifications is suppressed.
+    pub report_suppressed: bool,
+    /// How many times to retry if the daemon is unavailable.
+    pub retries: u32,
+    /// Delay before the first retry.  Doubled after each retry.
+    pub retry_delay: Duration,
+    /// Sound names the qube may use.  `None` means any valid name.
+    pub allowed_sound_names: Option<Vec<String>>,
+    /// Whether the qube may send sound files, which the proxy writes to a
+    /// file of its own for the daemon to play
+    pub sound_files: bool,
+    /// Largest sound file the qube may send, in bytes
+    pub max_sound_file_size: usize,
+    /// Size in bytes above which the image and then the body are dropped from
+    /// a notification.  Defaults to the maximum size of a D-Bus message.
+    pub max_message_size: usize,
+    /// Number of invoked actions buffered for the qube before the oldest ones
+    /// are dropped.
+    pub action_queue_size: usize,
+    /// Longest timeout in milliseconds of a transient notification.  Transient
+    /// notifications that would never expire get this timeout instead.
+    pub max_transient_timeout: i32,
+    /// Whether to accept 0 and 1 for the hints that the specification says
+    /// are booleans, as some toolkits send them
+    pub coerce_boolean_hints: bool,
+    /// Categories the qube may use.  `None` means any category that is not
+    /// denied.  See [`Self::category_allowed`] for how categories match.
+    pub allowed_categories: Option<Vec<String>>,
+    /// Categories the qube may not use.  Takes precedence over
+    /// `allowed_categories`.
+    pub denied_categories: Vec<String>,
+    /// Whether the qube may send notifications without a category.
+    pub allow_uncategorized: bool,
+    /// Minimum interval between updates of the same notification.
+    pub replace_cooldown: Duration,
+    /// Urgency of notifications that do not specify one.  `None` leaves it to
+    /// the daemon.
+    pub default_urgency: Option<Urgency>,
+    /// Lowest urgency the qube may use
+    pub min_urgency: Urgency,
+    /// Highest urgency the qube may use.  Takes precedence over `min_urgency`.
+    pub max_urgency: Urgency,
+    /// Whether to forward notifications whose summary looks empty
+    pub allow_empty_summary: bool,
+    /// What to show as the summary of a notification that has a body but no
+    /// summary
+    pub missing_summary: MissingSummary,
+    /// Whether to close the notifications of the qube when it disconnects
+    pub close_on_shutdown: bool,
+    /// How long to wait before closing the notifications of a qube that
+    /// disconnected, in case it comes back
+    pub shutdown_grace: Duration,
+    /// Whether to keep critical notifications open when the qube disconnects
+    pub keep_critical_on_shutdown: bool,
+    /// Maximum length of an action key, in bytes.  Keys are never longer
+    /// than 255 bytes.
+    pub max_action_key_len: usize,
+    /// Maximum length of an action label, in characters
+    pub max_action_label_len: usize,
+    /// Action keys the qube may use.  `None` means any valid key, and an
+    /// empty list means no actions at all.  Other actions are dropped.
+    pub allowed_action_keys: Option<Vec<String>>,
+    /// Whether to reject notifications with a too long action key or label,
+    /// instead of dropping that action
+    pub reject_long_actions: bool,
+    /// Whether to append the percentage of the `value` hint to the summary
+    /// if the daemon does not advertise the non-standard `progress`
+    /// capability
+    pub render_progress_in_summary: bool,
+    /// Whether bodies may ever contain markup.  If not, markup is always
+    /// escaped and the markup capabilities are not advertised to the qube.
+    pub markup: bool,
+    /// Whether notifications may have a body at all.  If not, only the
+    /// summary is shown, whatever the daemon supports.
+    pub allow_body: bool,
+    /// How long a notification may stay open without being updated before
+    /// the proxy closes it.  `None` means forever.
+    pub max_lifetime: Option<Duration>,
+    /// Whether the maximum lifetime also applies to critical notifications
+    pub expire_critical: bool,
+    /// Whether to group the notifications of the qube, on daemons that
+    /// support it
+    pub group_notifications: bool,
+    /// Whether to group the notifications of the qube by category too
+    pub group_by_category: bool,
+    /// Number of forwarded notifications to remember for debugging
+    pub history_size: usize,
+    /// Whether the remembered notifications include their body
+    pub history_bodies: bool,
+    /// Whether all notifications of the qube are transient, whatever the qube
+    /// asks for
+    pub force_transient: bool,
+    /// Time of day during which the non-critical notifications of the qube
+    /// are held until it is over
+    pub quiet_hours: Option<QuietHours>,
+    /// Whether to drop the non-critical notifications sent during quiet
+    /// hours instead of holding them
+    pub drop_during_quiet_hours: bool,
+    /// Number of notifications held during quiet hours, beyond which new
+    /// ones are rejected.  At least 1.
+    pub quiet_hours_queue_size: usize,
+    /// What to do with a new notification whose ID, as chosen by the qube,
+    /// is the same as that of one that is still open
+    pub reused_id: ReusedId,
+    /// Label shown instead of the name of the qube in front of its
+    /// notifications, such as an emoji.  The name still identifies the qube
+    /// everywhere else, such as in the policy and the notification IDs.
+    pub display_prefix: Option<TrustedStr>,
+    /// Icon of the notifications of the qube.  Empty means no icon.
+    pub app_icon: String,
+    /// Icons to use instead of `app_icon` for some categories, which match
+    /// like in [`Self::category_allowed`]
+    pub category_icons: Vec<(String, String)>,
+    /// Whether to drop the body of notifications whose body is the same as
+    /// their summary
+    pub drop_duplicate_body: bool,
+    /// Whether to append the body to the summary for daemons that do not
+    /// show bodies, instead of dropping it
+    pub fold_body: bool,
+    /// Maximum length in characters of a summary that a body was folded
+    /// into, not counting the name of the qube
+    pub max_folded_summary_len: usize,
+    /// Limits on the images the qube may send
+    pub image_limits: ImageLimits,
+    /// Whether to append the name of the qube to the body, for daemons that
+    /// truncate the summary
+    pub body_footer: bool,
+    /// Period within which identical signals from the daemon are only
+    /// delivered to the qube once
+    pub signal_debounce: Duration,
+    /// Maximum number of signals delivered to the qube per signal rate limit
+    /// window.  0 means unlimited.
+    pub signal_rate_limit: u32,
+    /// Length of a signal rate limit window.
+    pub signal_rate_limit_window: Duration,
+}
+
+impl Default for QubePolicy {
+    fn default() -> Self {
+        Self {
+            rate_limit: 0,
+            rate_limit_window: Duration::from_secs(10),
+            replace_rate_limit: None,
+            report_suppressed: false,
+            retries: 3,
+            retry_delay: Duration::from_millis(100),
+            allowed_sound_names: None,
+            sound_files: false,
+            max_sound_file_size: crate::MAX_SOUND_FILE_SIZE,
+            max_message_size: 1 << 27,
+            action_queue_size: 64,
+            max_transient_timeout: 10_000,
+            coerce_boolean_hints: true,
+            allowed_categories: None,
+            denied_categories: vec![],
+            allow_uncategorized: true,
+            replace_cooldown: Duration::from_millis(200),
+            default_urgency: None,
+            min_urgency: Urgency::Low,
+            max_urgency: Urgency::Critical,
+            allow_empty_summary: false,
+            missing_summary: MissingSummary::Keep,
+            close_on_shutdown: false,
+            shutdown_grace: Duration::from_secs(10),
+            keep_critical_on_shutdown: false,
+            max_action_key_len: 255,
+            max_action_label_len: 255,
+            allowed_action_keys: None,
+            reject_long_actions: false,
+            render_progress_in_summary: false,
+            markup: true,
+            allow_body: true,
+            max_lifetime: None,
+            expire_critical: false,
+            group_notifications: false,
+            group_by_category: false,
+            history_size: 16,
+            history_bodies: false,
+            force_transient: false,
+            quiet_hours: None,
+            drop_during_quiet_hours: false,
+            quiet_hours_queue_size: 32,
+            reused_id: ReusedId::New,
+            display_prefix: None,
+            app_icon: String::new(),
+            category_icons: vec![],
+            drop_duplicate_body: false,
+            fold_body: false,
+            max_folded_summary_len: 200,
+            image_limits: ImageLimits::default(),
+            body_footer: false,
+            signal_debounce: Duration::from_millis(100),
+            signal_rate_limit: 50,
+            signal_rate_limit_window: Duration::from_secs(1),
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(format!("Invalid boolean {:?}", value)),
+    }
+}
+
+pub(crate) fn parse_urgency(value: &str) -> Result<Urgency, String> {
+    match value {
+        "low" => Ok(Urgency::Low),
+        "normal" => Ok(Urgency::Normal),
+        "critical" => Ok(Urgency::Critical),
+        _ => Err(format!("Invalid urgency {:?}", value)),
+    }
+}
+
+fn parse_u32(value: &str) -> Result<u32, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid integer {:?}", value))
+}
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Daily period of time, such as the night
+///
+/// Times are in UTC, as the proxy does not know the time zone of the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    /// Start of the period, as time since midnight
+    pub start: Duration,
+    /// End of the period, as time since midnight.  Periods that span midnight
+    /// end before they start.
+    pub end: Duration,
+}
+
+impl QuietHours {
+    /// Parse a period such as `22:00-07:00`
+    fn parse(value: &str) -> Result<Self, String> {
+        let time = |time: &str| -> Result<Duration, String> {
+            let invalid = || format!("Invalid time {:?}, expected HH:MM", time);
+            let (hours, minutes) = time.trim().split_once(':').ok_or_else(invalid)?;
+            let hours: u64 = hours.parse().map_err(|_| invalid())?;
+            let minutes: u64 = minutes.parse().map_err(|_| invalid())?;
+            if hours >= 24 || minutes >= 60 {
+                return Err(invalid());
+            }
+            Ok(Duration::from_secs((hours * 60 + minutes) * 60))
+        };
+        let (start, end) = value
+            .split_once('-')
+            .ok_or_else(|| format!("Expected start-end, got {:?}", value))?;
+        let quiet_hours = Self {
+            start: time(start)?,
+            end: time(end)?,
+        };
+        if quiet_hours.start == quiet_hours.end {
+            return Err("Quiet hours must not be empty".to_owned());
+        }
+        Ok(quiet_hours)
+    }
+
+    /// If `time_of_day` is within the period, how long until it is over
+    pub fn remaining(&self, time_of_day: Duration) -> Option<Duration> {
+        // Everything is shifted by a day so that nothing is negative
+        let since_start = (time_of_day + DAY - self.start).as_millis() % DAY.as_millis();
+        let length = (self.end + DAY - self.start).as_millis() % DAY.as_millis();
+        if since_start >= length {
+            return None;
+        }
+        Some(Duration::from_millis((length - since_start) as u64))
+    }
+}
+
+/// How to treat a new notification that reuses the ID, chosen by the qube,
+/// of a notification that is still open
+///
+/// This is the sequence number of the message, not the local ID that the
+/// proxy hands out, which is never reused while the notification is open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReusedId {
+    /// Show a new notification, and leave the old one open
+    New,
+    /// Replace the old notification
+    Replace,
+}
+
+/// The summary of a notification that only has a body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingSummary {
+    /// Leave the summary empty, which `allow_empty_summary` decides about
+    Keep,
+    /// Use the name of the qube
+    Qube,
+    /// Use the first line of the body that is not blank, shortened
+    FirstLine,
+}
+
+/// Parse an image dimension in pixels
+fn parse_dimension(value: &str) -> Result<i32, String> {
+    match parse_u32(value)? {
+        0 => Err("Image dimensions must not be 0".to_owned()),
+        pixels => pixels
+            .try_into()
+            .map_err(|_| format!("Image dimension {} too large", pixels)),
+    }
+}
+
+/// Check that `icon` is an icon name or the absolute path of an image
+fn validate_icon(icon: &str) -> Result<(), String> {
+    let valid = if icon.starts_with('/') {
+        icon.chars().all(crate::is_safe_for_display)
+    } else {
+        !icon.is_empty()
+            && icon
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || b"-_.+".contains(&c))
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid icon {:?}", icon))
+    }
+}
+
+/// Whether the category list entry `entry` matches `category`.  An entry
+/// matches the category itself and, if it has no `.`, the whole class.
+fn category_matches(entry: &str, category: &str) -> bool {
+    category == entry
+        || (!entry.contains('.')
+            && category
+                .strip_prefix(entry)
+                .is_some_and(|rest| rest.starts_with('.')))
+}
+
+/// Parse a comma-separated list
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+impl QubePolicy {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "rate_limit" => self.rate_limit = parse_u32(value)?,
+            "replace_rate_limit" if value.is_empty() => self.replace_rate_limit = None,
+            "replace_rate_limit" => self.replace_rate_limit = Some(parse_u32(value)?),
+            "rate_limit_window_ms" => {
+                self.rate_limit_window = Duration::from_millis(parse_u32(value)?.into())
+            }
+            "report_suppressed" => self.report_suppressed = parse_bool(value)?,
+            "retries" => self.retries = parse_u32(value)?,
+            "retry_delay_ms" => self.retry_delay = Duration::from_millis(parse_u32(value)?.into()),
+            "sound_files" => self.sound_files = parse_bool(value)?,
+            "max_sound_file_size" => {
+                self.max_sound_file_size = match parse_u32(value)? as usize {
+                    size if size > crate::MAX_SOUND_FILE_SIZE => {
+                        return Err(format!(
+                            "max_sound_file_size cannot be more than {}",
+                            crate::MAX_SOUND_FILE_SIZE
+                        ))
+                    }
+                    size => size,
+                }
+            }
+            "allowed_sound_names" => self.allowed_sound_names = Some(parse_list(value)),
+            "max_message_size" => self.max_message_size = parse_u32(value)? as usize,
+            "action_queue_size" => self.action_queue_size = parse_u32(value)? as usize,
+            "coerce_boolean_hints" => self.coerce_boolean_hints = parse_bool(value)?,
+            "max_transient_timeout_ms" => {
+                self.max_transient_timeout = match parse_u32(value)? {
+                    0 => return Err("Transient timeout must not be 0".to_owned()),
+                    ms => ms
+                        .try_into()
+                        .map_err(|_| format!("Timeout {} too long", ms))?,
+                }
+            }
+            "allowed_categories" => self.allowed_categories = Some(parse_list(value)),
+            "denied_categories" => self.denied_categories = parse_list(value),
+            "allow_uncategorized" => self.allow_uncategorized = parse_bool(value)?,
+            "default_urgency" => self.default_urgency = Some(parse_urgency(value)?),
+            "min_urgency" => self.min_urgency = parse_urgency(value)?,
+            "max_urgency" => self.max_urgency = parse_urgency(value)?,
+            "allow_empty_summary" => self.allow_empty_summary = parse_bool(value)?,
+            "missing_summary" => {
+                self.missing_summary = match value {
+                    "keep" => MissingSummary::Keep,
+                    "qube" => MissingSummary::Qube,
+                    "first-line" => MissingSummary::FirstLine,
+                    _ => {
+                        return Err(format!(
+                            "Expected keep, qube or first-line, got {:?}",
+                            value
+                        ))
+                    }
+                }
+            }
+            "close_on_shutdown" => self.close_on_shutdown = parse_bool(value)?,
+            "shutdown_grace_ms" => {
+                self.shutdown_grace = Duration::from_millis(parse_u32(value)?.into())
+            }
+            "keep_critical_on_shutdown" => self.keep_critical_on_shutdown = parse_bool(value)?,
+            "max_action_key_len" => self.max_action_key_len = parse_u32(value)? as usize,
+            "max_action_label_len" => self.max_action_label_len = parse_u32(value)? as usize,
+            "reject_long_actions" => self.reject_long_actions = parse_bool(value)?,
+            "allowed_action_keys" => self.allowed_action_keys = Some(parse_list(value)),
+            "render_progress_in_summary" => self.render_progress_in_summary = parse_bool(value)?,
+            "markup" => self.markup = parse_bool(value)?,
+            "allow_body" => self.allow_body = parse_bool(value)?,
+            "body_footer" => self.body_footer = parse_bool(value)?,
+            "drop_duplicate_body" => self.drop_duplicate_body = parse_bool(value)?,
+            "force_transient" => self.force_transient = parse_bool(value)?,
+            "fold_body" => self.fold_body = parse_bool(value)?,
+            "max_folded_summary_len" => self.max_folded_summary_len = parse_u32(value)? as usize,
+            "quiet_hours" if value.is_empty() => self.quiet_hours = None,
+            "quiet_hours" => self.quiet_hours = Some(QuietHours::parse(value)?),
+            "drop_during_quiet_hours" => self.drop_during_quiet_hours = parse_bool(value)?,
+            "quiet_hours_queue_size" => match parse_u32(value)? {
+                0 => return Err("quiet_hours_queue_size must be at least 1".to_owned()),
+                size => self.quiet_hours_queue_size = size as usize,
+            },
+            "reused_id" => {
+                self.reused_id = match value {
+                    "new" => ReusedId::New,
+                    "replace" => ReusedId::Replace,
+                    _ => return Err(format!("Expected new or replace, got {:?}", value)),
+                }
+            }
+            "history_size" => self.history_size = parse_u32(value)? as usize,
+            "group_notifications" => self.group_notifications = parse_bool(value)?,
+            "group_by_category" => self.group_by_category = parse_bool(value)?,
+            "history_bodies" => self.history_bodies = parse_bool(value)?,
+            "display_prefix" => {
+                if value.is_empty() {
+                    return Err("Empty display prefix".to_owned());
+                }
+                let safe = |_, c| crate::is_safe_for_display(c);
+                if let Some(invalid) = crate::trusted::find_invalid_char(value, safe) {
+                    return Err(format!(
+                        "Invalid display prefix: {} is not allowed",
+                        invalid
+                    ));
+                }
+                self.display_prefix = Some(TrustedStr::from_validated(value.to_owned()))
+            }
+            "app_icon" => {
+                validate_icon(value)?;
+                self.app_icon = value.to_owned()
+            }
+            "category_icons" => {
+                self.category_icons = parse_list(value)
+                    .iter()
+                    .map(|entry| match entry.split_once(':') {
+                        Some((category, icon)) => {
+                            let icon = icon.trim();
+                            validate_icon(icon)?;
+                            Ok((category.trim().to_owned(), icon.to_owned()))
+                        }
+                        None => Err(format!("Expected category:icon, got {:?}", entry)),
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "max_lifetime_ms" => {
+                self.max_lifetime = match parse_u32(value)? {
+                    0 => None,
+                    ms => Some(Duration::from_millis(ms.into())),
+                }
+            }
+            "expire_critical" => self.expire_critical = parse_bool(value)?,
+            "max_image_size" => {
+                // The D-Bus image format cannot describe more data than this
+                self.image_limits.max_size = match parse_u32(value)? {
+                    size if size > i32::MAX as u32 => {
+                        return Err(format!("Image size {} too large", size))
+                    }
+                    size => size as usize,
+                }
+            }
+            "max_image_width" => self.image_limits.max_width = parse_dimension(value)?,
+            "max_image_height" => self.image_limits.max_height = parse_dimension(value)?,
+            "replace_cooldown_ms" => {
+                self.replace_cooldown = Duration::from_millis(parse_u32(value)?.into())
+            }
+            "signal_debounce_ms" => {
+                self.signal_debounce = Duration::from_millis(parse_u32(value)?.into())
+            }
+            "signal_rate_limit" => self.signal_rate_limit = parse_u32(value)?,
+            "signal_rate_limit_window_ms" => {
+                self.signal_rate_limit_window = Duration::from_millis(parse_u32(value)?.into())
+            }
+            _ => return Err(format!("Unknown setting {:?}", key)),
+        }
+        Ok(())
+    }
+
+    /// The urgency to send for a notification with urgency `urgency`: the
+    /// default urgency if there is none, clamped to the allowed range.
+    pub fn urgency(&self, urgency: Option<Urgency>) -> Option<Urgency> {
+        urgency
+            .or(self.default_urgency)
+            .map(|urgency| urgency.max(self.min_urgency).min(self.max_urgency))
+    }
+
+    /// Whether the qube may send a notification in the (validated) category
+    /// `category`.  A list entry matches the category itself and, if it has no
+    /// `.`, the whole class: `im` matches `im` and `im.received`.
+    pub fn category_allowed(&self, category: Option<&str>) -> bool {
+        let category = match category {
+            Some(category) => category,
+            None => return self.allow_uncategorized,
+        };
+        let matches = |entry: &String| category_matches(entry, category);
+        if self.denied_categories.iter().any(matches) {
+            return false;
+        }
+        match self.allowed_categories {
+            Some(ref allowed) => allowed.iter().any(matches),
+            None => true,
+        }
+    }
+
+    /// Whether the qube may use the (validated) action key `key`
+    pub fn action_key_allowed(&self, key: &str) -> bool {
+        self.allowed_action_keys
+            .as_ref()
+            .is_none_or(|allowed| allowed.iter().any(|entry| entry == key))
+    }
+
+    /// The icon for a notification in the (validated) category `category`:
+    /// the icon of the category if there is one, preferring exact matches,
+    /// and the icon of the qube otherwise
+    pub fn app_icon(&self, category: Option<&str>) -> &str {
+        let icons = &self.category_icons;
+        category
+            .and_then(|category| {
+                let exact = icons.iter().find(|(entry, _)| entry == category);
+                exact.or_else(|| {
+                    icons
+                        .iter()
+                        .find(|(entry, _)| category_matches(entry, category))
+                })
+            })
+            .map_or(&*self.app_icon, |(_, icon)| &**icon)
+    }
+
+    /// The event to send to the qube when `error` caused its notification with
+    /// sequence number `sequence` to be dropped, if any.  `minor_version` is the
+    /// negotiated protocol minor version.
+    pub fn suppression_event(
+        &self,
+        minor_version: u16,
+        error: &ProxyError,
+        sequence: u64,
+    ) -> Option<ReplyMessage> {
+        if !self.report_suppressed || minor_version < 1 {
+            return None;
+        }
+        error
+            .suppress_reason()
+            .map(|reason| ReplyMessage::Suppressed {
+                sequence,
+                reason: reason.to_owned(),
+            })
+    }
+}
+
+/// Notification daemon that notifications are forwarded to
+///
+/// Each instance of the proxy serves one qube and forwards to one target, so
+/// all of its state is scoped to that pair.
+#[derive(Debug, Default, Clone)]
+pub struct Target {
+    /// D-Bus address of the bus the daemon is on.  `None` means the session bus.
+    pub bus_address: Option<String>,
+    /// Maximum number of notifications of the qube waiting to be sent to the
+    /// daemon.  `None` means no limit.  See [`crate::QubeSendQueue`].
+    pub queue_size: Option<usize>,
+    /// File or Unix socket that every forwarded notification is also written
+    /// to, as JSON lines.  See [`crate::JsonLinesSink`].
+    pub json_lines: Option<std::path::PathBuf>,
+    /// Prefix of the state that the proxies for this target leave outside of
+    /// their process, such as grouping tags and the directory of sound files,
+    /// so that they do not collide with the proxies for other targets on the
+    /// same daemon or machine.  Defaults to the name of the target; `None`, as
+    /// for the default target, means no prefix.
+    pub namespace: Option<String>,
+}
+
+/// Whether `namespace` can be used as the namespace of a target.  It ends up
+/// in file names and hints, so only a few characters are allowed.
+fn is_valid_namespace(namespace: &str) -> bool {
+    !namespace.is_empty()
+        && namespace.len() <= 64
+        && namespace
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+}
+
+impl Target {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "bus_address" => self.bus_address = Some(value.to_owned()),
+            "queue_size" => match parse_u32(value)? {
+                0 => return Err("queue_size must be at least 1".to_owned()),
+                size => self.queue_size = Some(size as usize),
+            },
+            "json_lines" if value.is_empty() => {
+                return Err("json_lines must not be empty".to_owned())
+            }
+            "json_lines" => self.json_lines = Some(value.into()),
+            "namespace" if value.is_empty() => self.namespace = None,
+            "namespace" if is_valid_namespace(value) => self.namespace = Some(value.to_owned()),
+            "namespace" => return Err(format!("Invalid namespace {:?}", value)),
+            _ => return Err(format!("Unknown target setting {:?}", key)),
+        }
+        Ok(())
+    }
+
+    /// Connect to the bus of this target
+    pub async fn connect(&self) -> zbus::Result<zbus::Connection> {
+        match self.bus_address {
+            Some(ref address) => zbus::ConnectionBuilder::address(&**address)?.build().await,
+            None => zbus::Connection::session().await,
+        }
+    }
+}
+
+enum Section {
+    Global,
+    Qube(String),
+    Type(String),
+    Target(String),
+}
+
+/// Parsed configuration file
+///
+/// The file consists of `key = value` lines.  Settings before the first
+/// `[qube-name]` section header apply to every qube, while settings in a
+/// section only apply to the named qube.  `[target:name]` sections define
+/// the notification daemons that qubes can select with the service argument.
+/// `[type:name]` sections apply to the qubes of that type, such as `DispVM`,
+/// and are overridden by the sections of the qubes themselves.  Lines
+/// starting with `#` are comments.
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    global: Vec<(String, String)>,
+    qubes: HashMap<String, Vec<(String, String)>>,
+    types: HashMap<String, Vec<(String, String)>>,
+    targets: HashMap<String, Target>,
+}
+
+impl Config {
+    pub fn parse(data: &str) -> Result<Self, String> {
+        let mut config = Self::default();
+        let mut section = Section::Global;
+        for (line_number, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[') {
+                section = match name.strip_suffix(']') {
+                    Some(name) if !name.is_empty() => match name.strip_prefix("target:") {
+                        Some(target) if is_valid_namespace(target) => {
+                            let default = || Target {
+                                namespace: Some(target.to_owned()),
+                                ..Target::default()
+                            };
+                            config
+                                .targets
+                                .entry(target.to_owned())
+                                .or_insert_with(default);
+                            Section::Target(target.to_owned())
+                        }
+                        Some(_) => {
+                            return Err(format!("line {}: Invalid target name", line_number + 1))
+                        }
+                        None if name.starts_with("type:") => match &name[5..] {
+                            "" => return Err(format!("line {}: Empty type", line_number + 1)),
+                            vm_type => {
+                                config.types.entry(vm_type.to_owned()).or_default();
+                                Section::Type(vm_type.to_owned())
+                            }
+                        },
+                        None => {
+                            config.qubes.entry(name.to_owned()).or_default();
+                            Section::Qube(name.to_owned())
+                        }
+                    },
+                    _ => return Err(format!("line {}: Bad section header", line_number + 1)),
+                };
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(format!("line {}: Expected key = value", line_number + 1)),
+            };
+            let error = |e| format!("line {}: {}", line_number + 1, e);
+            if let Section::Target(ref name) = section {
+                let target = config.targets.get_mut(name).unwrap();
+                target.set(key, value).map_err(error)?;
+                continue;
+            }
+            // Check the setting now, so that errors are reported on startup
+            QubePolicy::default().set(key, value).map_err(error)?;
+            let entry = (key.to_owned(), value.to_owned());
+            match section {
+                Section::Qube(ref name) => config.qubes.get_mut(name).unwrap().push(entry),
+                Section::Type(ref name) => config.types.get_mut(name).unwrap().push(entry),
+                _ => config.global.push(entry),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Load the configuration file at `path`.  A missing file means the
+    /// default configuration.
+    pub fn load(path: &std::path::Path) -> Result<Self, String> {
+        match std::fs::read_to_string(path) {
+            Ok(data) => Self::parse(&data).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("Cannot read {}: {}", path.display(), e)),
+        }
+    }
+
+    /// The target named `name`.  The empty name is the default target, the
+    /// session bus.
+    pub fn target(&self, name: &str) -> Option<Target> {
+        if name.is_empty() {
+            return Some(Target::default());
+        }
+        self.targets.get(name).cloned()
+    }
+
+    /// The qubes with a section of their own
+    pub fn configured_qubes(&self) -> impl Iterator<Item = &str> {
+        self.qubes.keys().map(String::as_str)
+    }
+
+    /// The policy for the qube named `qube`, ignoring its type
+    pub fn policy_for(&self, qube: &str) -> QubePolicy {
+        self.policy_for_type(qube, None)
+    }
+
+    /// The policy for the qube named `qube`, of type `vm_type` if known.  The
+    /// type must come from trusted state, never from the qube.
+    pub fn policy_for_type(&self, qube: &str, vm_type: Option<&str>) -> QubePolicy {
+        let mut policy = QubePolicy::default();
+        let type_settings = vm_type.and_then(|vm_type| self.types.get(vm_type));
+        let settings = self.qubes.get(qube).into_iter().flatten();
+        let all = self
+            .global
+            .iter()
+            .chain(type_settings.into_iter().flatten());
+        for (key, value) in all.chain(settings) {
+            policy
+                .set(key, value)
+                .expect("settings validated when parsing");
+        }
+        policy
+    }
+}
+
+/// The type of `qube`, such as `AppVM` or `DispVM`, according to dom0.  The
+/// qube has no say in this, so the result can select the policy given
+/// by [`Config::policy_for_type`].
+pub fn qube_type(qube: &str) -> Option<String> {
+    let output = std::process::Command::new("qvm-prefs")
+        .args(["--get", "--", qube, "klass"])
+        .stderr(std::process::Stdio::inherit())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            let vm_type = String::from_utf8(output.stdout).ok()?;
+            Some(vm_type.trim().to_owned())
+        }
+        Ok(output) => {
+            eprintln!("Cannot get the type of {}: {}", qube, output.status);
+            None
+        }
+        Err(e) => {
+            eprintln!("Cannot get the type of {}: {}", qube, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            "# comment\nrate_limit = 5\n\n[work]\nreport_suppressed = true\nrate_limit = 7\n",
+        )
+        .unwrap();
+        let policy = config.policy_for("personal");
+        assert_eq!(policy.rate_limit, 5);
+        assert!(!policy.report_suppressed);
+        let policy = config.policy_for("work");
+        assert_eq!(policy.rate_limit, 7);
+        assert!(policy.report_suppressed);
+        assert!(Config::parse("bogus = 1").is_err());
+        assert!(Config::parse("rate_limit = -1").is_err());
+        let policy = Config::parse("reused_id = replace")
+            .unwrap()
+            .policy_for("work");
+        assert_eq!(policy.reused_id, ReusedId::Replace);
+        assert!(Config::parse("reused_id = ignore").is_err());
+        let policy = Config::parse("missing_summary = first-line")
+            .unwrap()
+            .policy_for("work");
+        assert_eq!(policy.missing_summary, MissingSummary::FirstLine);
+        assert!(Config::parse("missing_summary = body").is_err());
+        let config =
+            Config::parse("replace_rate_limit = 50\n[work]\nreplace_rate_limit =").unwrap();
+        assert_eq!(config.policy_for("personal").replace_rate_limit, Some(50));
+        assert_eq!(config.policy_for("work").replace_rate_limit, None);
+        assert!(Config::parse("[]").is_err());
+        assert!(
+            !Config::parse("markup = off")
+                .unwrap()
+                .policy_for("work")
+                .markup
+        );
+    }
+    #[test]
+    fn test_quiet_hours() {
+        let hours = |h: u64| Duration::from_secs(h * 3600);
+        let config = Config::parse("[work]\nquiet_hours = 22:00-07:30").unwrap();
+        let night = config.policy_for("work").quiet_hours.unwrap();
+        assert_eq!(night.start, hours(22));
+        assert_eq!(night.end, hours(7) + Duration::from_secs(30 * 60));
+        assert_eq!(night.remaining(hours(23)), Some(hours(8) + hours(1) / 2));
+        assert_eq!(night.remaining(hours(7)), Some(hours(1) / 2));
+        assert_eq!(night.remaining(hours(12)), None);
+        assert_eq!(night.remaining(hours(22)), Some(hours(9) + hours(1) / 2));
+        let lunch = QuietHours::parse("12:00-13:00").unwrap();
+        assert_eq!(lunch.remaining(hours(12)), Some(hours(1)));
+        assert_eq!(lunch.remaining(hours(13)), None);
+        assert!(config.policy_for("personal").quiet_hours.is_none());
+        assert!(Config::parse("quiet_hours = 22:00").is_err());
+        assert!(Config::parse("quiet_hours = 24:00-07:00").is_err());
+        assert!(Config::parse("quiet_hours = 07:00-07:00").is_err());
+        assert_eq!(config.policy_for("work").quiet_hours_queue_size, 32);
+        assert!(Config::parse("quiet_hours_queue_size = 0").is_err());
+    }
+    #[test]
+    fn test_parse_image_limits() {
+        let config = Config::parse(
+            "max_image_width = 64
+[screenshots]
+max_image_width = 1024
+",
+        )
+        .unwrap();
+        assert_eq!(config.policy_for("work").image_limits.max_width, 64);
+        let limits = config.policy_for("screenshots").image_limits;
+        assert_eq!(limits.max_width, 1024);
+        assert_eq!(limits.max_height, crate::MAX_HEIGHT);
+        assert_eq!(limits.max_size, crate::MAX_SIZE);
+        assert!(Config::parse("max_image_height = 0").is_err());
+        assert!(Config::parse("max_image_size = 4294967295").is_err());
+    }
+    #[test]
+    fn test_category_icons() {
+        let config = Config::parse(
+            "[work]\napp_icon = /usr/share/icons/work.png\n\
+            category_icons = email:mail-read, email.arrived:mail-unread\n",
+        )
+        .unwrap();
+        let policy = config.policy_for("work");
+        assert_eq!(policy.app_icon(Some("email.arrived")), "mail-unread");
+        assert_eq!(policy.app_icon(Some("email.bounced")), "mail-read");
+        assert_eq!(policy.app_icon(None), "/usr/share/icons/work.png");
+        assert_eq!(config.policy_for("personal").app_icon(Some("email")), "");
+        assert!(Config::parse("category_icons = email").is_err());
+        assert!(Config::parse("category_icons = email:../mail").is_err());
+        assert!(Config::parse("app_icon = mail\u{202e}").is_err());
+    }
+    #[test]
+    fn test_parse_targets() {
+        let config = Config::parse(
+            "[target:gui2]\nbus_address = unix:path=/run/gui2\nqueue_size = 8\njson_lines = /run/a11y.sock\n[work]\nrate_limit = 1",
+        )
+        .unwrap();
+        let target = config.target("gui2").unwrap();
+        assert_eq!(target.bus_address.as_deref(), Some("unix:path=/run/gui2"));
+        assert_eq!(target.queue_size, Some(8));
+        assert!(config.target("").unwrap().bus_address.is_none());
+        assert!(config.target("").unwrap().queue_size.is_none());
+        assert_eq!(
+            target.json_lines.as_deref(),
+            Some(std::path::Path::new("/run/a11y.sock"))
+        );
+        assert!(config.target("").unwrap().json_lines.is_none());
+        assert_eq!(target.namespace.as_deref(), Some("gui2"));
+        assert!(config.target("").unwrap().namespace.is_none());
+        let shared = Config::parse("[target:gui2]\nnamespace = shared\n[target:gui3]\nnamespace =");
+        let shared = shared.unwrap();
+        assert_eq!(
+            shared.target("gui2").unwrap().namespace.as_deref(),
+            Some("shared")
+        );
+        assert!(shared.target("gui3").unwrap().namespace.is_none());
+        assert!(Config::parse("[target:x]\nnamespace = ../x").is_err());
+        assert!(Config::parse("[target:a/b]").is_err());
+        assert!(config.target("gui4").is_none());
+        assert_eq!(config.policy_for("work").rate_limit, 1);
+        assert!(Config::parse("[target:]").is_err());
+        assert!(Config::parse("[target:x]\nrate_limit = 1").is_err());
+        assert!(Config::parse("[target:x]\nqueue_size = 0").is_err());
+    }
+    #[test]
+    fn test_type_policy() {
+        let config = Config::parse(
+            "min_urgency = normal\n[type:DispVM]\nmin_urgency = low\n\
+            [disp-override]\nmin_urgency = critical",
+        )
+        .unwrap();
+        let policy = config.policy_for_type("disp1234", Some("DispVM"));
+        assert_eq!(policy.min_urgency, Urgency::Low);
+        let policy = config.policy_for_type("disp-override", Some("DispVM"));
+        assert_eq!(policy.min_urgency, Urgency::Critical);
+        let policy = config.policy_for_type("work", Some("AppVM"));
+        assert_eq!(policy.min_urgency, Urgency::Normal);
+        assert_eq!(config.policy_for("disp1234").min_urgency, Urgency::Normal);
+        assert!(Config::parse("[type:]").is_err());
+        assert!(Config::parse("[type:DispVM]\nbogus = 1").is_err());
+    }
+    #[test]
+    fn test_category_policy() {
+        let policy = Config::parse(
+            "denied_categories = im.received
+[work]
+allowed_categories = email, im
+            allow_uncategorized = no",
+        )
+        .unwrap()
+        .policy_for("work");
+        assert!(policy.category_allowed(Some("email.arrived")));
+        assert!(policy.category_allowed(Some("email")));
+        assert!(policy.category_allowed(Some("im.error")));
+        assert!(!policy.category_allowed(Some("im.received")));
+        assert!(!policy.category_allowed(Some("emailx.arrived")));
+        assert!(!policy.category_allowed(Some("device.added")));
+        assert!(!policy.category_allowed(None));
+        assert!(QubePolicy::default().category_allowed(None));
+        assert!(QubePolicy::default().category_allowed(Some("device.added")));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::Urgency;
+use futures_channel::oneshot;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Notifications of a qube waiting to be sent to a target
+///
+/// Every server process serves a single qube, so in the server this bounds
+/// the notifications of that qube only: a flood from several qubes at once
+/// is bounded per qube, not in total.  A notification is queued from when it has been prepared until its delivery
+/// starts, such as while an update waits for the replace cooldown.  When the
+/// queue is full, one notification is shed to make room: the one with the
+/// lowest urgency, and the oldest among those.  A new notification that is
+/// less urgent than everything queued is shed itself.  The rate limit of
+/// each qube still applies before a notification is queued.
+#[derive(Debug, Clone)]
+pub struct QubeSendQueue(Rc<RefCell<QueueState>>);
+
+#[derive(Debug)]
+struct QueueState {
+    capacity: usize,
+    next_ticket: u64,
+    /// Oldest first
+    entries: Vec<QueueEntry>,
+    shed: u64,
+}
+
+#[derive(Debug)]
+struct QueueEntry {
+    ticket: u64,
+    urgency: Urgency,
+    shed: oneshot::Sender<()>,
+}
+
+/// A place in a [`QubeSendQueue`], given up when dropped
+#[derive(Debug)]
+pub(crate) struct QueueTicket {
+    queue: Rc<RefCell<QueueState>>,
+    ticket: u64,
+    shed: oneshot::Receiver<()>,
+}
+
+impl QubeSendQueue {
+    /// A queue with room for `capacity` notifications, at least one
+    pub fn new(capacity: usize) -> Self {
+        Self(Rc::new(RefCell::new(QueueState {
+            capacity: capacity.max(1),
+            next_ticket: 0,
+            entries: vec![],
+            shed: 0,
+        })))
+    }
+    /// Number of notifications queued
+    pub fn len(&self) -> usize {
+        self.0.borrow().entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Number of notifications shed so far
+    pub fn shed(&self) -> u64 {
+        self.0.borrow().shed
+    }
+    /// Queue a notification with urgency `urgency`.  Returns `None` if it was
+    /// shed right away.
+    pub(crate) fn enter(&self, urgency: Urgency) -> Option<QueueTicket> {
+        let mut state = self.0.borrow_mut();
+        if state.entries.len() >= state.capacity {
+            state.shed += 1;
+            // `min_by_key` returns the first minimum, which is the oldest
+            let (index, lowest) = state
+                .entries
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, entry)| entry.urgency)
+                .map(|(index, entry)| (index, entry.urgency))
+                .expect("capacity is at least 1");
+            if urgency < lowest {
+                return None;
+            }
+            let victim = state.entries.remove(index);
+            // The sender may be gone already, if the send is being cancelled
+            let _ = victim.shed.send(());
+        }
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        let (sender, receiver) = oneshot::channel();
+        state.entries.push(QueueEntry {
+            ticket,
+            urgency,
+            shed: sender,
+        });
+        Some(QueueTicket {
+            queue: self.0.clone(),
+            ticket,
+            shed: receiver,
+        })
+    }
+}
+
+impl QueueTicket {
+    /// Completes once this notification was shed to make room for another
+    pub(crate) async fn shed(&mut self) {
+        // The sender only goes away once the ticket is shed
+        let _ = (&mut self.shed).await;
+    }
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        self.queue
+            .borrow_mut()
+            .entries
+            .retain(|entry| entry.ticket != self.ticket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_shed_lowest_oldest() {
+        let queue = QubeSendQueue::new(3);
+        let mut normal = queue.enter(Urgency::Normal).unwrap();
+        let mut low_old = queue.enter(Urgency::Low).unwrap();
+        let mut low_new = queue.enter(Urgency::Low).unwrap();
+        // Full: the oldest low urgency notification makes room
+        let _critical = queue.enter(Urgency::Critical).unwrap();
+        assert!(low_old.shed.try_recv().unwrap().is_some());
+        assert!(low_new.shed.try_recv().unwrap().is_none());
+        assert_eq!(queue.len(), 3);
+        let _normal = queue.enter(Urgency::Normal).unwrap();
+        assert!(low_new.shed.try_recv().unwrap().is_some());
+        // Less urgent than everything queued
+        assert!(queue.enter(Urgency::Low).is_none());
+        assert!(normal.shed.try_recv().unwrap().is_none());
+        assert_eq!(queue.shed(), 3);
+        drop(normal);
+        assert_eq!(queue.len(), 2);
+        drop(low_old);
+        assert_eq!(queue.len(), 2);
+    }
+}
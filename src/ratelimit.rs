@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Fixed-window rate limiter
+///
+/// At most `limit` notifications are allowed in each window of length
+/// `window`.  A limit of 0 disables rate limiting.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    window_start: Option<Instant>,
+    count: u32,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            window_start: None,
+            count: 0,
+        }
+    }
+
+    /// Record an attempt to send a notification at time `now`.  Returns `false`
+    /// if the notification must be dropped.
+    pub fn check(&mut self, now: Instant) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+        match self.window_start {
+            Some(start) if now.saturating_duration_since(start) < self.window => {}
+            _ => {
+                self.window_start = Some(now);
+                self.count = 0;
+            }
+        }
+        if self.count >= self.limit {
+            return false;
+        }
+        self.count += 1;
+        true
+    }
+
+    /// Whether anything was sent in the window that is current at `now`
+    pub fn is_active(&self, now: Instant) -> bool {
+        self.window_start
+            .is_some_and(|start| now.saturating_duration_since(start) < self.window)
+    }
+}
+
+#[derive(Debug)]
+struct Replacement {
+    last_sent: Instant,
+    generation: u64,
+}
+
+/// Minimum interval between updates of the same notification
+///
+/// Replacing a notification faster than this would make it flicker.  Callers
+/// that come too early wait until the interval is over, and only the last of
+/// them is forwarded.
+#[derive(Debug)]
+pub struct ReplaceCooldown {
+    interval: Duration,
+    open: HashMap<u32, Replacement>,
+}
+
+impl ReplaceCooldown {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            open: HashMap::new(),
+        }
+    }
+
+    /// Start replacing the notification with local ID `local_id` at time
+    /// `now`.  Returns the time to wait until, and a token to pass to
+    /// [`Self::is_latest`] afterwards, if the replacement came too early.
+    pub fn begin(&mut self, local_id: u32, now: Instant) -> Option<(Instant, u64)> {
+        let replacement = self.open.get_mut(&local_id)?;
+        replacement.generation += 1;
+        let due = replacement.last_sent + self.interval;
+        (now < due).then_some((due, replacement.generation))
+    }
+
+    /// Whether no replacement of `local_id` began after the one that got
+    /// `token`
+    pub fn is_latest(&self, local_id: u32, token: u64) -> bool {
+        self.open
+            .get(&local_id)
+            .is_none_or(|replacement| replacement.generation == token)
+    }
+
+    /// Record that notification `local_id` was shown or updated at `now`
+    pub fn sent(&mut self, local_id: u32, now: Instant) {
+        self.open
+            .entry(local_id)
+            .and_modify(|replacement| replacement.last_sent = now)
+            .or_insert(Replacement {
+                last_sent: now,
+                generation: 0,
+            });
+    }
+
+    /// Forget notification `local_id` after it was closed
+    pub fn remove(&mut self, local_id: u32) {
+        self.open.remove(&local_id);
+    }
+}
+
+/// Filter for signals that a daemon re-emits in quick succession
+///
+/// A signal identical to one seen less than `window` ago is dropped, so a
+/// misbehaving daemon cannot flood the qube with copies of one event.
+#[derive(Debug)]
+pub struct SignalDebounce {
+    window: Duration,
+    seen: HashMap<(u32, String), Instant>,
+}
+
+impl SignalDebounce {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Record signal `key` for notification `local_id` at time `now`.
+    /// Returns `false` if it repeats a signal seen within the window.
+    pub fn check(&mut self, local_id: u32, key: &str, now: Instant) -> bool {
+        let window = self.window;
+        self.seen
+            .retain(|_, &mut last| now.saturating_duration_since(last) < window);
+        if self.seen.contains_key(&(local_id, key.to_owned())) {
+            return false;
+        }
+        if !window.is_zero() {
+            self.seen.insert((local_id, key.to_owned()), now);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_rate_limit_window() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(10));
+        assert!(limiter.check(start));
+        assert!(limiter.check(start + Duration::from_secs(1)));
+        assert!(!limiter.check(start + Duration::from_secs(9)));
+        assert!(limiter.check(start + Duration::from_secs(10)));
+        assert!(limiter.is_active(start + Duration::from_secs(19)));
+        assert!(!limiter.is_active(start + Duration::from_secs(20)));
+    }
+    #[test]
+    fn test_replace_cooldown() {
+        let start = Instant::now();
+        let mut cooldown = ReplaceCooldown::new(Duration::from_secs(1));
+        // Unknown notifications are not delayed
+        assert!(cooldown.begin(1, start).is_none());
+        cooldown.sent(1, start);
+        let (due, first) = cooldown.begin(1, start).unwrap();
+        assert_eq!(due, start + Duration::from_secs(1));
+        let (_, second) = cooldown.begin(1, start).unwrap();
+        assert!(!cooldown.is_latest(1, first));
+        assert!(cooldown.is_latest(1, second));
+        cooldown.sent(1, due);
+        assert!(cooldown.begin(1, due + Duration::from_secs(1)).is_none());
+    }
+    #[test]
+    fn test_rate_limit_disabled() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(0, Duration::from_secs(10));
+        for _ in 0..100 {
+            assert!(limiter.check(start));
+        }
+    }
+    #[test]
+    fn test_signal_debounce() {
+        let start = Instant::now();
+        let mut debounce = SignalDebounce::new(Duration::from_millis(100));
+        assert!(debounce.check(1, "default", start));
+        assert!(!debounce.check(1, "default", start + Duration::from_millis(99)));
+        assert!(debounce.check(1, "other", start));
+        assert!(debounce.check(2, "default", start));
+        assert!(debounce.check(1, "default", start + Duration::from_millis(100)));
+    }
+}
@@ -0,0 +1,551 @@
+//! Sanitization of the notifications of a qube, without any D-Bus
+use crate::{
+    boolean_hint, escape_markup, filter_hints, fold_body, grouping_hint, image_hint, is_blank,
+    is_valid_category, progress_value, provenance_label, render_progress, sanitize_actions,
+    sanitize_markup, sanitize_str, shed_to_fit, sound::validate_sound, trusted, Capabilities,
+    HintValue, ImageRejection, Metrics, MissingSummary, Notification, PolicyRejection,
+    PreparedNotification, Problems, ProxyError, QubePolicy, TrustedStr, Urgency,
+    DEFAULT_SPEC_VERSION, PROVENANCE_SEPARATOR, QUBES_HINT_PREFIX,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use zbus::zvariant::Value;
+
+/// Longest summary taken from the body of a notification, in characters
+const MAX_DERIVED_SUMMARY_LEN: usize = 80;
+
+/// The first line of `body` that is not blank, shortened to
+/// [`MAX_DERIVED_SUMMARY_LEN`] characters.  This is as untrusted as `body`.
+fn first_line(body: &str) -> String {
+    let line = body.lines().find(|line| !is_blank(line)).unwrap_or("");
+    let mut chars = line.trim().chars();
+    let mut summary: String = chars.by_ref().take(MAX_DERIVED_SUMMARY_LEN).collect();
+    if chars.next().is_some() {
+        summary.pop();
+        summary.push('\u{2026}')
+    }
+    summary
+}
+
+/// What a [`Sanitizer`] prepares notifications for
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Mode<'a> {
+    /// Looking at the result, as previews and validation do
+    Preview,
+    /// Sending it: rejections are logged and counted in the metrics
+    Send(&'a RefCell<Metrics>),
+}
+
+/// Turns the untrusted notifications of a qube into what is passed to the
+/// daemon
+///
+/// This is all of the security-critical processing of a notification.  It
+/// only depends on the policy of the qube and on what the daemon supports,
+/// so it can be used and tested without a daemon.
+#[derive(Debug, Clone, Copy)]
+pub struct Sanitizer<'a> {
+    policy: &'a QubePolicy,
+    qube_name: &'a str,
+    capabilities: Capabilities,
+    capabilities_known: bool,
+    spec_version: (u32, u32),
+    namespace: Option<&'a str>,
+    mode: Mode<'a>,
+}
+
+impl<'a> Sanitizer<'a> {
+    /// Sanitize the notifications of `qube_name` for a daemon with
+    /// `capabilities`.  `None` means the capabilities are unknown, in which
+    /// case the daemon is assumed to have none, but might interpret markup.
+    pub fn new(
+        policy: &'a QubePolicy,
+        qube_name: &'a str,
+        capabilities: Option<Capabilities>,
+    ) -> Self {
+        Self {
+            policy,
+            qube_name,
+            capabilities: capabilities.unwrap_or_default(),
+            capabilities_known: capabilities.is_some(),
+            spec_version: DEFAULT_SPEC_VERSION,
+            namespace: None,
+            mode: Mode::Preview,
+        }
+    }
+
+    /// Target version `spec_version` of the specification, which decides
+    /// the image hint
+    pub fn with_spec_version(mut self, spec_version: (u32, u32)) -> Self {
+        self.spec_version = spec_version;
+        self
+    }
+
+    /// Prefix the grouping tags with `namespace`, the namespace of the target
+    pub fn with_namespace(mut self, namespace: Option<&'a str>) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    /// Prepare notifications for sending, logging rejections and counting
+    /// them in `metrics`
+    pub(crate) fn recording(mut self, metrics: &'a RefCell<Metrics>) -> Self {
+        self.mode = Mode::Send(metrics);
+        self
+    }
+
+    /// Sanitize `notification`, stopping at the first problem
+    pub fn sanitize(&self, notification: Notification) -> Result<PreparedNotification, ProxyError> {
+        self.prepare(notification, &mut Problems::fail_fast())
+    }
+
+    /// Like [`Self::sanitize`], but report every problem of `notification`
+    /// at once
+    pub fn validate(
+        &self,
+        notification: Notification,
+    ) -> Result<PreparedNotification, Vec<ProxyError>> {
+        let mut problems = Problems::collect();
+        let prepared = self.prepare(notification, &mut problems).map_err(|e| {
+            problems.found.push(e);
+            std::mem::take(&mut problems.found)
+        })?;
+        if problems.found.is_empty() {
+            Ok(prepared)
+        } else {
+            Err(problems.found)
+        }
+    }
+
+    /// What sanitizing `notification` would change rather than reject, such
+    /// as the characters of its summary and body that are not safe to
+    /// display.  [`Self::validate`] does not report these, as sending
+    /// replaces them and succeeds.
+    pub fn warnings(&self, notification: &Notification) -> Vec<ProxyError> {
+        let (Notification::V1 { summary, body, .. } | Notification::V2 { summary, body, .. }) =
+            notification;
+        let mut warnings = vec![];
+        for (field, untrusted) in [("Summary", summary), ("Body", body)] {
+            if let Err(ProxyError::Validation(reason)) = TrustedStr::try_new(untrusted.clone()) {
+                warnings.push(ProxyError::Validation(format!("{}: {}", field, reason)))
+            }
+        }
+        warnings
+    }
+
+    fn has(&self, capability: Capabilities) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// Problems are reported to `problems`, which decides whether to go on.
+    pub(crate) fn prepare(
+        &self,
+        notification: Notification,
+        problems: &mut Problems,
+    ) -> Result<PreparedNotification, ProxyError> {
+        let Notification::V2 {
+            mut suppress_sound,
+            mut transient,
+            urgency,
+            replaces_id,
+            summary: untrusted_summary,
+            body: untrusted_body,
+            actions: untrusted_actions,
+            category: untrusted_category,
+            mut expire_timeout,
+            image,
+            hints: mut untrusted_hints,
+        } = notification.upgrade()
+        else {
+            unreachable!("upgrade() returns the latest version")
+        };
+        let policy = self.policy;
+        // The qube agent only passes these on if they are not booleans
+        untrusted_hints.retain(|(key, value)| {
+            let flag = match &**key {
+                "transient" => &mut transient,
+                "suppress-sound" => &mut suppress_sound,
+                _ => return true,
+            };
+            match boolean_hint(value, policy.coerce_boolean_hints) {
+                Some(value) => *flag |= value,
+                None => eprintln!("Dropping invalid boolean hint {:?} {:?}", key, value),
+            }
+            false
+        });
+        let untrusted_sound = untrusted_hints
+            .iter()
+            .position(|(key, _)| key == "sound-file")
+            .map(|index| untrusted_hints.remove(index).1);
+
+        if expire_timeout < -1 {
+            problems.report(ProxyError::Validation(format!(
+                "Invalid expiration timeout {}",
+                expire_timeout
+            )))?;
+            expire_timeout = -1
+        }
+
+        let transient = transient || policy.force_transient;
+        // A transient notification must go away on its own, so `transient`
+        // takes precedence over the timeout: never-expiring (0) and overly long
+        // timeouts are clamped.  -1 leaves the choice to the daemon, which is
+        // expected to pick a finite timeout.
+        if transient {
+            let max = policy.max_transient_timeout;
+            if expire_timeout == 0 || expire_timeout > max {
+                expire_timeout = max
+            }
+        }
+
+        let untrusted_actions = if untrusted_actions.len() & 1 != 0 {
+            problems.report(ProxyError::Validation(format!(
+                "Actions must have an even length, got {}",
+                untrusted_actions.len()
+            )))?;
+            vec![]
+        } else {
+            untrusted_actions
+        };
+
+        let untrusted_body = if is_blank(&untrusted_body) {
+            String::new()
+        } else {
+            untrusted_body
+        };
+        // The derived summary is sanitized like any other
+        let untrusted_summary = if is_blank(&untrusted_summary) && !untrusted_body.is_empty() {
+            match policy.missing_summary {
+                MissingSummary::Keep => untrusted_summary,
+                MissingSummary::Qube => self.qube_name.to_owned(),
+                MissingSummary::FirstLine => first_line(&untrusted_body),
+            }
+        } else {
+            untrusted_summary
+        };
+        // Invisible summaries would only show the qube name
+        if !policy.allow_empty_summary && is_blank(&untrusted_summary) {
+            problems.report(ProxyError::Validation("Empty summary".to_owned()))?;
+        }
+        // Qubes do not get to name the application: the daemon only sees
+        // which qube the notification is from.
+        let label = match policy.display_prefix {
+            Some(ref prefix) => provenance_label(prefix),
+            None => provenance_label(self.qube_name),
+        };
+        let application_name = "Qubes VM ".to_owned() + &label;
+
+        // Ideally the icon would be associated with the calling application,
+        // with an image suitably processed by Qubes OS to indicate trust.
+        // However, there is no good way to do that in practice, so the icon
+        // comes from the configuration, if anywhere.  The category is only
+        // used once it is validated, see below.
+        let mut icon = policy.app_icon(None);
+        let actions = if self.has(Capabilities::ACTIONS) {
+            match sanitize_actions(&untrusted_actions, policy) {
+                Ok(actions) => actions,
+                Err(e) => {
+                    problems.report(e)?;
+                    vec![]
+                }
+            }
+        } else {
+            vec![]
+        };
+
+        // this is slow but I don't care, the D-Bus call is orders of magnitude slower
+        // Set up the hints
+        let mut hints = HashMap::new();
+        if let Some(urgency) = policy.urgency(urgency) {
+            // this is a hack to appease the borrow checker
+            let urgency = match urgency {
+                Urgency::Low => &0,
+                Urgency::Normal => &1,
+                Urgency::Critical => &2,
+            };
+            hints.insert(
+                "urgency".to_owned(),
+                <zbus::zvariant::Value<'_> as From<&'_ u8>>::from(urgency),
+            );
+        }
+        if suppress_sound && self.has(Capabilities::SOUND) {
+            hints.insert("suppress-sound".to_owned(), Value::from(&true));
+        }
+        let mut sound_data = None;
+        match untrusted_sound {
+            None => {}
+            Some(HintValue::Bytes(_)) if !policy.sound_files => {
+                eprintln!("Dropping sound file: not allowed")
+            }
+            Some(HintValue::Bytes(untrusted_data)) => {
+                match validate_sound(&untrusted_data, policy.max_sound_file_size) {
+                    Err(reason) => problems.report(ProxyError::Validation(reason.to_owned()))?,
+                    // Not worth a file if it is not going to be played
+                    Ok(_) if suppress_sound || !self.has(Capabilities::SOUND) => {}
+                    // sanitized by validate_sound()
+                    Ok(_) => sound_data = Some(untrusted_data),
+                }
+            }
+            Some(_) => eprintln!("Dropping sound file hint that is not a file"),
+        }
+        if transient && self.has(Capabilities::PERSISTENCE) {
+            hints.insert("transient".to_owned(), Value::from(&true));
+        }
+        let mut category_valid = true;
+        if let Some(ref untrusted_category) = untrusted_category {
+            if is_valid_category(untrusted_category) {
+                icon = policy.app_icon(Some(untrusted_category));
+                let category = untrusted_category.as_bytes();
+                // sanitize end
+                hints.insert("category".to_owned(), Value::from(category.to_vec()));
+            } else {
+                let invalid = trusted::find_invalid_char(untrusted_category, |index, c| {
+                    c.is_ascii_lowercase() || (c == '.' && index > 0)
+                });
+                problems.report(ProxyError::Validation(match invalid {
+                    Some(invalid) => format!("Invalid category: {}", invalid),
+                    None => "Invalid category".to_owned(),
+                }))?;
+                category_valid = false
+            }
+        }
+        if category_valid && !policy.category_allowed(untrusted_category.as_deref()) {
+            if let Mode::Send(metrics) = self.mode {
+                metrics.borrow_mut().categories_blocked += 1;
+            }
+            problems.report(ProxyError::PolicyRejected(
+                PolicyRejection::CategoryBlocked(untrusted_category.clone()),
+            ))?;
+        }
+        let mut progress = None;
+        if render_progress(self.capabilities, policy) {
+            progress = untrusted_hints
+                .iter()
+                .find(|(key, _)| key == "value")
+                .and_then(|(_, value)| progress_value(value));
+        }
+        for (key, value) in filter_hints(untrusted_hints, self.capabilities, policy) {
+            hints.insert(key.to_owned(), value);
+        }
+        if policy.group_notifications {
+            // The category was validated above
+            let category = untrusted_category
+                .as_deref()
+                .filter(|_| category_valid && policy.group_by_category);
+            if let Some((key, tag)) =
+                grouping_hint(self.capabilities, self.namespace, self.qube_name, category)
+            {
+                hints.insert(key.to_owned(), Value::from(tag));
+            }
+        }
+        // For themes that show where notifications come from
+        hints.insert(
+            QUBES_HINT_PREFIX.to_owned() + "vmname",
+            Value::from(self.qube_name.to_owned()),
+        );
+        if let Some(image) = image {
+            let mut rejection = ImageRejection::new(self.qube_name, &image);
+            match image_hint(image, &policy.image_limits, self.spec_version) {
+                Ok((key, value)) => {
+                    hints.insert(key.to_owned(), value);
+                }
+                Err(reason) => {
+                    if let Mode::Send(metrics) = self.mode {
+                        rejection.reason = reason;
+                        eprintln!("{}", rejection);
+                        *metrics
+                            .borrow_mut()
+                            .images_rejected
+                            .entry(reason)
+                            .or_default() += 1;
+                    }
+                    problems.report(ProxyError::Validation(reason.to_owned()))?
+                }
+            }
+        }
+        let safe_summary = TrustedStr::sanitize(&untrusted_summary);
+        let mut safe_summary = safe_summary.into_string();
+        let mut body = sanitize_str(&untrusted_body);
+        // Showing the same text twice is just clutter
+        if policy.drop_duplicate_body && body == safe_summary {
+            body.clear()
+        }
+        // Some daemons without the body capability choke on bodies, and none
+        // shows them
+        let show_body = self.has(Capabilities::BODY) || !self.capabilities_known;
+        if !policy.allow_body {
+            body.clear()
+        } else if !show_body {
+            if policy.fold_body {
+                safe_summary = fold_body(&safe_summary, &body, policy.max_folded_summary_len)
+            }
+            body.clear()
+        }
+        let mut escaped_body;
+        let mut footer = String::new();
+        let markup = policy.markup && self.has(Capabilities::BODY_MARKUP);
+        // A daemon whose capabilities are unknown might interpret markup
+        if self.has(Capabilities::BODY_MARKUP) || !self.capabilities_known {
+            // Body markup must be sanitized, or escaped entirely if the
+            // policy turns it off
+            escaped_body = match markup {
+                true => sanitize_markup(&body),
+                false => escape_markup(&body),
+            };
+            if policy.body_footer && show_body && policy.allow_body {
+                footer = "\n\u{2014} from ".to_owned() + &escape_markup(&label)
+            }
+        } else {
+            escaped_body = body;
+            if policy.body_footer && show_body && policy.allow_body {
+                footer = "\n\u{2014} from ".to_owned() + &label
+            }
+        }
+        let mut summary = label + PROVENANCE_SEPARATOR + &safe_summary;
+        if let Some(percent) = progress {
+            summary += &format!(" ({}%)", percent)
+        }
+        if let Err(e) = shed_to_fit(
+            policy.max_message_size,
+            &[&*application_name, icon, &*summary, &*footer],
+            &mut escaped_body,
+            &actions,
+            &mut hints,
+            markup,
+        ) {
+            problems.report(e)?
+        }
+        // The footer is kept even if the body had to be truncated
+        if !footer.is_empty() {
+            if escaped_body.is_empty() {
+                footer.remove(0);
+            }
+            escaped_body += &footer
+        }
+        Ok(PreparedNotification {
+            app_name: application_name,
+            replaces_id,
+            app_icon: icon.to_owned(),
+            summary,
+            body: escaped_body,
+            actions,
+            hints,
+            expire_timeout,
+            category: untrusted_category.filter(|_| category_valid),
+            sound_data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::v2;
+    #[test]
+    fn test_sanitizer() {
+        let policy = QubePolicy::default();
+        let sanitizer = Sanitizer::new(&policy, "work", Some(Capabilities::BODY));
+        let untrusted = || {
+            v2("Hi\u{202e}", |fields| {
+                fields.body = "<b>x</b>".to_owned();
+                fields.actions = vec!["default".to_owned(), "Open".to_owned()];
+            })
+        };
+        let prepared = sanitizer.sanitize(untrusted()).unwrap();
+        assert_eq!(prepared.app_name, "Qubes VM work");
+        assert_eq!(prepared.summary, "work: Hi\u{FFFD}");
+        assert_eq!(prepared.body, "<b>x</b>");
+        // The daemon does not support actions
+        assert!(prepared.actions.is_empty());
+        assert_eq!(
+            prepared.hints[&(QUBES_HINT_PREFIX.to_owned() + "vmname")],
+            Value::from("work")
+        );
+        // Markup is escaped for daemons that might interpret it
+        let sanitizer = Sanitizer::new(&policy, "work", None);
+        let prepared = sanitizer.sanitize(untrusted()).unwrap();
+        assert_eq!(prepared.body, "&lt;b&gt;x&lt;/b&gt;");
+    }
+    #[test]
+    fn test_allow_body() {
+        let policy = QubePolicy {
+            allow_body: false,
+            fold_body: true,
+            body_footer: true,
+            ..QubePolicy::default()
+        };
+        let sanitizer = Sanitizer::new(&policy, "vault", Some(Capabilities::all()));
+        let untrusted = v2("Hi", |fields| {
+            fields.body = "<b>secret</b>".to_owned();
+        });
+        let prepared = sanitizer.sanitize(untrusted).unwrap();
+        assert_eq!(prepared.summary, "vault: Hi");
+        assert_eq!(prepared.body, "");
+    }
+    #[test]
+    fn test_missing_summary() {
+        let body_only = |text: &str| {
+            v2("", |fields| {
+                fields.body = text.to_owned();
+            })
+        };
+        let mut policy = QubePolicy::default();
+        let sanitize = |policy: &QubePolicy, text: &str| {
+            let sanitizer = Sanitizer::new(policy, "work", Some(Capabilities::BODY));
+            sanitizer.sanitize(body_only(text))
+        };
+        assert!(sanitize(&policy, "Build finished").is_err());
+        policy.missing_summary = MissingSummary::Qube;
+        let prepared = sanitize(&policy, "Build finished").unwrap();
+        assert_eq!(prepared.summary, "work: work");
+        assert_eq!(prepared.body, "Build finished");
+        policy.missing_summary = MissingSummary::FirstLine;
+        let prepared = sanitize(&policy, "\n  \u{200b}\n Build\u{202e} finished \nin 3s").unwrap();
+        assert_eq!(prepared.summary, "work: Build\u{FFFD} finished");
+        let prepared = sanitize(&policy, &"x".repeat(100)).unwrap();
+        assert_eq!(prepared.summary.chars().count(), "work: ".len() + 80);
+        assert!(prepared.summary.ends_with("x\u{2026}"));
+        // Without a body there is nothing to derive a summary from
+        assert!(sanitize(&policy, " ").is_err());
+    }
+    #[test]
+    fn test_sanitizer_problems() {
+        let policy = QubePolicy {
+            allowed_categories: Some(vec!["email".to_owned()]),
+            ..QubePolicy::default()
+        };
+        let sanitizer = Sanitizer::new(&policy, "work", Some(Capabilities::all()));
+        let untrusted = || {
+            v2(" ", |fields| {
+                fields.category = Some("im".to_owned());
+                fields.expire_timeout = -2;
+            })
+        };
+        assert!(matches!(
+            sanitizer.sanitize(untrusted()),
+            Err(ProxyError::Validation(_))
+        ));
+        let problems = sanitizer.validate(untrusted()).unwrap_err();
+        let codes: Vec<_> = problems.iter().map(ProxyError::code).collect();
+        assert_eq!(codes, ["invalid", "invalid", "blocked-category"]);
+    }
+    #[test]
+    fn test_validate_offsets() {
+        let policy = QubePolicy::default();
+        let sanitizer = Sanitizer::new(&policy, "work", Some(Capabilities::BODY));
+        let untrusted = || {
+            v2("\u{2603}\u{2603}\u{7}", |fields| {
+                fields.body = "caf\u{e9}\r\n".to_owned();
+            })
+        };
+        let warnings = sanitizer.warnings(&untrusted());
+        let warnings: Vec<_> = warnings.iter().map(ProxyError::to_string).collect();
+        assert_eq!(warnings.len(), 2, "{:?}", warnings);
+        assert!(warnings[0].contains("Summary: U+0007 at character index 2"));
+        assert!(warnings[1].contains("Body: U+000D at character index 4"));
+        // Sending replaces them, so they are not errors
+        assert!(sanitizer.validate(untrusted()).is_ok());
+        assert!(sanitizer.warnings(&v2("a", |_| {})).is_empty());
+        let prepared = sanitizer.sanitize(untrusted()).unwrap();
+        assert_eq!(prepared.summary, "work: \u{2603}\u{2603}\u{FFFD}");
+    }
+}
@@ -0,0 +1,91 @@
+/// Information about the notification daemon, from `GetServerInformation`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub name: String,
+    pub vendor: String,
+    pub version: String,
+    /// Version of the specification implemented by the daemon
+    pub spec_version: (u32, u32),
+}
+
+/// The specification version assumed if the daemon reports an invalid one
+pub const DEFAULT_SPEC_VERSION: (u32, u32) = (1, 2);
+
+/// Parse a specification version such as `1.2`
+pub(crate) fn parse_spec_version(version: &str) -> Option<(u32, u32)> {
+    let (major, minor) = version.split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+impl ServerInfo {
+    /// Parse the reply to `GetServerInformation`.  Fails if the specification
+    /// version is malformed.
+    pub fn parse(
+        (name, vendor, version, spec_version): (String, String, String, String),
+    ) -> Result<Self, String> {
+        let spec_version = parse_spec_version(&spec_version)
+            .ok_or_else(|| format!("Invalid specification version {:?}", spec_version))?;
+        Ok(Self {
+            name,
+            vendor,
+            version,
+            spec_version,
+        })
+    }
+
+    /// Like [`Self::parse`], but assumes [`DEFAULT_SPEC_VERSION`] if the
+    /// specification version is malformed.
+    pub fn parse_lenient(reply: (String, String, String, String)) -> Self {
+        let (name, vendor, version, _) = reply.clone();
+        Self::parse(reply).unwrap_or_else(|e| {
+            eprintln!("{}, assuming {:?}", e, DEFAULT_SPEC_VERSION);
+            Self {
+                name,
+                vendor,
+                version,
+                spec_version: DEFAULT_SPEC_VERSION,
+            }
+        })
+    }
+
+    /// What is assumed about a daemon whose information cannot be parsed
+    pub fn unknown() -> Self {
+        Self {
+            name: String::new(),
+            vendor: String::new(),
+            version: String::new(),
+            spec_version: DEFAULT_SPEC_VERSION,
+        }
+    }
+
+    /// Whether the daemon implements at least version `major.minor` of the
+    /// specification
+    pub fn supports_spec(&self, major: u32, minor: u32) -> bool {
+        self.spec_version >= (major, minor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn reply(spec: &str) -> (String, String, String, String) {
+        let s = |s: &str| s.to_owned();
+        (s("Mock"), s("Qubes OS"), s("0.0.1"), s(spec))
+    }
+    #[test]
+    fn test_parse_server_info() {
+        let info = ServerInfo::parse(reply("1.2")).unwrap();
+        assert_eq!(info.name, "Mock");
+        assert_eq!(info.spec_version, (1, 2));
+        assert!(info.supports_spec(1, 1));
+        assert!(info.supports_spec(1, 2));
+        assert!(!info.supports_spec(1, 3));
+        assert!(!info.supports_spec(2, 0));
+        assert!(ServerInfo::parse(reply("x.y")).is_err());
+        assert!(ServerInfo::parse(reply("1")).is_err());
+        assert!(ServerInfo::parse(reply("1.2.3")).is_err());
+        let info = ServerInfo::parse_lenient(reply("x.y"));
+        assert_eq!(info.spec_version, DEFAULT_SPEC_VERSION);
+        assert_eq!(info.vendor, "Qubes OS");
+    }
+}
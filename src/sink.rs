@@ -0,0 +1,234 @@
+use crate::{NotificationsProxy, PreparedNotification};
+use std::cell::{Cell, RefCell};
+use std::fmt::Write as _;
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use zbus::zvariant::Value;
+use zbus::Connection;
+
+pub type SinkFuture<'a, T> = Pin<Box<dyn Future<Output = zbus::Result<T>> + 'a>>;
+
+/// Somewhere notifications can be delivered, such as a notification daemon
+pub trait NotificationSink {
+    /// Name used in logs and metrics
+    fn name(&self) -> &str;
+    /// Show `notification`.  If `replaces_id` is not 0, it is the ID in this
+    /// sink of the notification to replace.  Returns the ID of the
+    /// notification in this sink.
+    fn notify<'a>(
+        &'a self,
+        notification: &'a PreparedNotification,
+        replaces_id: u32,
+    ) -> SinkFuture<'a, u32>;
+    /// Close the notification with ID `id` in this sink
+    fn close(&self, id: u32) -> SinkFuture<'_, ()>;
+}
+
+/// A notification daemon on a D-Bus connection
+#[derive(Clone)]
+pub struct DaemonSink {
+    name: String,
+    proxy: NotificationsProxy<'static>,
+}
+
+impl DaemonSink {
+    pub async fn new(name: String, connection: &Connection) -> zbus::Result<Self> {
+        let proxy = NotificationsProxy::new(connection).await?;
+        Ok(Self::from_proxy(name, proxy))
+    }
+
+    pub(crate) fn from_proxy(name: String, proxy: NotificationsProxy<'static>) -> Self {
+        Self { name, proxy }
+    }
+}
+
+impl NotificationSink for DaemonSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn notify<'a>(
+        &'a self,
+        notification: &'a PreparedNotification,
+        replaces_id: u32,
+    ) -> SinkFuture<'a, u32> {
+        Box::pin(self.proxy.notify(
+            notification.app_name.clone(),
+            replaces_id,
+            &notification.app_icon,
+            &notification.summary,
+            &notification.body,
+            &notification.actions,
+            &notification.hints,
+            notification.expire_timeout,
+        ))
+    }
+    fn close(&self, id: u32) -> SinkFuture<'_, ()> {
+        Box::pin(self.proxy.close_notification(id))
+    }
+}
+
+/// Writes every notification as a line of JSON, for screen readers and other
+/// tools that want a machine-readable stream
+///
+/// Notifications are written as prepared for the daemon, so they are as
+/// sanitized as what the daemon shows.  Closing a notification writes a line
+/// with only its `closed` ID.
+pub struct JsonLinesSink {
+    name: String,
+    out: RefCell<Box<dyn Write>>,
+    last_id: Cell<u32>,
+}
+
+impl JsonLinesSink {
+    pub fn new(name: String, out: Box<dyn Write>) -> Self {
+        Self {
+            name,
+            out: RefCell::new(out),
+            last_id: Cell::new(0),
+        }
+    }
+
+    /// Write to `path`: connect to it if it is a Unix socket, or else
+    /// append to it.
+    pub fn open(name: String, path: &std::path::Path) -> std::io::Result<Self> {
+        use std::os::unix::fs::FileTypeExt as _;
+        let is_socket =
+            std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+        let out: Box<dyn Write> = if is_socket {
+            let socket = std::os::unix::net::UnixStream::connect(path)?;
+            // A reader that does not keep up must not stall notifications
+            socket.set_write_timeout(Some(std::time::Duration::from_millis(100)))?;
+            Box::new(socket)
+        } else {
+            let file = std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)?;
+            Box::new(file)
+        };
+        Ok(Self::new(name, out))
+    }
+
+    fn write_line(&self, line: String) -> zbus::Result<()> {
+        let mut out = self.out.borrow_mut();
+        out.write_all(line.as_bytes())?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+fn push_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"')
+}
+
+fn push_json_list<'a>(out: &mut String, values: impl IntoIterator<Item = &'a Value<'a>>) {
+    out.push('[');
+    for (index, value) in values.into_iter().enumerate() {
+        if index > 0 {
+            out.push(',')
+        }
+        push_json_value(out, value)
+    }
+    out.push(']')
+}
+
+/// Write `value` as JSON.  Structures become lists of their fields, and
+/// types the proxy never sends become `null`.
+fn push_json_value(out: &mut String, value: &Value<'_>) {
+    match value {
+        Value::Bool(b) => write!(out, "{}", b).unwrap(),
+        Value::U8(n) => write!(out, "{}", n).unwrap(),
+        Value::I16(n) => write!(out, "{}", n).unwrap(),
+        Value::U16(n) => write!(out, "{}", n).unwrap(),
+        Value::I32(n) => write!(out, "{}", n).unwrap(),
+        Value::U32(n) => write!(out, "{}", n).unwrap(),
+        Value::I64(n) => write!(out, "{}", n).unwrap(),
+        Value::U64(n) => write!(out, "{}", n).unwrap(),
+        Value::Str(s) => push_json_str(out, s),
+        Value::Array(array) => push_json_list(out, array.get()),
+        Value::Structure(structure) => push_json_list(out, structure.fields()),
+        Value::Value(value) => push_json_value(out, value),
+        _ => out.push_str("null"),
+    }
+}
+
+/// The JSON line for `notification`, shown with ID `id`.  Hints are sorted
+/// by key, so that the output does not depend on hashing.
+pub(crate) fn notification_json(
+    notification: &PreparedNotification,
+    id: u32,
+    replaces_id: u32,
+) -> String {
+    let mut line = format!(
+        "{{\"id\":{},\"replaces_id\":{},\"app_name\":",
+        id, replaces_id
+    );
+    push_json_str(&mut line, &notification.app_name);
+    line.push_str(",\"app_icon\":");
+    push_json_str(&mut line, &notification.app_icon);
+    line.push_str(",\"summary\":");
+    push_json_str(&mut line, &notification.summary);
+    line.push_str(",\"body\":");
+    push_json_str(&mut line, &notification.body);
+    line.push_str(",\"actions\":[");
+    for (index, action) in notification.actions.iter().enumerate() {
+        if index > 0 {
+            line.push(',')
+        }
+        push_json_str(&mut line, action)
+    }
+    line.push_str("],\"hints\":{");
+    let mut hints: Vec<_> = notification.hints.iter().collect();
+    hints.sort_by_key(|&(key, _)| key);
+    for (index, (key, value)) in hints.into_iter().enumerate() {
+        if index > 0 {
+            line.push(',')
+        }
+        push_json_str(&mut line, key);
+        line.push(':');
+        push_json_value(&mut line, value)
+    }
+    writeln!(
+        line,
+        "}},\"expire_timeout\":{}}}",
+        notification.expire_timeout
+    )
+    .unwrap();
+    line
+}
+
+impl NotificationSink for JsonLinesSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn notify<'a>(
+        &'a self,
+        notification: &'a PreparedNotification,
+        replaces_id: u32,
+    ) -> SinkFuture<'a, u32> {
+        let id = match replaces_id {
+            0 => self.last_id.get() + 1,
+            id => id,
+        };
+        self.last_id.set(self.last_id.get().max(id));
+        let result = self.write_line(notification_json(notification, id, replaces_id));
+        Box::pin(std::future::ready(result.map(|()| id)))
+    }
+    fn close(&self, id: u32) -> SinkFuture<'_, ()> {
+        Box::pin(std::future::ready(
+            self.write_line(format!("{{\"closed\":{}}}\n", id)),
+        ))
+    }
+}
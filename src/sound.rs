@@ -0,0 +1,207 @@
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Largest sound file a qube may send, in bytes
+pub const MAX_SOUND_FILE_SIZE: usize = 1 << 20;
+
+/// Check that `untrusted_data` looks like a WAV or Ogg file no larger than
+/// `max_size` bytes, returning the file name extension for it.  Only the
+/// container is checked: what is inside is up to the daemon.
+pub(crate) fn validate_sound(
+    untrusted_data: &[u8],
+    max_size: usize,
+) -> Result<&'static str, &'static str> {
+    if untrusted_data.len() > max_size {
+        return Err("Sound file too large");
+    }
+    match untrusted_data {
+        [b'R', b'I', b'F', b'F', s0, s1, s2, s3, b'W', b'A', b'V', b'E', ..] => {
+            // The RIFF chunk must span the whole file
+            let size = u32::from_le_bytes([*s0, *s1, *s2, *s3]) as usize;
+            if size.checked_add(8) != Some(untrusted_data.len()) {
+                return Err("Truncated or padded WAV file");
+            }
+            Ok("wav")
+        }
+        // Version 0 is the only one there is
+        [b'O', b'g', b'g', b'S', 0, ..] => Ok("ogg"),
+        _ => Err("Unsupported sound file format"),
+    }
+}
+
+/// Directory for the sound files and sockets of this user's proxies for the
+/// targets in `namespace`, created if it is missing
+pub(crate) fn runtime_dir(namespace: Option<&str>) -> std::io::Result<PathBuf> {
+    let base = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    let dir = match namespace {
+        // Namespaces are validated to be safe in a file name
+        Some(namespace) => base.join(format!("qubes-notification-proxy-{}", namespace)),
+        None => base.join("qubes-notification-proxy"),
+    };
+    create_private_dir(&dir)?;
+    Ok(dir)
+}
+
+/// Create the directory `dir` that only this user can use.  If it exists
+/// already, it must be such a directory: without `XDG_RUNTIME_DIR`, it is in
+/// the temporary directory, where any user could have created it.
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt as _, MetadataExt as _};
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        result => return result,
+    }
+    // Not following symbolic links, which could point anywhere
+    let metadata = std::fs::symlink_metadata(dir)?;
+    let private =
+        metadata.is_dir() && metadata.mode() & 0o777 == 0o700 && metadata.uid() == effective_uid()?;
+    if !private {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} is not private to this user", dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// The effective user ID of this process
+fn effective_uid() -> std::io::Result<u32> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    // The real, effective, saved and file system user IDs
+    let uid = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))
+        .and_then(|uids| uids.split_whitespace().nth(1)?.parse().ok());
+    uid.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "No user ID in /proc/self/status",
+        )
+    })
+}
+
+/// A sound sent by a qube, written to a file that the proxy owns so that the
+/// daemon can play it.  The file is removed when this is dropped.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SoundFile {
+    path: PathBuf,
+}
+
+impl SoundFile {
+    /// Write `data`, which [`validate_sound`] accepted, to a new file in the
+    /// directory for `namespace`
+    pub(crate) fn create(
+        data: &[u8],
+        extension: &str,
+        namespace: Option<&str>,
+    ) -> std::io::Result<Self> {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let dir = runtime_dir(namespace)?;
+        // Nothing of the qube goes into the name
+        let name = format!(
+            "sound-{}-{}.{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            extension
+        );
+        let path = dir.join(name);
+        // Never follow a link or reuse a file that someone else put there
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let sound = Self { path };
+        file.write_all(data)?;
+        Ok(sound)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SoundFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            eprintln!("Cannot remove {}: {}", self.path.display(), e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    /// A WAV file with `samples` samples of silence
+    fn wav(samples: u32) -> Vec<u8> {
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(36 + samples).to_le_bytes());
+        data.extend_from_slice(b"WAVEfmt ");
+        data.extend_from_slice(&16u32.to_le_bytes());
+        // PCM, mono, 8000 Hz, 8000 bytes per second, 1 byte per frame, 8 bits
+        for field in [1u16, 1] {
+            data.extend_from_slice(&field.to_le_bytes())
+        }
+        for field in [8000u32, 8000] {
+            data.extend_from_slice(&field.to_le_bytes())
+        }
+        for field in [1u16, 8] {
+            data.extend_from_slice(&field.to_le_bytes())
+        }
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&samples.to_le_bytes());
+        data.resize(data.len() + samples as usize, 128);
+        data
+    }
+    #[test]
+    fn test_validate_sound() {
+        assert_eq!(validate_sound(&wav(100), 1000), Ok("wav"));
+        assert!(validate_sound(&wav(1000), 1000).is_err());
+        let mut padded = wav(100);
+        padded.push(0);
+        assert!(validate_sound(&padded, 1000).is_err());
+        assert!(validate_sound(&wav(100)[..50], 1000).is_err());
+        assert_eq!(validate_sound(b"OggS\0\x02", 1000), Ok("ogg"));
+        assert!(validate_sound(b"ID3\x04", 1000).is_err());
+        assert!(validate_sound(b"", 1000).is_err());
+    }
+    #[test]
+    fn test_sound_file() {
+        let data = wav(10);
+        let file = SoundFile::create(&data, "wav", None).unwrap();
+        let path = file.path().to_owned();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert_eq!(path.extension().unwrap(), "wav");
+        let other = SoundFile::create(&data, "wav", Some("gui2")).unwrap();
+        assert_ne!(other.path().parent(), path.parent());
+        assert!(other
+            .path()
+            .parent()
+            .unwrap()
+            .ends_with("qubes-notification-proxy-gui2"));
+        drop(file);
+        assert!(!path.exists());
+    }
+    #[test]
+    fn test_private_dir() {
+        use std::os::unix::fs::{MetadataExt as _, PermissionsExt as _};
+        let dir = std::env::temp_dir().join(format!("private-dir-test-{}", std::process::id()));
+        create_private_dir(&dir).unwrap();
+        let metadata = std::fs::metadata(&dir).unwrap();
+        assert_eq!(metadata.mode() & 0o777, 0o700);
+        assert_eq!(metadata.uid(), effective_uid().unwrap());
+        // Existing directories are checked
+        create_private_dir(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(create_private_dir(&dir).is_err());
+        std::fs::remove_dir(&dir).unwrap();
+        std::os::unix::fs::symlink(std::env::temp_dir(), &dir).unwrap();
+        assert!(create_private_dir(&dir).is_err());
+        std::fs::remove_file(&dir).unwrap();
+        std::fs::write(&dir, "").unwrap();
+        assert!(create_private_dir(&dir).is_err());
+        std::fs::remove_file(&dir).unwrap();
+    }
+}
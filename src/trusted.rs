@@ -0,0 +1,128 @@
+use crate::{is_safe_for_display, sanitize_str, ProxyError};
+
+/// A string that is safe to display: it only contains code points that are
+/// safe for display, tabs, and newlines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustedStr(String);
+
+/// Whether `c` may appear in a [`TrustedStr`]
+fn is_trusted_char(c: char) -> bool {
+    is_safe_for_display(c) || c == '\t' || c == '\n'
+}
+
+/// A character rejected by validation, and where it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct InvalidChar {
+    /// Index in characters, not bytes
+    pub(crate) index: usize,
+    pub(crate) c: char,
+}
+
+impl std::fmt::Display for InvalidChar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "U+{:04X} at character index {}",
+            self.c as u32, self.index
+        )
+    }
+}
+
+/// The first character of `untrusted` for which `allowed`, given its index
+/// in characters, returns `false`
+pub(crate) fn find_invalid_char(
+    untrusted: &str,
+    allowed: impl Fn(usize, char) -> bool,
+) -> Option<InvalidChar> {
+    untrusted
+        .chars()
+        .enumerate()
+        .find(|&(index, c)| !allowed(index, c))
+        .map(|(index, c)| InvalidChar { index, c })
+}
+
+impl TrustedStr {
+    /// Validate `untrusted`, failing if it contains any character that is not
+    /// safe to display.
+    pub fn try_new(untrusted: String) -> Result<Self, ProxyError> {
+        match find_invalid_char(&untrusted, |_, c| is_trusted_char(c)) {
+            Some(invalid) => Err(ProxyError::Validation(format!(
+                "{} is not safe to display",
+                invalid
+            ))),
+            None => Ok(Self(untrusted)),
+        }
+    }
+
+    /// Make `untrusted` safe to display with [`sanitize_str`], which replaces
+    /// the characters that are not.
+    pub fn sanitize(untrusted: &str) -> Self {
+        Self::from_validated(sanitize_str(untrusted))
+    }
+
+    /// Wrap `validated` without checking it again.  The caller must ensure
+    /// that every character of `validated` is safe to display, a tab or a
+    /// newline, for example because it was produced by [`sanitize_str`].
+    /// This is only checked in debug builds.
+    pub(crate) fn from_validated(validated: String) -> Self {
+        debug_assert!(validated.chars().all(is_trusted_char));
+        Self(validated)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl std::ops::Deref for TrustedStr {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_trusted_str() {
+        assert_eq!(
+            TrustedStr::try_new("a\tb\nc".to_owned()).unwrap().as_str(),
+            "a\tb\nc"
+        );
+        assert!(TrustedStr::try_new("a\u{7}b".to_owned()).is_err());
+        assert!(TrustedStr::try_new("a\rb".to_owned()).is_err());
+        let sanitized = TrustedStr::sanitize("a\u{7}b\r\n");
+        assert_eq!(&*sanitized, "a\u{FFFD}b\n");
+        // Sanitized strings pass validation, so they need not be checked twice
+        let validated = TrustedStr::try_new(sanitized.clone().into_string()).unwrap();
+        assert_eq!(validated, sanitized);
+    }
+    #[test]
+    fn test_invalid_char_offset() {
+        // Character indices, not byte offsets: each of these takes 3 bytes
+        let untrusted = "\u{2603}\u{2603}\u{2603}\u{7}x".to_owned();
+        match TrustedStr::try_new(untrusted) {
+            Err(ProxyError::Validation(message)) => {
+                assert_eq!(
+                    message,
+                    "U+0007 at character index 3 is not safe to display"
+                )
+            }
+            other => panic!("{:?}", other),
+        }
+        let invalid = find_invalid_char("\u{e9}t\u{e9}\u{202e}", |_, c| c != '\u{202e}');
+        assert_eq!(
+            invalid,
+            Some(InvalidChar {
+                index: 3,
+                c: '\u{202e}'
+            })
+        );
+        assert_eq!(invalid.unwrap().to_string(), "U+202E at character index 3");
+    }
+}